@@ -0,0 +1,666 @@
+use super::instruction::Instruction;
+use super::{MEM_PROGRAM_START, RAM_SIZE};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Constants may refer to other constants, up to this depth.
+const MAX_CONSTANT_DEPTH: usize = 16;
+
+/// An error in the source, at a 1-based line and column of `file`, or of the
+/// source passed to `assemble` when `file` is `None`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct AsmError {
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+/// Assembles source text into a ROM to be loaded at 0x200. Include paths are
+/// relative to the current directory.
+///
+/// Each line holds an optional `label:`, followed by an instruction in the
+/// mnemonics printed by the disassembler, a `db` or `dw` directive with
+/// comma separated values, `NAME equ value` or `include "file"`. Comments
+/// start with `;`. Numbers are decimal, or hex and binary with a `0x` or `0b`
+/// prefix, and labels and constants may be used before they are defined.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut program = Program::new();
+    program.parse(source, None, &mut Vec::new())?;
+    program.encode()
+}
+
+/// Assembles a source file, with include paths relative to the file.
+pub fn assemble_file(path: &Path) -> Result<Vec<u8>, AsmError> {
+    let mut program = Program::new();
+    let source = fs::read_to_string(path).map_err(|e| AsmError {
+        file: Some(path.to_path_buf()),
+        line: 0,
+        column: 0,
+        message: format!("unable to read file: {}", e),
+    })?;
+    program.parse(&source, Some(path), &mut vec![canonical(path)])?;
+    program.encode()
+}
+
+#[derive(Clone, Debug)]
+struct Location {
+    file: Option<PathBuf>,
+    line: usize,
+}
+
+impl Location {
+    fn error(&self, column: usize, message: String) -> AsmError {
+        AsmError {
+            file: self.file.clone(),
+            line: self.line,
+            column,
+            message,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Token<'a> {
+    text: &'a str,
+    column: usize,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Value {
+    Number(u32),
+    Symbol(String),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Operand {
+    V(u8),
+    I,
+    IndirectI,
+    DT,
+    ST,
+    K,
+    F,
+    HF,
+    B,
+    R,
+    Pitch,
+    Long,
+    Value(Value),
+}
+
+#[derive(Clone, Debug)]
+enum Statement {
+    Instruction {
+        mnemonic: String,
+        column: usize,
+        operands: Vec<(Operand, usize)>,
+    },
+    Data {
+        width: usize,
+        values: Vec<(Value, usize)>,
+    },
+}
+
+struct Program {
+    statements: Vec<(Location, Statement)>,
+    labels: HashMap<String, u32>,
+    constants: HashMap<String, Value>,
+    addr: u32,
+}
+
+impl Program {
+    fn new() -> Program {
+        Program {
+            statements: Vec::new(),
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            addr: MEM_PROGRAM_START as u32,
+        }
+    }
+
+    /// Parses the source into statements and assigns addresses to labels.
+    /// `includes` holds the files being parsed, to catch recursive includes.
+    fn parse(
+        &mut self,
+        source: &str,
+        file: Option<&Path>,
+        includes: &mut Vec<PathBuf>,
+    ) -> Result<(), AsmError> {
+        for (i, line) in source.lines().enumerate() {
+            let location = Location {
+                file: file.map(Path::to_path_buf),
+                line: i + 1,
+            };
+            let line = line.split(';').next().unwrap();
+            let mut tokens = tokenize(line);
+
+            if let Some(first) = tokens.first().copied() {
+                if first.text.ends_with(':') {
+                    let name = &first.text[..first.text.len() - 1];
+                    self.define_label(name, first.column, &location)?;
+                    tokens.remove(0);
+                }
+            }
+
+            let first = match tokens.first() {
+                Some(first) => *first,
+                None => continue,
+            };
+
+            match first.text.to_ascii_lowercase().as_str() {
+                "include" => {
+                    let path = line[first.column - 1 + first.text.len()..].trim();
+                    let path = path.trim_matches('"');
+                    self.include(path, file, first.column, &location, includes)?;
+                }
+                "db" | "dw" => {
+                    let width = if first.text.eq_ignore_ascii_case("db") {
+                        1
+                    } else {
+                        2
+                    };
+                    let values = tokens[1..]
+                        .iter()
+                        .map(|token| Ok((parse_value(token, &location)?, token.column)))
+                        .collect::<Result<Vec<_>, AsmError>>()?;
+                    if values.is_empty() {
+                        return Err(location.error(first.column, "expected values".to_string()));
+                    }
+                    let len = width * values.len();
+                    self.push(location, Statement::Data { width, values }, len)?;
+                }
+                _ if tokens.len() > 1 && tokens[1].text.eq_ignore_ascii_case("equ") => {
+                    let value = match tokens.get(2) {
+                        Some(token) if tokens.len() == 3 => parse_value(token, &location)?,
+                        _ => {
+                            return Err(
+                                location.error(tokens[1].column, "expected one value".to_string())
+                            )
+                        }
+                    };
+                    self.define_constant(first, value, &location)?;
+                }
+                _ => {
+                    let operands = tokens[1..]
+                        .iter()
+                        .map(|token| Ok((parse_operand(token, &location)?, token.column)))
+                        .collect::<Result<Vec<_>, AsmError>>()?;
+                    let len = if operands.iter().any(|(op, _)| *op == Operand::Long) {
+                        4
+                    } else {
+                        2
+                    };
+                    let statement = Statement::Instruction {
+                        mnemonic: first.text.to_ascii_uppercase(),
+                        column: first.column,
+                        operands,
+                    };
+                    self.push(location, statement, len)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn include(
+        &mut self,
+        path: &str,
+        from: Option<&Path>,
+        column: usize,
+        location: &Location,
+        includes: &mut Vec<PathBuf>,
+    ) -> Result<(), AsmError> {
+        if path.is_empty() {
+            return Err(location.error(column, "expected a file name".to_string()));
+        }
+
+        let path = match from.and_then(Path::parent) {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
+        };
+        if includes.contains(&canonical(&path)) {
+            return Err(location.error(column, format!("{} includes itself", path.display())));
+        }
+
+        let source = fs::read_to_string(&path).map_err(|e| {
+            location.error(column, format!("unable to read {}: {}", path.display(), e))
+        })?;
+        includes.push(canonical(&path));
+        self.parse(&source, Some(&path), includes)?;
+        includes.pop();
+        Ok(())
+    }
+
+    fn define_label(
+        &mut self,
+        name: &str,
+        column: usize,
+        location: &Location,
+    ) -> Result<(), AsmError> {
+        if !is_symbol(name) {
+            return Err(location.error(column, format!("invalid label \"{}\"", name)));
+        }
+        if self.labels.contains_key(name) || self.constants.contains_key(name) {
+            return Err(location.error(column, format!("{} is already defined", name)));
+        }
+        self.labels.insert(name.to_string(), self.addr);
+        Ok(())
+    }
+
+    fn define_constant(
+        &mut self,
+        name: Token,
+        value: Value,
+        location: &Location,
+    ) -> Result<(), AsmError> {
+        if !is_symbol(name.text) {
+            return Err(location.error(
+                name.column,
+                format!("invalid constant name \"{}\"", name.text),
+            ));
+        }
+        if self.labels.contains_key(name.text) || self.constants.contains_key(name.text) {
+            return Err(location.error(name.column, format!("{} is already defined", name.text)));
+        }
+        self.constants.insert(name.text.to_string(), value);
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        location: Location,
+        statement: Statement,
+        len: usize,
+    ) -> Result<(), AsmError> {
+        self.addr += len as u32;
+        if self.addr > RAM_SIZE as u32 {
+            return Err(location.error(1, "program does not fit in memory".to_string()));
+        }
+        self.statements.push((location, statement));
+        Ok(())
+    }
+
+    /// Resolves symbols and encodes every statement.
+    fn encode(&self) -> Result<Vec<u8>, AsmError> {
+        let mut rom = Vec::new();
+
+        for (location, statement) in &self.statements {
+            match statement {
+                Statement::Instruction {
+                    mnemonic,
+                    column,
+                    operands,
+                } => {
+                    let operands = operands
+                        .iter()
+                        .map(|(operand, column)| match operand {
+                            Operand::Value(value) => self
+                                .resolve(value, 0)
+                                .map(|n| (Operand::Value(Value::Number(n)), *column))
+                                .map_err(|message| location.error(*column, message)),
+                            _ => Ok((operand.clone(), *column)),
+                        })
+                        .collect::<Result<Vec<_>, AsmError>>()?;
+
+                    let (instr, long) = instruction(mnemonic, *column, &operands)
+                        .map_err(|(column, message)| location.error(column, message))?;
+                    rom.extend_from_slice(&instr.encode().to_be_bytes());
+                    if let Some(addr) = long {
+                        rom.extend_from_slice(&addr.to_be_bytes());
+                    }
+                }
+                Statement::Data { width, values } => {
+                    let max = if *width == 1 { 0xFF } else { 0xFFFF };
+                    for (value, column) in values {
+                        let n = self
+                            .resolve(value, 0)
+                            .map_err(|message| location.error(*column, message))?;
+                        if n > max {
+                            return Err(location.error(*column, format!("{:#X} is too large", n)));
+                        }
+                        if *width == 1 {
+                            rom.push(n as u8);
+                        } else {
+                            rom.extend_from_slice(&(n as u16).to_be_bytes());
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(rom)
+    }
+
+    fn resolve(&self, value: &Value, depth: usize) -> Result<u32, String> {
+        match value {
+            Value::Number(n) => Ok(*n),
+            Value::Symbol(name) => {
+                if let Some(&addr) = self.labels.get(name) {
+                    return Ok(addr);
+                }
+                match self.constants.get(name) {
+                    Some(_) if depth >= MAX_CONSTANT_DEPTH => {
+                        Err(format!("{} refers to itself", name))
+                    }
+                    Some(value) => self.resolve(value, depth + 1),
+                    None => Err(format!("undefined symbol {}", name)),
+                }
+            }
+        }
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Splits a line into tokens separated by whitespace and commas.
+fn tokenize(line: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+
+    for (i, c) in line
+        .char_indices()
+        .chain(std::iter::once((line.len(), ' ')))
+    {
+        if c.is_whitespace() || c == ',' {
+            if let Some(s) = start.take() {
+                tokens.push(Token {
+                    text: &line[s..i],
+                    column: s + 1,
+                });
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+
+    tokens
+}
+
+fn is_symbol(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_value(token: &Token, location: &Location) -> Result<Value, AsmError> {
+    let text = token.text;
+    let lower = text.to_ascii_lowercase();
+
+    let number = if let Some(hex) = lower.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        u32::from_str_radix(bin, 2).ok()
+    } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+        text.parse().ok()
+    } else if is_symbol(text) {
+        return Ok(Value::Symbol(text.to_string()));
+    } else {
+        None
+    };
+
+    number
+        .map(Value::Number)
+        .ok_or_else(|| location.error(token.column, format!("invalid value \"{}\"", text)))
+}
+
+fn parse_operand(token: &Token, location: &Location) -> Result<Operand, AsmError> {
+    let operand = match token.text.to_ascii_uppercase().as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::DT,
+        "ST" => Operand::ST,
+        "K" => Operand::K,
+        "F" => Operand::F,
+        "HF" => Operand::HF,
+        "B" => Operand::B,
+        "R" => Operand::R,
+        "PITCH" => Operand::Pitch,
+        "LONG" => Operand::Long,
+        reg if reg.len() == 2 && reg.starts_with('V') => match u8::from_str_radix(&reg[1..], 16) {
+            Ok(x) => Operand::V(x),
+            Err(_) => Operand::Value(parse_value(token, location)?),
+        },
+        _ => Operand::Value(parse_value(token, location)?),
+    };
+    Ok(operand)
+}
+
+/// Builds the instruction for a mnemonic and its resolved operands, along
+/// with the address following `LD I, LONG`. Errors carry the column of the
+/// offending token.
+fn instruction(
+    mnemonic: &str,
+    column: usize,
+    operands: &[(Operand, usize)],
+) -> Result<(Instruction, Option<u16>), (usize, String)> {
+    let value = |i: usize, max: u32| -> Result<u32, (usize, String)> {
+        match &operands[i] {
+            (Operand::Value(Value::Number(n)), _) if *n <= max => Ok(*n),
+            (Operand::Value(Value::Number(n)), column) => {
+                Err((*column, format!("{:#X} does not fit in {:#X}", n, max)))
+            }
+            (_, column) => Err((*column, "expected a value".to_string())),
+        }
+    };
+    let addr = |i| value(i, 0xFFF).map(|n| n as u16);
+    let byte = |i| value(i, 0xFF).map(|n| n as u8);
+    let nibble = |i| value(i, 0xF).map(|n| n as u8);
+
+    let kinds: Vec<&Operand> = operands.iter().map(|(op, _)| op).collect();
+    let instr = match (mnemonic, kinds.as_slice()) {
+        ("CLS", []) => Instruction::CLS,
+        ("RET", []) => Instruction::RET,
+        ("SCR", []) => Instruction::SCR,
+        ("SCL", []) => Instruction::SCL,
+        ("EXIT", []) => Instruction::EXIT,
+        ("LOW", []) => Instruction::LOW,
+        ("HIGH", []) => Instruction::HIGH,
+        ("AUDIO", []) => Instruction::AUDIO,
+        ("SYS", [Operand::Value(_)]) => Instruction::SYS(addr(0)?),
+        ("JP", [Operand::Value(_)]) => Instruction::JP(addr(0)?),
+        ("JP", [Operand::V(0), Operand::Value(_)]) => Instruction::JPV0(addr(1)?),
+        ("CALL", [Operand::Value(_)]) => Instruction::CALL(addr(0)?),
+        ("SE", [Operand::V(x), Operand::V(y)]) => Instruction::SE2(*x, *y),
+        ("SE", [Operand::V(x), Operand::Value(_)]) => Instruction::SE(*x, byte(1)?),
+        ("SNE", [Operand::V(x), Operand::V(y)]) => Instruction::SNE2(*x, *y),
+        ("SNE", [Operand::V(x), Operand::Value(_)]) => Instruction::SNE(*x, byte(1)?),
+        ("LD", [Operand::V(x), Operand::V(y)]) => Instruction::LD2(*x, *y),
+        ("LD", [Operand::V(x), Operand::Value(_)]) => Instruction::LD(*x, byte(1)?),
+        ("LD", [Operand::V(x), Operand::DT]) => Instruction::LD3(*x),
+        ("LD", [Operand::V(x), Operand::K]) => Instruction::LD4(*x),
+        ("LD", [Operand::V(x), Operand::IndirectI]) => Instruction::LD10(*x),
+        ("LD", [Operand::V(x), Operand::R]) => Instruction::LD13(*x),
+        ("LD", [Operand::I, Operand::Value(_)]) => Instruction::LDI(addr(1)?),
+        ("LD", [Operand::I, Operand::Long, Operand::Value(_)]) => {
+            let long = value(2, 0xFFFF)? as u16;
+            return Ok((Instruction::LDIL, Some(long)));
+        }
+        ("LD", [Operand::DT, Operand::V(x)]) => Instruction::LD5(*x),
+        ("LD", [Operand::ST, Operand::V(x)]) => Instruction::LD6(*x),
+        ("LD", [Operand::F, Operand::V(x)]) => Instruction::LD7(*x),
+        ("LD", [Operand::B, Operand::V(x)]) => Instruction::LD8(*x),
+        ("LD", [Operand::IndirectI, Operand::V(x)]) => Instruction::LD9(*x),
+        ("LD", [Operand::HF, Operand::V(x)]) => Instruction::LD11(*x),
+        ("LD", [Operand::R, Operand::V(x)]) => Instruction::LD12(*x),
+        ("LD", [Operand::Pitch, Operand::V(x)]) => Instruction::PITCH(*x),
+        ("ADD", [Operand::V(x), Operand::V(y)]) => Instruction::ADD2(*x, *y),
+        ("ADD", [Operand::V(x), Operand::Value(_)]) => Instruction::ADD(*x, byte(1)?),
+        ("ADD", [Operand::I, Operand::V(x)]) => Instruction::ADD3(*x),
+        ("OR", [Operand::V(x), Operand::V(y)]) => Instruction::OR(*x, *y),
+        ("AND", [Operand::V(x), Operand::V(y)]) => Instruction::AND(*x, *y),
+        ("XOR", [Operand::V(x), Operand::V(y)]) => Instruction::XOR(*x, *y),
+        ("SUB", [Operand::V(x), Operand::V(y)]) => Instruction::SUB(*x, *y),
+        ("SUBN", [Operand::V(x), Operand::V(y)]) => Instruction::SUBN(*x, *y),
+        ("SHR", [Operand::V(x)]) => Instruction::SHR(*x, *x),
+        ("SHR", [Operand::V(x), Operand::V(y)]) => Instruction::SHR(*x, *y),
+        ("SHL", [Operand::V(x)]) => Instruction::SHL(*x, *x),
+        ("SHL", [Operand::V(x), Operand::V(y)]) => Instruction::SHL(*x, *y),
+        ("RND", [Operand::V(x), Operand::Value(_)]) => Instruction::RND(*x, byte(1)?),
+        ("DRW", [Operand::V(x), Operand::V(y), Operand::Value(_)]) => {
+            Instruction::DRW(*x, *y, nibble(2)?)
+        }
+        ("SKP", [Operand::V(x)]) => Instruction::SKP(*x),
+        ("SKNP", [Operand::V(x)]) => Instruction::SKNP(*x),
+        ("SCD", [Operand::Value(_)]) => Instruction::SCD(nibble(0)?),
+        ("SCU", [Operand::Value(_)]) => Instruction::SCU(nibble(0)?),
+        ("SAVE", [Operand::V(x), Operand::V(y)]) => Instruction::SAVE(*x, *y),
+        ("LOAD", [Operand::V(x), Operand::V(y)]) => Instruction::LOAD(*x, *y),
+        ("PLANE", [Operand::Value(_)]) => Instruction::PLANE(nibble(0)?),
+        _ if is_mnemonic(mnemonic) => {
+            return Err((column, format!("invalid operands for {}", mnemonic)))
+        }
+        _ => return Err((column, format!("unknown instruction {}", mnemonic))),
+    };
+
+    Ok((instr, None))
+}
+
+fn is_mnemonic(mnemonic: &str) -> bool {
+    [
+        "CLS", "RET", "SCR", "SCL", "EXIT", "LOW", "HIGH", "AUDIO", "SYS", "JP", "CALL", "SE",
+        "SNE", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SUBN", "SHR", "SHL", "RND", "DRW", "SKP",
+        "SKNP", "SCD", "SCU", "SAVE", "LOAD", "PLANE",
+    ]
+    .contains(&mnemonic)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    fn error(source: &str) -> (usize, usize, String) {
+        let err = assemble(source).unwrap_err();
+        (err.line, err.column, err.message)
+    }
+
+    #[test]
+    fn instructions() {
+        let rom = assemble(
+            "  CLS\n\
+             \tld v1, 0x0A ; comment\n\
+             DRW V0, V1, 2\n\
+             LD I, LONG 0x1234\n\
+             SHR V3\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            rom,
+            [0x00, 0xE0, 0x61, 0x0A, 0xD0, 0x12, 0xF0, 0x00, 0x12, 0x34, 0x83, 0x36]
+        );
+    }
+
+    #[test]
+    fn labels() {
+        let rom = assemble(
+            "start: CALL sub\n\
+             JP start\n\
+             sub:\n\
+             RET\n",
+        )
+        .unwrap();
+
+        assert_eq!(rom, [0x22, 0x04, 0x12, 0x00, 0x00, 0xEE]);
+    }
+
+    #[test]
+    fn constants() {
+        let rom = assemble(
+            "SPEED equ STEP\n\
+             STEP equ 0b101\n\
+             ADD V2, SPEED\n\
+             sprite equ data\n\
+             LD I, sprite\n\
+             data: db 0xF0, 144\n\
+             dw 0xABCD\n",
+        )
+        .unwrap();
+
+        assert_eq!(rom, [0x72, 0x05, 0xA2, 0x04, 0xF0, 0x90, 0xAB, 0xCD]);
+    }
+
+    #[test]
+    fn roundtrip() {
+        // the disassembler output must assemble back to the same opcode
+        for opcode in 0..=0xFFFF {
+            match Instruction::decode(opcode) {
+                Ok(Instruction::LDIL) | Err(_) => {}
+                Ok(instr) => {
+                    let rom = assemble(&instr.to_string()).unwrap();
+                    assert_eq!(rom, opcode.to_be_bytes(), "{}", instr);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            error("CLS\n  LD V1, 0x100"),
+            (2, 10, "0x100 does not fit in 0xFF".to_string())
+        );
+        assert_eq!(
+            error("JP nowhere"),
+            (1, 4, "undefined symbol nowhere".to_string())
+        );
+        assert_eq!(
+            error("  FOO V1"),
+            (1, 3, "unknown instruction FOO".to_string())
+        );
+        assert_eq!(
+            error("SKP 3"),
+            (1, 1, "invalid operands for SKP".to_string())
+        );
+        assert_eq!(
+            error("a: CLS\na: CLS"),
+            (2, 1, "a is already defined".to_string())
+        );
+        assert_eq!(
+            error("db 0x1G"),
+            (1, 4, "invalid value \"0x1G\"".to_string())
+        );
+        assert_eq!(error("x equ x\nJP x").2, "x refers to itself");
+    }
+
+    #[test]
+    fn include() {
+        let dir = env::temp_dir().join(format!("chip8-asm-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("main.asm"), "include \"lib.asm\"\nCALL sub\n").unwrap();
+        fs::write(dir.join("lib.asm"), "JP end\nsub: RET\nend:\n").unwrap();
+        fs::write(dir.join("loop.asm"), "include \"loop.asm\"\n").unwrap();
+        fs::write(dir.join("bad.asm"), "include \"lib.asm\"\nCLS V0\n").unwrap();
+
+        let rom = assemble_file(&dir.join("main.asm"));
+        let recursive = assemble_file(&dir.join("loop.asm")).unwrap_err();
+        let bad = assemble_file(&dir.join("bad.asm")).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(rom.unwrap(), [0x12, 0x04, 0x00, 0xEE, 0x22, 0x02]);
+        assert_eq!(recursive.line, 1);
+        assert_eq!((bad.file, bad.line), (Some(dir.join("bad.asm")), 2));
+    }
+}
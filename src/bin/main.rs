@@ -1,5 +1,387 @@
-use chip8::run;
+use chip8::{
+    assemble_file, compile_octo, debug, disassemble, run, serve_gdb, Config, Keymap, Movie, Quirks,
+    Target,
+};
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+
+const USAGE: &str = "Usage: chip8 [options] <rom>
+       chip8 debug [options] <rom>
+       chip8 gdb [-p <port>] [options] <rom>
+       chip8 disasm <rom>
+       chip8 asm <source> <rom>
+       chip8 octo [-t <target>] <source.8o> <rom>
+
+Octo sources (.8o) can also be run directly, compiled for XO-CHIP and run
+with the modern quirks unless --quirks is given.
+
+Options:
+    -c, --clock <hz>    instructions executed per second (default 600)
+    -s, --scale <n>     size of a CHIP-8 pixel on screen (default 10)
+    -k, --keymap <file> load key bindings from a file
+    -q, --quirks <name> interpreter quirks: vip (default), chip48, schip or modern
+    -r, --rewind <mib>  memory kept for rewinding in MiB (default 16, 0 disables)
+        --trace <file>  write every executed instruction to a file
+        --seed <n>      seed for random numbers, to make runs repeatable
+        --record <file> record the keys pressed to a movie file
+        --replay <file> replay a movie file recorded with --record
+    -t, --target <name> Octo target: chip8, schip or xochip (default)
+    -p, --port <port>   local port to wait for GDB on (default 1234)
+    -h, --help          print this help";
+
+const DEFAULT_GDB_PORT: u16 = 1234;
+
+struct Args {
+    rom_path: String,
+    keymap_path: Option<String>,
+    replay_path: Option<String>,
+    config: Config,
+    /// Whether `config.quirks` was chosen with `--quirks`.
+    quirks_given: bool,
+    /// The options given that only running a ROM directly uses, as they were
+    /// spelled.
+    run_options: Vec<String>,
+    /// The options given that only commands with a window use, as they were
+    /// spelled.
+    window_options: Vec<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut config = Config::default();
+    let mut rom_path = None;
+    let mut keymap_path = None;
+    let mut replay_path = None;
+    let mut quirks_given = false;
+    let mut run_options = Vec::new();
+    let mut window_options = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-c" | "--clock" => {
+                config.clock_speed = parse_value(arg, iter.next())?;
+                if config.clock_speed == 0 {
+                    return Err(format!("{} must be greater than zero", arg));
+                }
+            }
+            "-s" | "--scale" => {
+                config.scale = parse_value(arg, iter.next())?;
+                if config.scale <= 0.0 {
+                    return Err(format!("{} must be greater than zero", arg));
+                }
+            }
+            "-q" | "--quirks" => {
+                let name: String = parse_value(arg, iter.next())?;
+                config.quirks = Quirks::from_name(&name)
+                    .ok_or_else(|| format!("unknown quirks preset {}", name))?;
+                quirks_given = true;
+            }
+            "-r" | "--rewind" => {
+                let mib: usize = parse_value(arg, iter.next())?;
+                config.rewind_capacity = mib << 20;
+                window_options.push(arg.clone());
+            }
+            "--seed" => {
+                config.seed = Some(parse_value(arg, iter.next())?);
+            }
+            "--record" => {
+                config.record_path = Some(parse_value(arg, iter.next())?);
+                run_options.push(arg.clone());
+            }
+            "--replay" => {
+                replay_path = Some(parse_value(arg, iter.next())?);
+                run_options.push(arg.clone());
+            }
+            "--trace" => {
+                config.trace_path = Some(parse_value(arg, iter.next())?);
+            }
+            "-k" | "--keymap" => {
+                keymap_path = Some(parse_value(arg, iter.next())?);
+                window_options.push(arg.clone());
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if rom_path.is_none() => rom_path = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+
+    if config.record_path.is_some() && replay_path.is_some() {
+        return Err("--record and --replay cannot be combined".to_string());
+    }
+    let rom_path = rom_path.ok_or_else(|| "missing ROM path".to_string())?;
+    Ok(Args {
+        rom_path,
+        keymap_path,
+        replay_path,
+        config,
+        quirks_given,
+        run_options,
+        window_options,
+    })
+}
+
+/// Fails if any of `options` were given, as `command` would ignore them.
+fn reject_options(options: &[String], command: &str) -> Result<(), String> {
+    match options.first() {
+        Some(option) => Err(format!("{} cannot be used with {}", option, command)),
+        None => Ok(()),
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(option: &str, value: Option<&String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} requires a value", option))?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", option, value))
+}
+
+fn read_rom(path: &str) -> Vec<u8> {
+    match fs::read(path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("error: unable to read {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn disasm(args: &[String]) {
+    let path = match args {
+        [path] => path,
+        _ => {
+            eprintln!("error: disasm takes a single ROM path\n\n{}", USAGE);
+            process::exit(1);
+        }
+    };
+
+    for line in disassemble(&read_rom(path)) {
+        println!("{}", line);
+    }
+}
+
+fn asm(args: &[String]) {
+    let (source, output) = match args {
+        [source, output] => (source, output),
+        _ => {
+            eprintln!("error: asm takes a source and an output path\n\n{}", USAGE);
+            process::exit(1);
+        }
+    };
+
+    let rom = match assemble_file(Path::new(source)) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = fs::write(output, rom) {
+        eprintln!("error: unable to write {}: {}", output, e);
+        process::exit(1);
+    }
+}
+
+fn compile(path: &str, target: Target) -> Vec<u8> {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("error: unable to read {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    match compile_octo(&source, target) {
+        Ok(program) => program.rom,
+        Err(e) => {
+            eprintln!("error: {}:{}", path, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn octo(args: &[String]) {
+    let (target, paths) = match args {
+        [flag, name, paths @ ..] if flag == "-t" || flag == "--target" => {
+            match Target::from_name(name) {
+                Some(target) => (target, paths),
+                None => {
+                    eprintln!("error: unknown target {}\n\n{}", name, USAGE);
+                    process::exit(1);
+                }
+            }
+        }
+        _ => (Target::XoChip, args),
+    };
+
+    let (source, output) = match paths {
+        [source, output] => (source, output),
+        _ => {
+            eprintln!("error: octo takes a source and an output path\n\n{}", USAGE);
+            process::exit(1);
+        }
+    };
+
+    let rom = compile(source, target);
+    if let Err(e) = fs::write(output, rom) {
+        eprintln!("error: unable to write {}: {}", output, e);
+        process::exit(1);
+    }
+}
+
+/// Reads the ROM, compiling it first if it is an Octo source. Octo sources
+/// are compiled for XO-CHIP, so they run with its quirks unless others were
+/// chosen.
+fn load(args: &mut Args) -> Vec<u8> {
+    if !args.rom_path.ends_with(".8o") {
+        return read_rom(&args.rom_path);
+    }
+    if !args.quirks_given {
+        args.config.quirks = Quirks::MODERN;
+    }
+    compile(&args.rom_path, Target::XoChip)
+}
+
+/// Replaces the default key bindings with those from `--keymap`, if given.
+fn load_keymap(args: &mut Args) {
+    let path = match &args.keymap_path {
+        Some(path) => path,
+        None => return,
+    };
+    args.config.keymap = match fs::read_to_string(path) {
+        Ok(source) => match Keymap::parse(&source) {
+            Ok(keymap) => keymap,
+            Err(e) => {
+                eprintln!("error: invalid keymap {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+        Err(e) => {
+            eprintln!("error: unable to read {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+}
+
+fn debugger(args: &[String]) {
+    let args = parse_args(args).and_then(|args| {
+        reject_options(&args.run_options, "debug")?;
+        reject_options(&args.window_options, "debug")?;
+        Ok(args)
+    });
+    let mut args = match args {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(1);
+        }
+    };
+
+    let rom = load(&mut args);
+    if let Err(e) = debug(&rom, args.config) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn gdb(args: &[String]) {
+    let (port, args) = match args {
+        [flag, port, args @ ..] if flag == "-p" || flag == "--port" => match port.parse() {
+            Ok(port) => (port, args),
+            Err(_) => {
+                eprintln!("error: invalid value for {}: {}\n\n{}", flag, port, USAGE);
+                process::exit(1);
+            }
+        },
+        _ => (DEFAULT_GDB_PORT, args),
+    };
+
+    let args = parse_args(args).and_then(|args| {
+        reject_options(&args.run_options, "gdb")?;
+        Ok(args)
+    });
+    let mut args = match args {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(1);
+        }
+    };
+    load_keymap(&mut args);
+
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("error: unable to listen on port {}: {}", port, e);
+            process::exit(1);
+        }
+    };
+    if let Ok(addr) = listener.local_addr() {
+        println!("Waiting for GDB on {}", addr);
+    }
+
+    let rom = load(&mut args);
+    if let Err(e) = serve_gdb(&rom, args.config, listener) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
 
 fn main() {
-    run();
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    match args.first().map(String::as_str) {
+        Some("disasm") => return disasm(&args[1..]),
+        Some("asm") => return asm(&args[1..]),
+        Some("octo") => return octo(&args[1..]),
+        Some("debug") => return debugger(&args[1..]),
+        Some("gdb") => return gdb(&args[1..]),
+        _ => {}
+    }
+
+    let mut args = match parse_args(&args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(1);
+        }
+    };
+
+    load_keymap(&mut args);
+
+    if let Some(path) = &args.replay_path {
+        let movie = match fs::read(path) {
+            Ok(data) => match Movie::parse(&data) {
+                Ok(movie) => movie,
+                Err(e) => {
+                    eprintln!("error: invalid movie {}: {}", path, e);
+                    process::exit(1);
+                }
+            },
+            Err(e) => {
+                eprintln!("error: unable to read {}: {}", path, e);
+                process::exit(1);
+            }
+        };
+        println!(
+            "Replaying {} frames before the keyboard takes over",
+            movie.len()
+        );
+        args.config.replay = Some(movie);
+    }
+
+    args.config.state_path = Some(PathBuf::from(format!("{}.state", args.rom_path)));
+    let rom = load(&mut args);
+    if let Err(e) = run(&rom, args.config) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,729 @@
+use super::disasm::{Line, LineKind};
+use super::instruction::Instruction;
+use super::{Change, Register, Watchpoint, NUM_REGISTERS, VM};
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "Commands:
+    s, step [n]            execute one or n instructions
+    back [n]               undo one or n instructions
+    c, continue            run until a breakpoint is hit
+    b, break <addr>        break when PC reaches an address
+    b, break op <mnemonic> break before any instruction with the mnemonic, e.g. DRW
+    b, break               list breakpoints
+    d, delete <addr>       remove a breakpoint (or `delete op <mnemonic>`)
+    d, delete              remove all breakpoints
+    w, watch read <addr>   stop after an instruction reads a byte of memory
+    w, watch write <addr>  stop after an instruction writes a byte of memory
+    w, watch <reg> [to|from <value>]
+                           stop after V0-VF or I changes, optionally only
+                           to or from a value
+    w, watch               list watchpoints
+    u, unwatch [...]       remove a watchpoint, or all of them
+    r, regs                print the registers
+    x, mem <addr> [len]    dump len bytes of memory (default 64)
+    bt, stack              print the call stack
+    l, list                print the instructions around PC
+    h, help                print this help
+    q, quit                leave the debugger
+
+Addresses and values are hexadecimal, with or without 0x. An empty line repeats the
+last command.";
+
+/// Number of instructions listed before and after PC.
+const CONTEXT_BEFORE: u16 = 3;
+const CONTEXT_AFTER: u16 = 5;
+
+/// Bytes kept for undoing instructions.
+const HISTORY_CAPACITY: usize = 64 << 20;
+
+const DEFAULT_DUMP_LEN: usize = 64;
+const DUMP_BYTES_PER_LINE: usize = 16;
+
+const MNEMONICS: [&str; 31] = [
+    "SYS", "CLS", "RET", "JP", "CALL", "SE", "SNE", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SHR",
+    "SUBN", "SHL", "RND", "DRW", "SKP", "SKNP", "SCD", "SCR", "SCL", "EXIT", "LOW", "HIGH", "SCU",
+    "SAVE", "LOAD", "PLANE", "AUDIO",
+];
+
+/// An interactive debugger driving a VM one command at a time.
+pub struct Debugger {
+    vm: VM,
+    breakpoints: BTreeSet<u16>,
+    /// Mnemonics of the instructions to break on, in upper case.
+    opcode_breakpoints: BTreeSet<String>,
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new(mut vm: VM) -> Debugger {
+        vm.set_history(HISTORY_CAPACITY);
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            opcode_breakpoints: BTreeSet::new(),
+            last_command: String::new(),
+        }
+    }
+
+    /// Ends the session, returning the VM as the debugger left it.
+    pub fn into_vm(self) -> VM {
+        self.vm
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    /// Reads commands from `input` until it ends or the user quits, writing
+    /// the results to `output`.
+    pub fn repl<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<()> {
+        writeln!(output, "{}", self.list())?;
+        loop {
+            write!(output, "(chip8) ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            match self.command(&line) {
+                Some(result) => writeln!(output, "{}", result)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Runs one command line and returns its output, or `None` if the user
+    /// asked to quit.
+    pub fn command(&mut self, line: &str) -> Option<String> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words.as_slice() {
+            [] => Ok(String::new()),
+            ["q"] | ["quit"] => return None,
+            ["h"] | ["help"] => Ok(HELP.to_string()),
+            ["s"] | ["step"] => Ok(self.run(Some(1))),
+            ["s", count] | ["step", count] => parse_count(count).map(|n| self.run(Some(n))),
+            ["back"] => Ok(self.back(1)),
+            ["back", count] => parse_count(count).map(|n| self.back(n)),
+            ["c"] | ["continue"] => Ok(self.run(None)),
+            ["b"] | ["break"] => Ok(self.list_breakpoints()),
+            ["b", "op", mnemonic] | ["break", "op", mnemonic] => {
+                parse_mnemonic(mnemonic).map(|mnemonic| {
+                    let result = format!("breakpoint on {}", mnemonic);
+                    self.opcode_breakpoints.insert(mnemonic);
+                    result
+                })
+            }
+            ["b", addr] | ["break", addr] => parse_addr(addr).map(|addr| {
+                self.breakpoints.insert(addr);
+                format!("breakpoint at {:#05X}", addr)
+            }),
+            ["d"] | ["delete"] => {
+                self.breakpoints.clear();
+                self.opcode_breakpoints.clear();
+                Ok("deleted all breakpoints".to_string())
+            }
+            ["d", "op", mnemonic] | ["delete", "op", mnemonic] => parse_mnemonic(mnemonic)
+                .and_then(|mnemonic| {
+                    if self.opcode_breakpoints.remove(&mnemonic) {
+                        Ok(format!("deleted breakpoint on {}", mnemonic))
+                    } else {
+                        Err(format!("no breakpoint on {}", mnemonic))
+                    }
+                }),
+            ["d", addr] | ["delete", addr] => parse_addr(addr).and_then(|addr| {
+                if self.breakpoints.remove(&addr) {
+                    Ok(format!("deleted breakpoint at {:#05X}", addr))
+                } else {
+                    Err(format!("no breakpoint at {:#05X}", addr))
+                }
+            }),
+            ["w"] | ["watch"] => Ok(self.list_watchpoints()),
+            ["w", spec @ ..] | ["watch", spec @ ..] => parse_watchpoint(spec).map(|watchpoint| {
+                self.vm.add_watchpoint(watchpoint);
+                format!("watchpoint on {}", watchpoint)
+            }),
+            ["u"] | ["unwatch"] => {
+                for watchpoint in self.vm.watchpoints().to_vec() {
+                    self.vm.remove_watchpoint(watchpoint);
+                }
+                Ok("deleted all watchpoints".to_string())
+            }
+            ["u", spec @ ..] | ["unwatch", spec @ ..] => {
+                parse_watchpoint(spec).and_then(|watchpoint| {
+                    if self.vm.remove_watchpoint(watchpoint) {
+                        Ok(format!("deleted watchpoint on {}", watchpoint))
+                    } else {
+                        Err(format!("no watchpoint on {}", watchpoint))
+                    }
+                })
+            }
+            ["r"] | ["regs"] => Ok(self.registers()),
+            ["x", addr] | ["mem", addr] => {
+                parse_addr(addr).and_then(|addr| self.dump(addr, DEFAULT_DUMP_LEN))
+            }
+            ["x", addr, len] | ["mem", addr, len] => {
+                parse_addr(addr).and_then(|addr| self.dump(addr, parse_count(len)?))
+            }
+            ["bt"] | ["stack"] => Ok(self.stack()),
+            ["l"] | ["list"] => Ok(self.list()),
+            _ => Err(format!("unknown command `{}`, try `help`", line)),
+        };
+
+        Some(result.unwrap_or_else(|e| format!("error: {}", e)))
+    }
+
+    /// Executes instructions until `limit` is reached, a breakpoint is hit
+    /// or the program stops, and reports where it stopped.
+    fn run(&mut self, limit: Option<usize>) -> String {
+        let mut count = 0;
+        let reason = loop {
+            if self.vm.has_exited() {
+                break Some("program exited".to_string());
+            }
+            // a breakpoint at the starting PC has already been reported, and
+            // one at a key wait would be hit again every time it is repeated
+            if count > 0 && !self.vm.is_waiting_for_key() {
+                if let Some(reason) = self.breakpoint_hit() {
+                    break Some(reason);
+                }
+            }
+            if limit == Some(count) {
+                break None;
+            }
+            if limit.is_none()
+                && self.current_instruction() == Some(Instruction::JP(self.vm.reg_pc))
+            {
+                break Some(format!("infinite loop at {:#05X}", self.vm.reg_pc));
+            }
+            let pc = self.vm.reg_pc;
+            match self.vm.step() {
+                Ok(Some(watchpoint)) => {
+                    break Some(format!(
+                        "watchpoint on {} hit by the instruction at {:#05X}",
+                        watchpoint, pc
+                    ))
+                }
+                // there is no keyboard to press a key on
+                Ok(None) if limit.is_none() && self.vm.is_waiting_for_key() => {
+                    break Some(format!("waiting for a key at {:#05X}", pc))
+                }
+                Ok(None) => {}
+                Err(e) => break Some(format!("error: {}", e)),
+            }
+            count += 1;
+        };
+
+        match reason {
+            Some(reason) => format!("{}\n{}", reason, self.list()),
+            None => self.list(),
+        }
+    }
+
+    fn back(&mut self, count: usize) -> String {
+        for _ in 0..count {
+            if !self.vm.step_back() {
+                return format!("no more history\n{}", self.list());
+            }
+        }
+        self.list()
+    }
+
+    fn breakpoint_hit(&self) -> Option<String> {
+        let pc = self.vm.reg_pc;
+        if self.breakpoints.contains(&pc) {
+            return Some(format!("breakpoint at {:#05X}", pc));
+        }
+
+        let instr = self.current_instruction()?;
+        let mnemonic = instr.to_string();
+        let mnemonic = mnemonic.split_whitespace().next().unwrap_or_default();
+        if self.opcode_breakpoints.contains(mnemonic) {
+            Some(format!("breakpoint on {} at {:#05X}", mnemonic, pc))
+        } else {
+            None
+        }
+    }
+
+    fn current_instruction(&self) -> Option<Instruction> {
+        match self.line_at(self.vm.reg_pc)?.kind {
+            LineKind::Code(instr) => Some(instr),
+            LineKind::Data => None,
+        }
+    }
+
+    fn registers(&self) -> String {
+        let vm = &self.vm;
+        let mut out = String::new();
+        for (row, registers) in vm.gen_registers.chunks(NUM_REGISTERS / 2).enumerate() {
+            let registers: Vec<String> = registers
+                .iter()
+                .enumerate()
+                .map(|(i, v)| format!("V{:X}={:02X}", row * NUM_REGISTERS / 2 + i, v))
+                .collect();
+            out.push_str(&registers.join(" "));
+            out.push('\n');
+        }
+        out.push_str(&format!(
+            "I={:04X} PC={:04X} SP={:X} DT={:02X} ST={:02X}",
+            vm.reg_i, vm.reg_pc, vm.reg_sp, vm.reg_delay, vm.reg_sound
+        ));
+        out
+    }
+
+    fn dump(&self, addr: u16, len: usize) -> Result<String, String> {
+        let start = addr as usize;
+        let size = self.vm.memory_size();
+        if start >= size {
+            return Err(format!("{:#05X} is past the end of memory", addr));
+        }
+        let end = start.saturating_add(len).min(size);
+        let lines: Vec<String> = self.vm.memory[start..end]
+            .chunks(DUMP_BYTES_PER_LINE)
+            .enumerate()
+            .map(|(i, bytes)| {
+                let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                format!(
+                    "{:04X}: {}",
+                    start + i * DUMP_BYTES_PER_LINE,
+                    bytes.join(" ")
+                )
+            })
+            .collect();
+        Ok(lines.join("\n"))
+    }
+
+    fn stack(&self) -> String {
+        let frames = &self.vm.stack[..self.vm.reg_sp as usize];
+        if frames.is_empty() {
+            return "stack is empty".to_string();
+        }
+
+        // the innermost call first, like a backtrace
+        let lines: Vec<String> = frames
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(i, ret)| {
+                format!(
+                    "#{} return to {:#05X}, called from {:#05X}",
+                    i,
+                    ret,
+                    ret.wrapping_sub(2)
+                )
+            })
+            .collect();
+        lines.join("\n")
+    }
+
+    /// Lists the instructions around PC. Memory before PC is decoded from a
+    /// fixed distance back, so data mixed with code may be misaligned.
+    fn list(&self) -> String {
+        let pc = self.vm.reg_pc;
+        let mut addr = pc.saturating_sub(CONTEXT_BEFORE * 2);
+        let mut lines = Vec::new();
+
+        while lines.len() < (CONTEXT_BEFORE + 1 + CONTEXT_AFTER) as usize {
+            let line = match self.line_at(addr) {
+                Some(line) => line,
+                None => break,
+            };
+            let marker = if addr == pc { "=>" } else { "  " };
+            lines.push(format!("{} {}", marker, line));
+
+            match (addr as usize).checked_add(line.bytes.len()) {
+                Some(next) if next < self.vm.memory_size() => addr = next as u16,
+                _ => break,
+            }
+        }
+
+        lines.join("\n")
+    }
+
+    /// Decodes the instruction at an address in memory, or returns its two
+    /// bytes as data if it is not one.
+    fn line_at(&self, addr: u16) -> Option<Line> {
+        let memory = &self.vm.memory;
+        let start = addr as usize;
+        let size = self.vm.memory_size();
+        if start + 1 >= size {
+            return None;
+        }
+
+        let opcode = (memory[start] as u16) << 8 | memory[start + 1] as u16;
+        let (kind, len) = match Instruction::decode(opcode) {
+            Ok(Instruction::LDIL) if start + 3 < size => (LineKind::Code(Instruction::LDIL), 4),
+            Ok(Instruction::LDIL) | Err(_) => (LineKind::Data, 2),
+            Ok(instr) => (LineKind::Code(instr), 2),
+        };
+
+        Some(Line {
+            addr,
+            bytes: memory[start..start + len].to_vec(),
+            kind,
+        })
+    }
+
+    fn list_breakpoints(&self) -> String {
+        let lines: Vec<String> = self
+            .breakpoints
+            .iter()
+            .map(|addr| format!("breakpoint at {:#05X}", addr))
+            .chain(
+                self.opcode_breakpoints
+                    .iter()
+                    .map(|mnemonic| format!("breakpoint on {}", mnemonic)),
+            )
+            .collect();
+
+        if lines.is_empty() {
+            "no breakpoints".to_string()
+        } else {
+            lines.join("\n")
+        }
+    }
+
+    fn list_watchpoints(&self) -> String {
+        let lines: Vec<String> = self
+            .vm
+            .watchpoints()
+            .iter()
+            .map(|watchpoint| format!("watchpoint on {}", watchpoint))
+            .collect();
+
+        if lines.is_empty() {
+            "no watchpoints".to_string()
+        } else {
+            lines.join("\n")
+        }
+    }
+}
+
+fn parse_watchpoint(words: &[&str]) -> Result<Watchpoint, String> {
+    let (register, change) = match words {
+        ["read", addr] => return Ok(Watchpoint::Read(parse_addr(addr)?)),
+        ["write", addr] => return Ok(Watchpoint::Write(parse_addr(addr)?)),
+        [register] => (parse_register(register)?, Change::Any),
+        [register, "to", value] => {
+            let register = parse_register(register)?;
+            (register, Change::To(parse_value(register, value)?))
+        }
+        [register, "from", value] => {
+            let register = parse_register(register)?;
+            (register, Change::From(parse_value(register, value)?))
+        }
+        _ => {
+            return Err(
+                "expected `read <addr>`, `write <addr>` or `<reg> [to|from <value>]`".to_string(),
+            )
+        }
+    };
+    Ok(Watchpoint::Register(register, change))
+}
+
+fn parse_register(s: &str) -> Result<Register, String> {
+    let upper = s.to_uppercase();
+    if upper == "I" {
+        return Ok(Register::I);
+    }
+    match upper.strip_prefix('V') {
+        Some(digit) if digit.len() == 1 => u8::from_str_radix(digit, 16)
+            .map(Register::V)
+            .map_err(|_| format!("invalid register {}", s)),
+        _ => Err(format!("invalid register {}", s)),
+    }
+}
+
+fn parse_value(register: Register, s: &str) -> Result<u16, String> {
+    let value = parse_addr(s).map_err(|_| format!("invalid value {}", s))?;
+    match register {
+        Register::V(_) if value > 0xFF => Err(format!("{} does not fit in {}", s, register)),
+        _ => Ok(value),
+    }
+}
+
+fn parse_addr(s: &str) -> Result<u16, String> {
+    let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid address {}", s))
+}
+
+fn parse_count(s: &str) -> Result<usize, String> {
+    s.parse().map_err(|_| format!("invalid count {}", s))
+}
+
+fn parse_mnemonic(s: &str) -> Result<String, String> {
+    let mnemonic = s.to_uppercase();
+    if MNEMONICS.contains(&mnemonic.as_str()) {
+        Ok(mnemonic)
+    } else {
+        Err(format!("unknown mnemonic {}", s))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_vm;
+
+    fn create_debugger(rom: &[u8]) -> Debugger {
+        Debugger::new(test_vm(rom))
+    }
+
+    fn run(debugger: &mut Debugger, line: &str) -> String {
+        debugger.command(line).unwrap()
+    }
+
+    #[test]
+    fn step() {
+        let mut debugger = create_debugger(&[0x61, 0x0A, 0x62, 0x0B, 0x63, 0x0C]);
+
+        let output = run(&mut debugger, "step");
+        assert_eq!(debugger.vm().reg_pc, 0x202);
+        assert!(output.contains("=> 202: 620B             LD V2, 0x0B"));
+
+        run(&mut debugger, "s 2");
+        assert_eq!(debugger.vm().gen_registers[3], 0x0C);
+        assert_eq!(debugger.vm().reg_pc, 0x206);
+    }
+
+    #[test]
+    fn empty_line_repeats_command() {
+        let mut debugger = create_debugger(&[0x61, 0x0A, 0x62, 0x0B]);
+
+        run(&mut debugger, "step");
+        run(&mut debugger, "");
+        assert_eq!(debugger.vm().reg_pc, 0x204);
+    }
+
+    #[test]
+    fn continue_to_breakpoint() {
+        let mut debugger = create_debugger(&[0x61, 0x0A, 0x62, 0x0B, 0x63, 0x0C, 0x12, 0x06]);
+
+        assert_eq!(run(&mut debugger, "break 0x204"), "breakpoint at 0x204");
+        let output = run(&mut debugger, "continue");
+        assert!(output.starts_with("breakpoint at 0x204\n"));
+        assert_eq!(debugger.vm().reg_pc, 0x204);
+
+        // continuing steps off the breakpoint and on to the infinite loop
+        let output = run(&mut debugger, "c");
+        assert!(output.starts_with("infinite loop at 0x206\n"));
+        assert_eq!(debugger.vm().gen_registers[3], 0x0C);
+    }
+
+    #[test]
+    fn continue_to_key_wait() {
+        // LD V0, 1; LD V1, K
+        let mut debugger = create_debugger(&[0x60, 0x01, 0xF1, 0x0A]);
+
+        assert_eq!(run(&mut debugger, "break 0x202"), "breakpoint at 0x202");
+        let output = run(&mut debugger, "c");
+        assert!(output.starts_with("breakpoint at 0x202\n"));
+
+        // the breakpoint is not hit again while the wait repeats
+        let output = run(&mut debugger, "c");
+        assert!(output.starts_with("waiting for a key at 0x202\n"));
+        assert!(debugger.vm().is_waiting_for_key());
+    }
+
+    #[test]
+    fn break_on_opcode_class() {
+        let mut debugger = create_debugger(&[0x61, 0x0A, 0x62, 0x0B, 0xD1, 0x21, 0x00, 0xFD]);
+
+        assert_eq!(run(&mut debugger, "break op drw"), "breakpoint on DRW");
+        let output = run(&mut debugger, "continue");
+        assert!(output.starts_with("breakpoint on DRW at 0x204\n"));
+
+        run(&mut debugger, "delete op DRW");
+        let output = run(&mut debugger, "continue");
+        assert!(output.starts_with("program exited\n"));
+    }
+
+    #[test]
+    fn list_and_delete_breakpoints() {
+        let mut debugger = create_debugger(&[]);
+
+        assert_eq!(run(&mut debugger, "break"), "no breakpoints");
+        run(&mut debugger, "b 300");
+        run(&mut debugger, "b op CALL");
+        assert_eq!(
+            run(&mut debugger, "break"),
+            "breakpoint at 0x300\nbreakpoint on CALL"
+        );
+        assert_eq!(run(&mut debugger, "d 300"), "deleted breakpoint at 0x300");
+        assert_eq!(run(&mut debugger, "d 300"), "error: no breakpoint at 0x300");
+        run(&mut debugger, "delete");
+        assert_eq!(run(&mut debugger, "break"), "no breakpoints");
+    }
+
+    #[test]
+    fn registers() {
+        let mut debugger = create_debugger(&[0x6F, 0x01, 0xA2, 0x34]);
+        run(&mut debugger, "step 2");
+
+        assert_eq!(
+            run(&mut debugger, "regs"),
+            "V0=00 V1=00 V2=00 V3=00 V4=00 V5=00 V6=00 V7=00\n\
+             V8=00 V9=00 VA=00 VB=00 VC=00 VD=00 VE=00 VF=01\n\
+             I=0234 PC=0204 SP=0 DT=00 ST=00"
+        );
+    }
+
+    #[test]
+    fn memory_dump() {
+        let rom: Vec<u8> = (0..20).collect();
+        let mut debugger = create_debugger(&rom);
+
+        assert_eq!(
+            run(&mut debugger, "mem 200 20"),
+            "0200: 00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F\n\
+             0210: 10 11 12 13"
+        );
+        assert_eq!(run(&mut debugger, "x FFE 8"), "0FFE: 00 00");
+        assert_eq!(
+            run(&mut debugger, "x 1000"),
+            "error: 0x1000 is past the end of memory"
+        );
+        assert_eq!(
+            run(&mut debugger, "x 1 18446744073709551615"),
+            run(&mut debugger, "x 1 65535")
+        );
+    }
+
+    #[test]
+    fn call_stack() {
+        let mut debugger =
+            create_debugger(&[0x22, 0x04, 0x00, 0xFD, 0x22, 0x08, 0x00, 0xEE, 0x00, 0xEE]);
+
+        assert_eq!(run(&mut debugger, "stack"), "stack is empty");
+        run(&mut debugger, "step 2");
+        assert_eq!(
+            run(&mut debugger, "bt"),
+            "#0 return to 0x206, called from 0x204\n\
+             #1 return to 0x202, called from 0x200"
+        );
+    }
+
+    #[test]
+    fn list_around_pc() {
+        let mut debugger = create_debugger(&[0x00, 0xE0, 0xF0, 0x00, 0x12, 0x34, 0xFF, 0xFF]);
+
+        assert_eq!(
+            run(&mut debugger, "list")
+                .lines()
+                .take(5)
+                .collect::<Vec<_>>(),
+            [
+                "   1FA: 0000             SYS 0x000",
+                "   1FC: 0000             SYS 0x000",
+                "   1FE: 0000             SYS 0x000",
+                "=> 200: 00E0             CLS",
+                "   202: F0001234         LD I, LONG 0x1234",
+            ]
+        );
+        assert!(run(&mut debugger, "l").contains("   206: FFFF             DB 0xFF, 0xFF"));
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut debugger = create_debugger(&[
+            0xA3, 0x00, // 200: LD I, 0x300
+            0x61, 0x05, // 202: LD V1, 0x05
+            0xF1, 0x55, // 204: LD [I], V1
+            0x71, 0x01, // 206: ADD V1, 0x01
+            0x12, 0x08, // 208: JP 0x208
+        ]);
+
+        assert_eq!(
+            run(&mut debugger, "watch write 301"),
+            "watchpoint on write to 0x301"
+        );
+        assert_eq!(
+            run(&mut debugger, "w v1 from 5"),
+            "watchpoint on change of V1 from 0x05"
+        );
+        assert_eq!(
+            run(&mut debugger, "watch"),
+            "watchpoint on write to 0x301\nwatchpoint on change of V1 from 0x05"
+        );
+
+        let output = run(&mut debugger, "continue");
+        assert!(
+            output.starts_with("watchpoint on write to 0x301 hit by the instruction at 0x204\n")
+        );
+        assert_eq!(debugger.vm().reg_pc, 0x206);
+
+        let output = run(&mut debugger, "c");
+        assert!(output
+            .starts_with("watchpoint on change of V1 from 0x05 hit by the instruction at 0x206\n"));
+
+        assert_eq!(
+            run(&mut debugger, "unwatch write 301"),
+            "deleted watchpoint on write to 0x301"
+        );
+        run(&mut debugger, "unwatch");
+        assert_eq!(run(&mut debugger, "watch"), "no watchpoints");
+        assert_eq!(
+            run(&mut debugger, "watch V1 to 100"),
+            "error: 100 does not fit in V1"
+        );
+        assert_eq!(run(&mut debugger, "watch VG"), "error: invalid register VG");
+    }
+
+    #[test]
+    fn back() {
+        let mut debugger = create_debugger(&[0x61, 0x0A, 0x71, 0x01, 0x71, 0x01]);
+        run(&mut debugger, "step 3");
+        assert_eq!(debugger.vm().gen_registers[1], 0x0C);
+
+        let output = run(&mut debugger, "back 2");
+        assert!(output.contains("=> 202: 7101"));
+        assert_eq!(debugger.vm().gen_registers[1], 0x0A);
+
+        assert!(run(&mut debugger, "back 5").starts_with("no more history\n"));
+        assert_eq!(debugger.vm().reg_pc, 0x200);
+        assert_eq!(debugger.vm().gen_registers[1], 0);
+    }
+
+    #[test]
+    fn errors() {
+        let mut debugger = create_debugger(&[0xFF, 0xFF]);
+
+        assert_eq!(
+            run(&mut debugger, "frobnicate"),
+            "error: unknown command `frobnicate`, try `help`"
+        );
+        assert_eq!(run(&mut debugger, "break zz"), "error: invalid address zz");
+        assert_eq!(
+            run(&mut debugger, "break op NOP"),
+            "error: unknown mnemonic NOP"
+        );
+        assert!(run(&mut debugger, "step").starts_with("error: unknown opcode"));
+        assert_eq!(debugger.vm().reg_pc, 0x200);
+    }
+
+    #[test]
+    fn quit() {
+        let mut debugger = create_debugger(&[]);
+
+        assert_eq!(debugger.command("quit"), None);
+    }
+
+    #[test]
+    fn repl() {
+        let mut debugger = create_debugger(&[0x61, 0x0A]);
+        let mut output = Vec::new();
+
+        debugger.repl(&b"step\nq\n"[..], &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("(chip8) "));
+        assert_eq!(debugger.vm().reg_pc, 0x202);
+    }
+}
@@ -0,0 +1,255 @@
+use super::instruction::Instruction;
+use super::{MEM_PROGRAM_START, RAM_SIZE};
+
+use std::fmt;
+
+/// The maximum number of data bytes shown on one line.
+const DATA_BYTES_PER_LINE: usize = 8;
+
+/// One line of a listing, covering the bytes of an instruction or a run of
+/// data starting at `addr`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Line {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub kind: LineKind,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LineKind {
+    Code(Instruction),
+    Data,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex: String = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(f, "{:03X}: {:<16} ", self.addr, hex)?;
+
+        match self.kind {
+            LineKind::Code(Instruction::LDIL) => write!(
+                f,
+                "{} {:#06X}",
+                Instruction::LDIL,
+                (self.bytes[2] as u16) << 8 | self.bytes[3] as u16
+            ),
+            LineKind::Code(instr) => write!(f, "{}", instr),
+            LineKind::Data => {
+                let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:#04X}", b)).collect();
+                write!(f, "DB {}", bytes.join(", "))
+            }
+        }
+    }
+}
+
+/// Disassembles a ROM loaded at 0x200.
+///
+/// Code is found by following every path from 0x200 through jumps, calls and
+/// skips, and everything that is not reached is listed as data. Targets of
+/// `JP V0` depend on a register and are not followed, so code that is only
+/// reached through a jump table shows up as data. Bytes past the end of the
+/// 64 KiB address space are left out.
+pub fn disassemble(rom: &[u8]) -> Vec<Line> {
+    let rom = &rom[..rom.len().min(RAM_SIZE - MEM_PROGRAM_START as usize)];
+    let code = find_code(rom);
+
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let addr = MEM_PROGRAM_START + offset as u16;
+
+        if let Some(instr) = code[offset] {
+            let len = instruction_len(instr);
+            lines.push(Line {
+                addr,
+                bytes: rom[offset..offset + len].to_vec(),
+                kind: LineKind::Code(instr),
+            });
+            offset += len;
+        } else {
+            let start = offset;
+            while offset < rom.len()
+                && code[offset].is_none()
+                && offset - start < DATA_BYTES_PER_LINE
+            {
+                offset += 1;
+            }
+            lines.push(Line {
+                addr,
+                bytes: rom[start..offset].to_vec(),
+                kind: LineKind::Data,
+            });
+        }
+    }
+
+    lines
+}
+
+/// Marks the ROM offsets where a reachable instruction starts.
+fn find_code(rom: &[u8]) -> Vec<Option<Instruction>> {
+    let mut code = vec![None; rom.len()];
+    let mut visited = vec![false; rom.len()];
+    let mut pending = vec![MEM_PROGRAM_START];
+
+    while let Some(addr) = pending.pop() {
+        let mut addr = addr;
+        loop {
+            let offset = match rom_offset(rom, addr) {
+                Some(offset) if !visited[offset] => offset,
+                _ => break,
+            };
+            let instr = match decode_at(rom, addr) {
+                Some(instr) => instr,
+                None => break,
+            };
+            visited[offset] = true;
+            code[offset] = Some(instr);
+
+            let next = addr.wrapping_add(instruction_len(instr) as u16);
+            match instr {
+                Instruction::JP(target) => {
+                    pending.push(target);
+                    break;
+                }
+                Instruction::CALL(target) => pending.push(target),
+                Instruction::RET | Instruction::EXIT | Instruction::JPV0(_) => break,
+                Instruction::SE(..)
+                | Instruction::SNE(..)
+                | Instruction::SE2(..)
+                | Instruction::SNE2(..)
+                | Instruction::SKP(_)
+                | Instruction::SKNP(_) => {
+                    // the skipped instruction may be a 4 byte LDIL
+                    let skipped = match decode_at(rom, next) {
+                        Some(skipped) => instruction_len(skipped) as u16,
+                        None => 2,
+                    };
+                    pending.push(next.wrapping_add(skipped));
+                }
+                _ => {}
+            }
+            addr = next;
+        }
+    }
+
+    code
+}
+
+fn rom_offset(rom: &[u8], addr: u16) -> Option<usize> {
+    (addr as usize)
+        .checked_sub(MEM_PROGRAM_START as usize)
+        .filter(|&offset| offset < rom.len())
+}
+
+/// Decodes the instruction at an address, if there is a complete one in the
+/// ROM.
+fn decode_at(rom: &[u8], addr: u16) -> Option<Instruction> {
+    let offset = rom_offset(rom, addr)?;
+    if offset + 1 >= rom.len() {
+        return None;
+    }
+    let opcode = (rom[offset] as u16) << 8 | rom[offset + 1] as u16;
+    let instr = Instruction::decode(opcode).ok()?;
+    if instr == Instruction::LDIL && offset + 3 >= rom.len() {
+        return None;
+    }
+    Some(instr)
+}
+
+fn instruction_len(instr: Instruction) -> usize {
+    if instr == Instruction::LDIL {
+        4
+    } else {
+        2
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn listing(rom: &[u8]) -> Vec<String> {
+        disassemble(rom)
+            .iter()
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn straight_line() {
+        assert_eq!(
+            listing(&[0x61, 0x0A, 0xD0, 0x12, 0x00, 0xFD]),
+            [
+                "200: 610A             LD V1, 0x0A",
+                "202: D012             DRW V0, V1, 2",
+                "204: 00FD             EXIT",
+            ]
+        );
+    }
+
+    #[test]
+    fn data_after_jump() {
+        let lines = disassemble(&[0x12, 0x04, 0xF0, 0x90, 0x00, 0xE0, 0x12, 0x04]);
+
+        assert_eq!(lines[0].kind, LineKind::Code(Instruction::JP(0x204)));
+        assert_eq!(lines[1].kind, LineKind::Data);
+        assert_eq!(lines[1].bytes, [0xF0, 0x90]);
+        assert_eq!(lines[1].to_string(), "202: F090             DB 0xF0, 0x90");
+        assert_eq!(lines[2].kind, LineKind::Code(Instruction::CLS));
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn follows_calls_and_skips() {
+        let lines = disassemble(&[
+            0x22, 0x08, // 200: CALL 0x208
+            0x30, 0x00, // 202: SE V0, 0x00
+            0x12, 0x02, // 204: JP 0x202
+            0x00, 0xFD, // 206: EXIT
+            0x00, 0xEE, // 208: RET
+            0xFF, 0xFF, // 20A: data
+        ]);
+
+        let kinds: Vec<LineKind> = lines.iter().map(|line| line.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                LineKind::Code(Instruction::CALL(0x208)),
+                LineKind::Code(Instruction::SE(0, 0)),
+                LineKind::Code(Instruction::JP(0x202)),
+                LineKind::Code(Instruction::EXIT),
+                LineKind::Code(Instruction::RET),
+                LineKind::Data,
+            ]
+        );
+    }
+
+    #[test]
+    fn long_load() {
+        assert_eq!(
+            listing(&[0xF0, 0x00, 0x12, 0x34, 0x00, 0xFD]),
+            [
+                "200: F0001234         LD I, LONG 0x1234",
+                "204: 00FD             EXIT",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_opcode_is_data() {
+        let lines = disassemble(&[0xFF, 0xFF, 0x00]);
+
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].kind, LineKind::Data);
+        assert_eq!(lines[0].bytes, [0xFF, 0xFF, 0x00]);
+    }
+    #[test]
+    fn rom_larger_than_memory() {
+        // LD V0, 0x00 over and over, past the end of memory
+        let rom: Vec<u8> = [0x60, 0x00].iter().cycle().take(70_000).copied().collect();
+        let lines = disassemble(&rom);
+
+        assert_eq!(lines.len(), (RAM_SIZE - 0x200) / 2);
+        assert_eq!(lines.last().unwrap().addr, 0xFFFE);
+    }
+}
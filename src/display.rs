@@ -0,0 +1,382 @@
+use super::quirks::EdgeMode;
+
+use std::ops::{Index, IndexMut};
+
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+
+pub const NUM_PLANES: usize = 2;
+
+/// A display in either 64x32 (lores) or the SUPER-CHIP 128x64 (hires)
+/// resolution, with the two XO-CHIP bit planes giving four colors.
+///
+/// Each row is stored in a `u128` where pixel `x` is bit `width - 1 - x`, so
+/// in lores only the lower 64 bits are used. Only the first `height` rows are
+/// part of the picture. Indexing the display accesses the rows of the first
+/// plane.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Display {
+    hires: bool,
+    /// Bitmask of the planes that drawing, clearing and scrolling act on.
+    selected_planes: u8,
+    planes: [[u128; HIRES_HEIGHT]; NUM_PLANES],
+}
+
+impl Default for Display {
+    fn default() -> Display {
+        Display::new()
+    }
+}
+
+impl Display {
+    pub fn new() -> Display {
+        Display {
+            hires: false,
+            selected_planes: 0b01,
+            planes: [[0; HIRES_HEIGHT]; NUM_PLANES],
+        }
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    /// Switches resolution, which clears all planes.
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.planes = [[0; HIRES_HEIGHT]; NUM_PLANES];
+    }
+
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_WIDTH
+        } else {
+            LORES_WIDTH
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_HEIGHT
+        } else {
+            LORES_HEIGHT
+        }
+    }
+
+    pub fn selected_planes(&self) -> u8 {
+        self.selected_planes
+    }
+
+    /// Selects the planes to act on, where bit `n` of `mask` is plane `n`.
+    pub fn select_planes(&mut self, mask: u8) {
+        self.selected_planes = mask & ((1 << NUM_PLANES) - 1);
+    }
+
+    pub(crate) fn from_planes(
+        hires: bool,
+        selected_planes: u8,
+        planes: [[u128; HIRES_HEIGHT]; NUM_PLANES],
+    ) -> Display {
+        Display {
+            hires,
+            selected_planes,
+            planes,
+        }
+    }
+
+    /// Returns every row of every plane, including the ones outside of the
+    /// picture in lores.
+    pub(crate) fn planes(&self) -> &[[u128; HIRES_HEIGHT]; NUM_PLANES] {
+        &self.planes
+    }
+
+    pub fn rows(&self, plane: usize) -> &[u128] {
+        &self.planes[plane][..self.height()]
+    }
+
+    /// Returns the color of a pixel, where bit `n` is set if the pixel is lit
+    /// in plane `n`.
+    pub fn color(&self, x: usize, y: usize) -> u8 {
+        let mask = 1 << (self.width() - 1 - x);
+        (0..NUM_PLANES)
+            .filter(|&plane| self.planes[plane][y] & mask != 0)
+            .fold(0, |color, plane| color | 1 << plane)
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.color(x, y) != 0
+    }
+
+    /// Clears the selected planes.
+    pub fn clear(&mut self) {
+        for plane in self.selected() {
+            self.planes[plane] = [0; HIRES_HEIGHT];
+        }
+    }
+
+    /// XORs a sprite onto the selected planes with its top left corner at
+    /// `(x, y)`, wrapping the start position. Each row of the sprite is
+    /// `sprite_width / 8` bytes. With several planes selected, the sprite
+    /// holds the rows for each plane in turn. Returns whether any pixel was
+    /// erased.
+    pub fn draw(
+        &mut self,
+        x: usize,
+        y: usize,
+        sprite: &[u8],
+        sprite_width: usize,
+        edge: EdgeMode,
+    ) -> bool {
+        let width = self.width();
+        let height = self.height();
+        let x = x % width;
+        let y = y % height;
+
+        let num_planes = self.selected_planes.count_ones() as usize;
+        if num_planes == 0 {
+            return false;
+        }
+
+        let mut collision = false;
+        let plane_len = sprite.len() / num_planes;
+        for (plane, sprite) in self.selected().zip(sprite.chunks(plane_len)) {
+            let rows = &mut self.planes[plane];
+            for (i, bytes) in sprite.chunks(sprite_width / 8).enumerate() {
+                let mut row = y + i;
+                if row >= height {
+                    match edge {
+                        EdgeMode::Clip => break,
+                        EdgeMode::Wrap => row %= height,
+                    }
+                }
+
+                let bits = bytes.iter().fold(0, |bits, &byte| bits << 8 | byte as u16);
+                let mask = sprite_mask(bits, sprite_width, x, width, edge);
+                if rows[row] & mask != 0 {
+                    collision = true;
+                }
+                rows[row] ^= mask;
+            }
+        }
+
+        collision
+    }
+
+    pub fn scroll_down(&mut self, n: usize) {
+        let height = self.height();
+        for plane in self.selected() {
+            let rows = &mut self.planes[plane];
+            for row in (0..height).rev() {
+                rows[row] = if row >= n { rows[row - n] } else { 0 };
+            }
+        }
+    }
+
+    pub fn scroll_up(&mut self, n: usize) {
+        let height = self.height();
+        for plane in self.selected() {
+            let rows = &mut self.planes[plane];
+            for row in 0..height {
+                rows[row] = if row + n < height { rows[row + n] } else { 0 };
+            }
+        }
+    }
+
+    pub fn scroll_left(&mut self, n: usize) {
+        let mask = row_mask(self.width());
+        for plane in self.selected() {
+            for row in self.planes[plane].iter_mut() {
+                *row = (*row << n) & mask;
+            }
+        }
+    }
+
+    pub fn scroll_right(&mut self, n: usize) {
+        for plane in self.selected() {
+            for row in self.planes[plane].iter_mut() {
+                *row >>= n;
+            }
+        }
+    }
+
+    /// Returns the indices of the selected planes. The iterator holds a copy
+    /// of the mask, so the planes can be changed while iterating.
+    fn selected(&self) -> impl Iterator<Item = usize> {
+        let mask = self.selected_planes;
+        (0..NUM_PLANES).filter(move |&plane| mask & (1 << plane) != 0)
+    }
+}
+
+impl Index<usize> for Display {
+    type Output = u128;
+
+    fn index(&self, row: usize) -> &u128 {
+        &self.planes[0][row]
+    }
+}
+
+impl IndexMut<usize> for Display {
+    fn index_mut(&mut self, row: usize) -> &mut u128 {
+        &mut self.planes[0][row]
+    }
+}
+
+fn row_mask(width: usize) -> u128 {
+    u128::MAX >> (HIRES_WIDTH - width)
+}
+
+/// Positions one row of a sprite at `x` on a display row of `width` pixels.
+pub(crate) fn sprite_mask(
+    bits: u16,
+    sprite_width: usize,
+    x: usize,
+    width: usize,
+    edge: EdgeMode,
+) -> u128 {
+    let row = (bits as u128) << (width - sprite_width);
+    match edge {
+        EdgeMode::Clip => row >> x,
+        EdgeMode::Wrap => {
+            let wrapped = row.checked_shl((width - x) as u32).unwrap_or(0);
+            ((row >> x) | wrapped) & row_mask(width)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resolution() {
+        let mut display = Display::new();
+        assert_eq!((display.width(), display.height()), (64, 32));
+
+        display[0] = 1;
+        display.set_hires(true);
+
+        assert_eq!((display.width(), display.height()), (128, 64));
+        assert_eq!(display.rows(0).len(), 64);
+        assert_eq!(display[0], 0);
+    }
+
+    #[test]
+    fn pixel() {
+        let mut display = Display::new();
+        display[1] = 1 << 63;
+
+        assert!(display.pixel(0, 1));
+        assert!(!display.pixel(1, 1));
+    }
+
+    #[test]
+    fn draw_hires_clip() {
+        let mut display = Display::new();
+        display.set_hires(true);
+
+        let collision = display.draw(124, 63, &[0xFF, 0xFF], 8, EdgeMode::Clip);
+
+        assert!(!collision);
+        assert_eq!(display[63], 0b1111);
+        assert_eq!(display[0], 0);
+    }
+
+    #[test]
+    fn draw_hires_wrap() {
+        let mut display = Display::new();
+        display.set_hires(true);
+
+        display.draw(124, 0, &[0xFF], 8, EdgeMode::Wrap);
+
+        assert_eq!(display[0], 0b1111 | (0b1111 << 124));
+    }
+
+    #[test]
+    fn draw_wide_sprite() {
+        let mut display = Display::new();
+        display.set_hires(true);
+
+        display.draw(0, 0, &[0x80, 0x01, 0xFF, 0xFF], 16, EdgeMode::Clip);
+
+        assert_eq!(display[0], 0x8001 << 112);
+        assert_eq!(display[1], 0xFFFF << 112);
+    }
+
+    #[test]
+    fn scroll_down() {
+        let mut display = Display::new();
+        display[0] = 1;
+        display[31] = 1;
+
+        display.scroll_down(2);
+
+        assert_eq!(display[0], 0);
+        assert_eq!(display[2], 1);
+        // the bottom row scrolls off the lores display
+        assert_eq!(display[31], 0);
+    }
+
+    #[test]
+    fn scroll_left_right() {
+        let mut display = Display::new();
+        display[0] = 0xF0 | (1 << 63);
+
+        display.scroll_left(4);
+        assert_eq!(display[0], 0xF00);
+
+        display.scroll_right(4);
+        display.scroll_right(4);
+        assert_eq!(display[0], 0xF);
+    }
+
+    #[test]
+    fn scroll_up() {
+        let mut display = Display::new();
+        display[0] = 1;
+        display[2] = 1;
+
+        display.scroll_up(2);
+
+        assert_eq!(display[0], 1);
+        assert_eq!(display[2], 0);
+        assert_eq!(display[31], 0);
+    }
+
+    #[test]
+    fn draw_planes() {
+        let mut display = Display::new();
+        display.select_planes(0b11);
+
+        // the rows for plane 0 are followed by the rows for plane 1
+        let collision = display.draw(0, 0, &[0xC0, 0x80, 0x40, 0x80], 8, EdgeMode::Clip);
+
+        assert!(!collision);
+        assert_eq!(display.color(0, 0), 0b01);
+        assert_eq!(display.color(1, 0), 0b11);
+        assert_eq!(display.color(0, 1), 0b11);
+        assert_eq!(display.color(1, 1), 0b00);
+    }
+
+    #[test]
+    fn clear_selected_planes() {
+        let mut display = Display::new();
+        display.select_planes(0b11);
+        display.draw(0, 0, &[0x80, 0x80], 8, EdgeMode::Clip);
+
+        display.select_planes(0b10);
+        display.clear();
+
+        assert_eq!(display.color(0, 0), 0b01);
+    }
+
+    #[test]
+    fn draw_no_planes() {
+        let mut display = Display::new();
+        display.select_planes(0);
+
+        assert!(!display.draw(0, 0, &[0xFF], 8, EdgeMode::Clip));
+        assert_eq!(display[0], 0);
+    }
+}
@@ -0,0 +1,55 @@
+use super::keys::Keypad;
+use super::Display;
+
+/// Requests from the user that are carried out on the VM between frames.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Command {
+    QuickSave,
+    QuickLoad,
+    /// Runs backwards frame by frame until `StopRewind`.
+    StartRewind,
+    StopRewind,
+}
+
+/// The host side of the emulator: shows frames, collects input and plays the
+/// buzzer. Implementations are driven once per frame by `run_with`.
+pub trait Frontend {
+    fn present(&mut self, display: &Display);
+
+    /// Presses and releases keys on the keypad according to the host input
+    /// since the last call. Returns `false` once the user has asked to quit.
+    fn poll_input(&mut self, keypad: &mut Keypad) -> bool;
+
+    /// Returns the commands given since the last call.
+    fn poll_commands(&mut self) -> Vec<Command>;
+
+    fn play_tone(&mut self);
+
+    fn stop_tone(&mut self);
+
+    /// Replaces the buzzer tone with an XO-CHIP audio pattern of 128 one-bit
+    /// samples, played back at `4000 * 2 ^ ((pitch - 64) / 48)` Hz.
+    fn set_audio(&mut self, pattern: &[u8; 16], pitch: u8);
+}
+
+/// A frontend without any output, for running the VM on machines without a
+/// display.
+pub struct Headless;
+
+impl Frontend for Headless {
+    fn present(&mut self, _display: &Display) {}
+
+    fn poll_input(&mut self, _keypad: &mut Keypad) -> bool {
+        true
+    }
+
+    fn poll_commands(&mut self) -> Vec<Command> {
+        Vec::new()
+    }
+
+    fn play_tone(&mut self) {}
+
+    fn stop_tone(&mut self) {}
+
+    fn set_audio(&mut self, _pattern: &[u8; 16], _pitch: u8) {}
+}
@@ -0,0 +1,798 @@
+use super::frontend::Frontend;
+use super::{
+    Config, FrameCycles, Sound, VmErrorKind, Watchpoint, FRAME_DURATION, NUM_REGISTERS, STACK_SIZE,
+    VM,
+};
+
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+const INTERRUPT: u8 = 0x03;
+
+/// V0-VF followed by I, PC, SP, DT and ST.
+const NUM_GDB_REGISTERS: usize = NUM_REGISTERS + 5;
+const REG_I: usize = NUM_REGISTERS;
+const REG_PC: usize = NUM_REGISTERS + 1;
+const REG_SP: usize = NUM_REGISTERS + 2;
+const REG_DT: usize = NUM_REGISTERS + 3;
+const REG_ST: usize = NUM_REGISTERS + 4;
+
+/// Serves one connection speaking the GDB remote serial protocol, see
+/// https://sourceware.org/gdb/current/onlinedocs/gdb/Remote-Protocol.html
+///
+/// Runs until GDB detaches, kills the program or disconnects, and returns
+/// whether it detached, leaving the program running.
+pub(crate) fn serve<F: Frontend>(
+    vm: &mut VM,
+    config: &Config,
+    frontend: &mut F,
+    stream: TcpStream,
+) -> io::Result<bool> {
+    let mut connection = Connection::new(stream);
+    let mut stub = Stub::new();
+
+    while let Some(packet) = connection.read_packet()? {
+        let packet = match packet {
+            Packet::Command(packet) => packet,
+            // there is nothing to interrupt while stopped
+            Packet::Interrupt => continue,
+        };
+
+        match stub.handle(vm, &packet) {
+            Action::Reply(reply) => connection.send(&reply)?,
+            Action::Step => {
+                let stop = stub.step(vm, true).unwrap_or(Stop::Trap);
+                connection.send(&stub.stopped(stop))?;
+            }
+            Action::Continue => {
+                let stop = stub.resume(vm, config, frontend, &mut connection)?;
+                connection.send(&stub.stopped(stop))?;
+                if stop == Stop::Quit {
+                    return Ok(false);
+                }
+            }
+            Action::Detach => {
+                connection.send("OK")?;
+                return Ok(true);
+            }
+            Action::Kill => return Ok(false),
+        }
+    }
+
+    Ok(false)
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum Packet {
+    Command(String),
+    Interrupt,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum Action {
+    Reply(String),
+    Step,
+    Continue,
+    Detach,
+    Kill,
+}
+
+/// Why the program stopped running.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Stop {
+    Trap,
+    Interrupted,
+    Watch(Watchpoint),
+    Exited,
+    Error(VmErrorKind),
+    /// The frontend was closed.
+    Quit,
+}
+
+struct Stub {
+    breakpoints: BTreeSet<u16>,
+    last_stop: Stop,
+}
+
+impl Stub {
+    fn new() -> Stub {
+        Stub {
+            breakpoints: BTreeSet::new(),
+            last_stop: Stop::Trap,
+        }
+    }
+
+    fn handle(&mut self, vm: &mut VM, packet: &str) -> Action {
+        let reply = if packet == "?" {
+            stop_reply(self.last_stop)
+        } else if packet == "g" {
+            (0..NUM_GDB_REGISTERS)
+                .map(|n| encode_register(n, read_register(vm, n)))
+                .collect()
+        } else if let Some(values) = packet.strip_prefix('G') {
+            reply_ok(write_registers(vm, values))
+        } else if let Some(n) = packet.strip_prefix('p') {
+            match usize::from_str_radix(n, 16) {
+                Ok(n) if n < NUM_GDB_REGISTERS => encode_register(n, read_register(vm, n)),
+                _ => "E01".to_string(),
+            }
+        } else if let Some(args) = packet.strip_prefix('P') {
+            reply_ok(args.split_once('=').and_then(|(n, value)| {
+                let n = usize::from_str_radix(n, 16).ok()?;
+                write_register(vm, n, parse_register(n, value)?)
+            }))
+        } else if let Some(args) = packet.strip_prefix('m') {
+            match parse_range(args, vm.memory_size()) {
+                Some((start, len)) => vm.memory[start..start + len]
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect(),
+                None => "E01".to_string(),
+            }
+        } else if let Some(args) = packet.strip_prefix('M') {
+            reply_ok(write_memory(vm, args))
+        } else if let Some(addr) = packet.strip_prefix('c') {
+            return resume_at(vm, addr, Action::Continue);
+        } else if let Some(addr) = packet.strip_prefix('s') {
+            return resume_at(vm, addr, Action::Step);
+        } else if let Some(args) = packet.strip_prefix('Z') {
+            reply_ok(self.set_breakpoint(vm, args, true))
+        } else if let Some(args) = packet.strip_prefix('z') {
+            reply_ok(self.set_breakpoint(vm, args, false))
+        } else if packet.starts_with("qSupported") {
+            "PacketSize=1000;qXfer:features:read+".to_string()
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let xml = target_xml();
+            match parse_range(args, xml.len()) {
+                Some((offset, len)) => read_chunk(&xml, offset, len),
+                None => "E01".to_string(),
+            }
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else if packet.starts_with('H') {
+            "OK".to_string()
+        } else if packet.starts_with('D') {
+            return Action::Detach;
+        } else if packet == "k" {
+            return Action::Kill;
+        } else {
+            // an empty reply tells GDB that the packet is not supported
+            String::new()
+        };
+
+        Action::Reply(reply)
+    }
+
+    /// Handles the arguments of `Z` and `z` packets: a type, an address and
+    /// a kind, which for watchpoints is the number of bytes watched.
+    fn set_breakpoint(&mut self, vm: &mut VM, args: &str, insert: bool) -> Option<()> {
+        let mut args = args.split(',');
+        let kind = args.next()?;
+        let addr = u16::from_str_radix(args.next()?, 16).ok()?;
+        let len = usize::from_str_radix(args.next()?, 16).ok()?;
+
+        let watchpoints: &[fn(u16) -> Watchpoint] = match kind {
+            // software and hardware breakpoints are the same thing here
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                return Some(());
+            }
+            "2" => &[Watchpoint::Write],
+            "3" => &[Watchpoint::Read],
+            "4" => &[Watchpoint::Read, Watchpoint::Write],
+            _ => return None,
+        };
+
+        let end = (addr as usize).checked_add(len)?;
+        if end > vm.memory_size() {
+            return None;
+        }
+        for addr in addr as usize..end {
+            let addr = addr as u16;
+            for watchpoint in watchpoints {
+                if insert {
+                    vm.add_watchpoint(watchpoint(addr));
+                } else {
+                    vm.remove_watchpoint(watchpoint(addr));
+                }
+            }
+        }
+        Some(())
+    }
+
+    /// Executes one instruction, unless a breakpoint is set at PC. The
+    /// breakpoint a program was stopped at is ignored on the first step.
+    fn step(&self, vm: &mut VM, first: bool) -> Option<Stop> {
+        if vm.has_exited() {
+            return Some(Stop::Exited);
+        }
+        // a key wait repeats the instruction, which should only trap once
+        if !first && !vm.is_waiting_for_key() && self.breakpoints.contains(&vm.reg_pc) {
+            return Some(Stop::Trap);
+        }
+
+        match vm.step() {
+            Ok(Some(watchpoint)) => Some(Stop::Watch(watchpoint)),
+            Ok(None) if vm.has_exited() => Some(Stop::Exited),
+            Ok(None) => None,
+            Err(e) => Some(Stop::Error(e.kind)),
+        }
+    }
+
+    /// Runs the program frame by frame, like `run_with`, until it stops or
+    /// GDB interrupts it.
+    fn resume<F: Frontend>(
+        &self,
+        vm: &mut VM,
+        config: &Config,
+        frontend: &mut F,
+        connection: &mut Connection,
+    ) -> io::Result<Stop> {
+        let mut sound = Sound::default();
+        let mut frame_cycles = FrameCycles::new(config);
+        let mut first = true;
+
+        let stop = 'frames: loop {
+            for _ in 0..frame_cycles.next_frame() {
+                if let Some(stop) = self.step(vm, first) {
+                    break 'frames stop;
+                }
+                first = false;
+            }
+
+            sound.update(vm, frontend);
+            frontend.present(&vm.display);
+
+            if !frontend.poll_input(&mut vm.keypad) {
+                break Stop::Quit;
+            }
+            if connection.poll_interrupt()? {
+                break Stop::Interrupted;
+            }
+            thread::sleep(FRAME_DURATION);
+        };
+
+        // the buzzer should not keep going while the program is stopped
+        sound.stop(frontend);
+        frontend.present(&vm.display);
+        Ok(stop)
+    }
+
+    fn stopped(&mut self, stop: Stop) -> String {
+        self.last_stop = stop;
+        stop_reply(stop)
+    }
+}
+
+fn stop_reply(stop: Stop) -> String {
+    match stop {
+        Stop::Trap | Stop::Watch(Watchpoint::Register(..)) => "S05".to_string(),
+        Stop::Interrupted => "S02".to_string(),
+        Stop::Watch(Watchpoint::Write(addr)) => format!("T05watch:{:x};", addr),
+        Stop::Watch(Watchpoint::Read(addr)) => format!("T05rwatch:{:x};", addr),
+        Stop::Exited => "W00".to_string(),
+        // SIGILL for bad instructions, SIGSEGV for bad addresses
+        Stop::Error(VmErrorKind::UnknownOpcode) | Stop::Error(VmErrorKind::InvalidKey(_)) => {
+            "S04".to_string()
+        }
+        Stop::Error(_) => "S0b".to_string(),
+        Stop::Quit => "X09".to_string(),
+    }
+}
+
+fn reply_ok(result: Option<()>) -> String {
+    match result {
+        Some(()) => "OK".to_string(),
+        None => "E01".to_string(),
+    }
+}
+
+/// Handles `c` and `s`, which may give an address to resume at.
+fn resume_at(vm: &mut VM, addr: &str, action: Action) -> Action {
+    if !addr.is_empty() {
+        match u16::from_str_radix(addr, 16) {
+            Ok(addr) => vm.set_pc(addr),
+            Err(_) => return Action::Reply("E01".to_string()),
+        }
+    }
+    action
+}
+
+fn register_size(n: usize) -> usize {
+    match n {
+        REG_I | REG_PC => 2,
+        _ => 1,
+    }
+}
+
+fn read_register(vm: &VM, n: usize) -> u16 {
+    match n {
+        REG_I => vm.reg_i,
+        REG_PC => vm.reg_pc,
+        REG_SP => vm.reg_sp as u16,
+        REG_DT => vm.reg_delay as u16,
+        REG_ST => vm.reg_sound as u16,
+        _ => vm.gen_registers[n] as u16,
+    }
+}
+
+fn write_register(vm: &mut VM, n: usize, value: u16) -> Option<()> {
+    match n {
+        REG_I => vm.reg_i = value,
+        REG_PC => vm.set_pc(value),
+        REG_SP if value as usize <= STACK_SIZE => vm.reg_sp = value as u8,
+        REG_SP => return None,
+        REG_DT => vm.reg_delay = value as u8,
+        REG_ST => vm.reg_sound = value as u8,
+        _ if n < NUM_REGISTERS => vm.gen_registers[n] = value as u8,
+        _ => return None,
+    }
+    Some(())
+}
+
+// Registers are sent little-endian, which is what GDB assumes for a target
+// that does not declare an architecture.
+fn encode_register(n: usize, value: u16) -> String {
+    value.to_le_bytes()[..register_size(n)]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn parse_register(n: usize, hex: &str) -> Option<u16> {
+    if n >= NUM_GDB_REGISTERS || hex.len() != register_size(n) * 2 {
+        return None;
+    }
+    let mut bytes = [0; 2];
+    for (i, byte) in bytes.iter_mut().take(register_size(n)).enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(u16::from_le_bytes(bytes))
+}
+
+fn write_registers(vm: &mut VM, hex: &str) -> Option<()> {
+    let mut values = Vec::with_capacity(NUM_GDB_REGISTERS);
+    let mut rest = hex;
+    for n in 0..NUM_GDB_REGISTERS {
+        let len = register_size(n) * 2;
+        if rest.len() < len {
+            return None;
+        }
+        let (value, tail) = rest.split_at(len);
+        values.push(parse_register(n, value)?);
+        rest = tail;
+    }
+    if !rest.is_empty() || values[REG_SP] as usize > STACK_SIZE {
+        return None;
+    }
+
+    for (n, value) in values.into_iter().enumerate() {
+        write_register(vm, n, value)?;
+    }
+    Some(())
+}
+
+/// Parses `addr,length` into a range of the `size` bytes that can be read.
+fn parse_range(args: &str, size: usize) -> Option<(usize, usize)> {
+    let (start, len) = args.split_once(',')?;
+    let start = usize::from_str_radix(start, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    if start > size {
+        return None;
+    }
+    Some((start, len.min(size - start)))
+}
+
+fn write_memory(vm: &mut VM, args: &str) -> Option<()> {
+    let (range, hex) = args.split_once(':')?;
+    let (start, len) = range.split_once(',')?;
+    let start = usize::from_str_radix(start, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    // the values come straight from the packet, so they may overflow
+    let end = start.checked_add(len)?;
+    if end > vm.memory_size() || Some(hex.len()) != len.checked_mul(2) {
+        return None;
+    }
+
+    let bytes = (0..len)
+        .map(|i| u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    vm.memory[start..end].copy_from_slice(&bytes);
+    Some(())
+}
+
+/// Describes the registers, since GDB does not know about CHIP-8.
+fn target_xml() -> String {
+    let mut registers = String::new();
+    for n in 0..NUM_REGISTERS {
+        registers.push_str(&format!(
+            "<reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\"/>",
+            n
+        ));
+    }
+
+    format!(
+        "<?xml version=\"1.0\"?>\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\">\
+         <feature name=\"org.chip8.core\">\
+         {}\
+         <reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>\
+         <reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>\
+         <reg name=\"sp\" bitsize=\"8\" type=\"uint8\"/>\
+         <reg name=\"dt\" bitsize=\"8\" type=\"uint8\"/>\
+         <reg name=\"st\" bitsize=\"8\" type=\"uint8\"/>\
+         </feature>\
+         </target>",
+        registers
+    )
+}
+
+/// Replies to a `qXfer` read, marking the last chunk with `l`.
+fn read_chunk(data: &str, offset: usize, len: usize) -> String {
+    let start = offset.min(data.len());
+    let end = start.saturating_add(len).min(data.len());
+    let marker = if end == data.len() { 'l' } else { 'm' };
+    format!("{}{}", marker, &data[start..end])
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+struct Connection {
+    stream: TcpStream,
+    pending: VecDeque<u8>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Connection {
+        Connection {
+            stream,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Reads more bytes from the stream. Returns `false` once it is closed.
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 1024];
+        let n = self.stream.read(&mut buf)?;
+        self.pending.extend(&buf[..n]);
+        Ok(n > 0)
+    }
+
+    fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        loop {
+            if let Some(b) = self.pending.pop_front() {
+                return Ok(Some(b));
+            }
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Reads the next packet, acknowledging it. Returns `None` once the
+    /// connection is closed.
+    fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            match self.next_byte()? {
+                None => return Ok(None),
+                Some(INTERRUPT) => return Ok(Some(Packet::Interrupt)),
+                Some(b'$') => {}
+                // acknowledgements of our replies, which are not resent
+                Some(_) => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.next_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                }
+            }
+            let mut sum = [0; 2];
+            for digit in sum.iter_mut() {
+                match self.next_byte()? {
+                    None => return Ok(None),
+                    Some(b) => *digit = b,
+                }
+            }
+
+            let sum = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+            if sum == Some(checksum(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(Packet::Command(
+                    String::from_utf8_lossy(&data).into_owned(),
+                )));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    /// Checks without blocking whether GDB has sent an interrupt. A closed
+    /// connection counts as one, so that the program stops.
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let filled = self.fill();
+        self.stream.set_nonblocking(false)?;
+
+        match filled {
+            Ok(false) => return Ok(true),
+            Ok(true) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+
+        match self.pending.iter().position(|&b| b == INTERRUPT) {
+            Some(i) => {
+                self.pending.remove(i);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frontend::Headless;
+    use crate::{test_vm, Quirks};
+    use std::net::TcpListener;
+
+    fn reply(stub: &mut Stub, vm: &mut VM, packet: &str) -> String {
+        match stub.handle(vm, packet) {
+            Action::Reply(reply) => reply,
+            action => panic!("expected a reply, got {:?}", action),
+        }
+    }
+
+    #[test]
+    fn read_registers() {
+        let mut vm = test_vm(&[]);
+        let mut stub = Stub::new();
+        vm.gen_registers[0] = 0x12;
+        vm.gen_registers[0xF] = 0x01;
+        vm.reg_i = 0x345;
+        vm.reg_delay = 0x20;
+
+        let v = format!("12{}01", "00".repeat(14));
+        assert_eq!(
+            reply(&mut stub, &mut vm, "g"),
+            [v.as_str(), "4503", "0002", "00", "20", "00"].concat()
+        );
+        assert_eq!(reply(&mut stub, &mut vm, "p10"), "4503");
+        assert_eq!(reply(&mut stub, &mut vm, "pf"), "01");
+        assert_eq!(reply(&mut stub, &mut vm, "p15"), "E01");
+    }
+
+    #[test]
+    fn write_registers() {
+        let mut vm = test_vm(&[]);
+        let mut stub = Stub::new();
+
+        assert_eq!(reply(&mut stub, &mut vm, "P11=0003"), "OK");
+        assert_eq!(vm.reg_pc, 0x300);
+        assert_eq!(reply(&mut stub, &mut vm, "P3=ab"), "OK");
+        assert_eq!(vm.gen_registers[3], 0xAB);
+        assert_eq!(reply(&mut stub, &mut vm, "P3=abcd"), "E01");
+        assert_eq!(reply(&mut stub, &mut vm, "P12=11"), "E01");
+
+        let registers = "0102030405060708090a0b0c0d0e0f10341222020301ff";
+        assert_eq!(reply(&mut stub, &mut vm, &format!("G{}", registers)), "OK");
+        assert_eq!(vm.gen_registers[0xF], 0x10);
+        assert_eq!(vm.reg_i, 0x1234);
+        assert_eq!(vm.reg_pc, 0x222);
+        assert_eq!(vm.reg_sp, 3);
+        assert_eq!(vm.reg_sound, 0xFF);
+        assert_eq!(reply(&mut stub, &mut vm, "G0102"), "E01");
+    }
+
+    #[test]
+    fn moving_pc_stops_key_wait() {
+        // LD V0, K
+        let mut vm = test_vm(&[0xF0, 0x0A]);
+        let mut stub = Stub::new();
+        vm.step().unwrap();
+        assert!(vm.is_waiting_for_key());
+
+        // writing the same PC back leaves the wait alone
+        assert_eq!(reply(&mut stub, &mut vm, "P11=0002"), "OK");
+        assert!(vm.is_waiting_for_key());
+        assert_eq!(reply(&mut stub, &mut vm, "P11=0202"), "OK");
+        assert!(!vm.is_waiting_for_key());
+
+        vm.set_pc(0x200);
+        vm.step().unwrap();
+        assert_eq!(resume_at(&mut vm, "202", Action::Step), Action::Step);
+        assert!(!vm.is_waiting_for_key());
+    }
+
+    #[test]
+    fn memory() {
+        let mut vm = test_vm(&[0xAB, 0xCD]);
+        let mut stub = Stub::new();
+
+        assert_eq!(reply(&mut stub, &mut vm, "m200,3"), "abcd00");
+        assert_eq!(reply(&mut stub, &mut vm, "mffe,4"), "0000");
+        assert_eq!(reply(&mut stub, &mut vm, "m1001,1"), "E01");
+        assert_eq!(reply(&mut stub, &mut vm, "M300,2:1234"), "OK");
+        assert_eq!(vm.memory[0x300..0x302], [0x12, 0x34]);
+        assert_eq!(reply(&mut stub, &mut vm, "Mfff,2:1234"), "E01");
+        assert_eq!(reply(&mut stub, &mut vm, "M300,2:12"), "E01");
+        assert_eq!(reply(&mut stub, &mut vm, "M1,ffffffffffffffff:"), "E01");
+        assert_eq!(reply(&mut stub, &mut vm, "M1,8000000000000000:"), "E01");
+        assert_eq!(
+            reply(&mut stub, &mut vm, "m1,ffffffffffffffff").len(),
+            2 * 0xFFF
+        );
+
+        vm.set_quirks(Quirks::MODERN);
+        assert_eq!(reply(&mut stub, &mut vm, "mfffe,4"), "0000");
+        assert_eq!(reply(&mut stub, &mut vm, "Mffff,1:12"), "OK");
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut vm = test_vm(&[0x60, 0x01, 0x61, 0x02, 0x62, 0x03]);
+        let mut stub = Stub::new();
+
+        assert_eq!(reply(&mut stub, &mut vm, "Z0,202,2"), "OK");
+        assert_eq!(stub.step(&mut vm, true), None);
+        assert_eq!(stub.step(&mut vm, false), Some(Stop::Trap));
+        assert_eq!(vm.reg_pc, 0x202);
+
+        // resuming from a breakpoint steps over it
+        assert_eq!(stub.step(&mut vm, true), None);
+        assert_eq!(reply(&mut stub, &mut vm, "z0,202,2"), "OK");
+        assert!(stub.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut vm = test_vm(&[0xA3, 0x00, 0xF0, 0x55]);
+        let mut stub = Stub::new();
+
+        assert_eq!(reply(&mut stub, &mut vm, "Z2,300,1"), "OK");
+        assert_eq!(stub.step(&mut vm, true), None);
+        let stop = stub.step(&mut vm, false).unwrap();
+        assert_eq!(stop, Stop::Watch(Watchpoint::Write(0x300)));
+        assert_eq!(stop_reply(stop), "T05watch:300;");
+
+        assert_eq!(reply(&mut stub, &mut vm, "z2,300,1"), "OK");
+        assert!(vm.watchpoints().is_empty());
+        assert_eq!(reply(&mut stub, &mut vm, "Z9,300,1"), "E01");
+        assert_eq!(reply(&mut stub, &mut vm, "Z2,300,ffffffffffffffff"), "E01");
+        assert!(vm.watchpoints().is_empty());
+    }
+
+    #[test]
+    fn resume_packets() {
+        let mut vm = test_vm(&[]);
+        let mut stub = Stub::new();
+
+        assert_eq!(stub.handle(&mut vm, "c"), Action::Continue);
+        assert_eq!(stub.handle(&mut vm, "s2a0"), Action::Step);
+        assert_eq!(vm.reg_pc, 0x2A0);
+        assert_eq!(stub.handle(&mut vm, "D"), Action::Detach);
+        assert_eq!(stub.handle(&mut vm, "k"), Action::Kill);
+    }
+
+    #[test]
+    fn queries() {
+        let mut vm = test_vm(&[]);
+        let mut stub = Stub::new();
+
+        assert_eq!(reply(&mut stub, &mut vm, "?"), "S05");
+        assert!(
+            reply(&mut stub, &mut vm, "qSupported:multiprocess+").contains("qXfer:features:read+")
+        );
+        assert_eq!(reply(&mut stub, &mut vm, "vMustReplyEmpty"), "");
+
+        let xml = target_xml();
+        let first = reply(&mut stub, &mut vm, "qXfer:features:read:target.xml:0,10");
+        assert_eq!(first, format!("m{}", &xml[..0x10]));
+        let rest = reply(&mut stub, &mut vm, "qXfer:features:read:target.xml:10,1000");
+        assert_eq!(rest, format!("l{}", &xml[0x10..]));
+    }
+
+    #[test]
+    fn stop_replies() {
+        assert_eq!(stop_reply(Stop::Interrupted), "S02");
+        assert_eq!(stop_reply(Stop::Exited), "W00");
+        assert_eq!(stop_reply(Stop::Error(VmErrorKind::UnknownOpcode)), "S04");
+        assert_eq!(stop_reply(Stop::Error(VmErrorKind::StackOverflow)), "S0b");
+        assert_eq!(
+            stop_reply(Stop::Watch(Watchpoint::Read(0x3f0))),
+            "T05rwatch:3f0;"
+        );
+    }
+
+    fn send(stream: &mut TcpStream, data: &str) {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        stream.write_all(packet.as_bytes()).unwrap();
+    }
+
+    fn receive(stream: &mut TcpStream) -> String {
+        let mut received = Vec::new();
+        let mut byte = [0];
+        // skip the acknowledgement, then read up to the checksum
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            received.push(byte[0]);
+        }
+        let mut sum = [0; 2];
+        stream.read_exact(&mut sum).unwrap();
+        assert_eq!(
+            u8::from_str_radix(std::str::from_utf8(&sum).unwrap(), 16).unwrap(),
+            checksum(&received)
+        );
+        String::from_utf8(received).unwrap()
+    }
+
+    #[test]
+    fn session() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut vm = test_vm(&[0x60, 0x01, 0x61, 0x02, 0x12, 0x04]);
+            serve(&mut vm, &Config::default(), &mut Headless, stream).unwrap();
+            vm.gen_registers[1]
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        // a bad checksum is rejected
+        client.write_all(b"$g#00").unwrap();
+        let mut nack = [0];
+        client.read_exact(&mut nack).unwrap();
+        assert_eq!(&nack, b"-");
+
+        send(&mut client, "Z0,204,2");
+        assert_eq!(receive(&mut client), "OK");
+        send(&mut client, "c");
+        assert_eq!(receive(&mut client), "S05");
+        send(&mut client, "p11");
+        assert_eq!(receive(&mut client), "0402");
+
+        // the program loops forever until it is interrupted
+        send(&mut client, "z0,204,2");
+        assert_eq!(receive(&mut client), "OK");
+        send(&mut client, "c");
+        client.write_all(&[INTERRUPT]).unwrap();
+        assert_eq!(receive(&mut client), "S02");
+
+        send(&mut client, "k");
+        assert_eq!(server.join().unwrap(), 0x02);
+    }
+}
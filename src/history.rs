@@ -0,0 +1,258 @@
+use super::display::Display;
+use super::rng::Rng;
+use super::{WaitingForKey, VM};
+use super::{AUDIO_PATTERN_SIZE, NUM_REGISTERS, NUM_RPL_FLAGS, STACK_SIZE};
+
+use std::collections::VecDeque;
+use std::mem;
+
+/// A bounded log of what each instruction changed, for `VM::step_back`.
+///
+/// Before every instruction the registers are copied, and so is the display
+/// if the instruction changes it. Memory is only copied where the
+/// instruction writes to it. Once the log grows past its capacity, the oldest
+/// entries are dropped.
+#[derive(Default)]
+pub(crate) struct History {
+    capacity: usize,
+    used: usize,
+    entries: VecDeque<Entry>,
+}
+
+struct Entry {
+    registers: Registers,
+    /// The display before the instruction, kept only if it changed.
+    display: Option<Box<Display>>,
+    /// The bytes overwritten by the instruction, in the order written.
+    memory: Vec<(usize, Vec<u8>)>,
+}
+
+/// Everything an instruction can change besides memory and the display.
+struct Registers {
+    stack: [u16; STACK_SIZE],
+    gen_registers: [u8; NUM_REGISTERS],
+    reg_i: u16,
+    reg_pc: u16,
+    reg_sp: u8,
+    reg_delay: u8,
+    reg_sound: u8,
+    rpl_flags: [u8; NUM_RPL_FLAGS],
+    audio_pattern: Option<[u8; AUDIO_PATTERN_SIZE]>,
+    reg_pitch: u8,
+    rng: Rng,
+    timer_cycles: u32,
+    drawn_this_frame: bool,
+    exited: bool,
+    waiting_for_key: Option<WaitingForKey>,
+}
+
+impl History {
+    /// Creates a log using about `capacity` bytes. A capacity of zero
+    /// records nothing.
+    pub fn new(capacity: usize) -> History {
+        History {
+            capacity,
+            used: 0,
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.used = 0;
+        self.entries.clear();
+    }
+
+    /// Starts an entry for the instruction `vm` is about to execute.
+    pub fn begin(&mut self, vm: &VM) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.entries.push_back(Entry {
+            registers: Registers::of(vm),
+            display: Some(Box::new(vm.display)),
+            memory: Vec::new(),
+        });
+    }
+
+    /// Records the bytes an instruction is about to overwrite.
+    pub fn write(&mut self, start: usize, old: &[u8]) {
+        if let Some(entry) = self.entries.back_mut() {
+            entry.memory.push((start, old.to_vec()));
+        }
+    }
+
+    /// Finishes the entry for an instruction that executed successfully.
+    pub fn end(&mut self, display: &Display) {
+        let entry = match self.entries.back_mut() {
+            Some(entry) => entry,
+            None => return,
+        };
+        if entry.display.as_deref() == Some(display) {
+            entry.display = None;
+        }
+        self.used += entry.size();
+
+        while self.used > self.capacity && self.entries.len() > 1 {
+            let oldest = self.entries.pop_front().unwrap();
+            self.used -= oldest.size();
+        }
+    }
+
+    /// Drops the entry for an instruction that failed, which left the VM as
+    /// it was.
+    pub fn cancel(&mut self) {
+        self.entries.pop_back();
+    }
+
+    /// Undoes the last recorded instruction, returning `false` when there
+    /// are none left.
+    pub fn undo(&mut self, vm: &mut VM) -> bool {
+        let entry = match self.entries.pop_back() {
+            Some(entry) => entry,
+            None => return false,
+        };
+        self.used -= entry.size();
+
+        for (start, old) in entry.memory.iter().rev() {
+            vm.memory[*start..*start + old.len()].copy_from_slice(old);
+        }
+        if let Some(display) = entry.display {
+            vm.display = *display;
+        }
+        entry.registers.restore(vm);
+        true
+    }
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        let display = self
+            .display
+            .as_ref()
+            .map_or(0, |_| mem::size_of::<Display>());
+        let memory: usize = self
+            .memory
+            .iter()
+            .map(|(_, old)| mem::size_of::<(usize, Vec<u8>)>() + old.len())
+            .sum();
+        mem::size_of::<Entry>() + display + memory
+    }
+}
+
+impl Registers {
+    fn of(vm: &VM) -> Registers {
+        Registers {
+            stack: vm.stack,
+            gen_registers: vm.gen_registers,
+            reg_i: vm.reg_i,
+            reg_pc: vm.reg_pc,
+            reg_sp: vm.reg_sp,
+            reg_delay: vm.reg_delay,
+            reg_sound: vm.reg_sound,
+            rpl_flags: vm.rpl_flags,
+            audio_pattern: vm.audio_pattern,
+            reg_pitch: vm.reg_pitch,
+            rng: vm.rng,
+            timer_cycles: vm.timer_cycles,
+            drawn_this_frame: vm.drawn_this_frame,
+            exited: vm.exited,
+            waiting_for_key: vm.waiting_for_key,
+        }
+    }
+
+    fn restore(self, vm: &mut VM) {
+        vm.stack = self.stack;
+        vm.gen_registers = self.gen_registers;
+        vm.reg_i = self.reg_i;
+        vm.reg_pc = self.reg_pc;
+        vm.reg_sp = self.reg_sp;
+        vm.reg_delay = self.reg_delay;
+        vm.reg_sound = self.reg_sound;
+        vm.rpl_flags = self.rpl_flags;
+        vm.audio_pattern = self.audio_pattern;
+        vm.reg_pitch = self.reg_pitch;
+        vm.rng = self.rng;
+        vm.timer_cycles = self.timer_cycles;
+        vm.drawn_this_frame = self.drawn_this_frame;
+        vm.exited = self.exited;
+        vm.waiting_for_key = self.waiting_for_key;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_vm;
+
+    fn create_vm(rom: &[u8]) -> VM {
+        let mut vm = test_vm(rom);
+        vm.set_history(1 << 20);
+        vm
+    }
+
+    #[test]
+    fn undo_memory_writes() {
+        // LD I, 0x300; LD V0, 0xAB; LD B, V0; LD [I], V2
+        let mut vm = create_vm(&[0xA3, 0x00, 0x60, 0xAB, 0xF0, 0x33, 0xF2, 0x55]);
+        for _ in 0..4 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.memory[0x300..0x303], [0xAB, 0x00, 0x00]);
+        assert!(vm.step_back());
+        assert_eq!(vm.memory[0x300..0x303], [1, 7, 1]);
+        assert!(vm.step_back());
+        assert_eq!(vm.memory[0x300..0x303], [0, 0, 0]);
+        assert_eq!(vm.reg_pc, 0x204);
+    }
+
+    #[test]
+    fn undo_drawing() {
+        // LD I, 0x300; DRW V0, V0, 1 with a full row at 0x300
+        let mut vm = create_vm(&[0xA3, 0x00, 0xD0, 0x01]);
+        vm.memory[0x300] = 0xFF;
+        vm.step().unwrap();
+        let before = vm.display;
+        vm.step().unwrap();
+        assert_ne!(vm.display, before);
+
+        assert!(vm.step_back());
+        assert_eq!(vm.display, before);
+    }
+
+    #[test]
+    fn display_kept_only_when_changed() {
+        // LD V0, 5; DRW V0, V0, 1 with I pointing at the font
+        let mut vm = create_vm(&[0x60, 0x05, 0xD0, 0x01]);
+        vm.step().unwrap();
+        assert!(vm.history.entries[0].display.is_none());
+        vm.step().unwrap();
+        assert!(vm.history.entries[1].display.is_some());
+        assert!(vm.history.used < 2 * mem::size_of::<Display>() + 2 * mem::size_of::<Entry>());
+    }
+
+    #[test]
+    fn capacity() {
+        let mut vm = test_vm(&[0x70, 0x01, 0x12, 0x00]);
+        vm.set_history(10 * mem::size_of::<Entry>());
+        for _ in 0..100 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.history.entries.len(), 10);
+
+        for _ in 0..10 {
+            assert!(vm.step_back());
+        }
+        assert!(!vm.step_back());
+        assert_eq!(vm.gen_registers[0], 45);
+        assert_eq!(vm.history.used, 0);
+    }
+
+    #[test]
+    fn cleared_by_loading() {
+        let mut vm = create_vm(&[0x60, 0x05]);
+        let state = vm.save_state();
+        vm.step().unwrap();
+        vm.load_state(&state).unwrap();
+        assert!(!vm.step_back());
+    }
+}
@@ -0,0 +1,438 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Instruction {
+    SYS(u16),
+    CLS,
+    RET,
+    JP(u16),
+    CALL(u16),
+    SE(u8, u8),
+    SNE(u8, u8),
+    SE2(u8, u8),
+    LD(u8, u8),
+    ADD(u8, u8),
+    LD2(u8, u8),
+    OR(u8, u8),
+    AND(u8, u8),
+    XOR(u8, u8),
+    ADD2(u8, u8),
+    SUB(u8, u8),
+    SHR(u8, u8),
+    SUBN(u8, u8),
+    SHL(u8, u8),
+    SNE2(u8, u8),
+    LDI(u16),
+    JPV0(u16),
+    RND(u8, u8),
+    DRW(u8, u8, u8),
+    SKP(u8),
+    SKNP(u8),
+    LD3(u8),
+    LD4(u8),
+    LD5(u8),
+    LD6(u8),
+    ADD3(u8),
+    LD7(u8),
+    LD8(u8),
+    LD9(u8),
+    LD10(u8),
+
+    // SUPER-CHIP
+    SCD(u8),
+    SCR,
+    SCL,
+    EXIT,
+    LOW,
+    HIGH,
+    LD11(u8),
+    LD12(u8),
+    LD13(u8),
+
+    // XO-CHIP
+    SCU(u8),
+    SAVE(u8, u8),
+    LOAD(u8, u8),
+    /// Loads I with the 16-bit address in the word following the opcode.
+    LDIL,
+    PLANE(u8),
+    AUDIO,
+    PITCH(u8),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    UnknownOpcode(u16),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnknownOpcode(opcode) => write!(f, "unknown opcode {:#06X}", opcode),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+impl Instruction {
+    /// Decodes a big-endian CHIP-8 opcode into an instruction.
+    pub fn decode(opcode: u16) -> Result<Instruction, DecodeError> {
+        let nnn = opcode & 0x0FFF;
+        let x = ((opcode >> 8) & 0xF) as u8;
+        let y = ((opcode >> 4) & 0xF) as u8;
+        let n = (opcode & 0xF) as u8;
+        let kk = (opcode & 0xFF) as u8;
+
+        let instr = match opcode >> 12 {
+            0x0 => match opcode {
+                0x00E0 => Instruction::CLS,
+                0x00EE => Instruction::RET,
+                0x00FB => Instruction::SCR,
+                0x00FC => Instruction::SCL,
+                0x00FD => Instruction::EXIT,
+                0x00FE => Instruction::LOW,
+                0x00FF => Instruction::HIGH,
+                _ if opcode & 0xFFF0 == 0x00C0 => Instruction::SCD(n),
+                _ if opcode & 0xFFF0 == 0x00D0 => Instruction::SCU(n),
+                _ => Instruction::SYS(nnn),
+            },
+            0x1 => Instruction::JP(nnn),
+            0x2 => Instruction::CALL(nnn),
+            0x3 => Instruction::SE(x, kk),
+            0x4 => Instruction::SNE(x, kk),
+            0x5 => match n {
+                0x0 => Instruction::SE2(x, y),
+                0x2 => Instruction::SAVE(x, y),
+                0x3 => Instruction::LOAD(x, y),
+                _ => return Err(DecodeError::UnknownOpcode(opcode)),
+            },
+            0x6 => Instruction::LD(x, kk),
+            0x7 => Instruction::ADD(x, kk),
+            0x8 => match n {
+                0x0 => Instruction::LD2(x, y),
+                0x1 => Instruction::OR(x, y),
+                0x2 => Instruction::AND(x, y),
+                0x3 => Instruction::XOR(x, y),
+                0x4 => Instruction::ADD2(x, y),
+                0x5 => Instruction::SUB(x, y),
+                0x6 => Instruction::SHR(x, y),
+                0x7 => Instruction::SUBN(x, y),
+                0xE => Instruction::SHL(x, y),
+                _ => return Err(DecodeError::UnknownOpcode(opcode)),
+            },
+            0x9 if n == 0x0 => Instruction::SNE2(x, y),
+            0xA => Instruction::LDI(nnn),
+            0xB => Instruction::JPV0(nnn),
+            0xC => Instruction::RND(x, kk),
+            0xD => Instruction::DRW(x, y, n),
+            0xE => match kk {
+                0x9E => Instruction::SKP(x),
+                0xA1 => Instruction::SKNP(x),
+                _ => return Err(DecodeError::UnknownOpcode(opcode)),
+            },
+            0xF => match kk {
+                0x00 if x == 0x0 => Instruction::LDIL,
+                0x01 => Instruction::PLANE(x),
+                0x02 if x == 0x0 => Instruction::AUDIO,
+                0x07 => Instruction::LD3(x),
+                0x0A => Instruction::LD4(x),
+                0x15 => Instruction::LD5(x),
+                0x18 => Instruction::LD6(x),
+                0x1E => Instruction::ADD3(x),
+                0x29 => Instruction::LD7(x),
+                0x33 => Instruction::LD8(x),
+                0x55 => Instruction::LD9(x),
+                0x65 => Instruction::LD10(x),
+                0x30 => Instruction::LD11(x),
+                0x75 => Instruction::LD12(x),
+                0x85 => Instruction::LD13(x),
+                0x3A => Instruction::PITCH(x),
+                _ => return Err(DecodeError::UnknownOpcode(opcode)),
+            },
+            _ => return Err(DecodeError::UnknownOpcode(opcode)),
+        };
+
+        Ok(instr)
+    }
+
+    /// Encodes the instruction into its big-endian CHIP-8 opcode.
+    ///
+    /// Operands wider than their field are truncated, so `SYS(0x0E0)` encodes
+    /// to the same opcode as `CLS`.
+    pub fn encode(&self) -> u16 {
+        match *self {
+            Instruction::SYS(addr) => addr & 0x0FFF,
+            Instruction::CLS => 0x00E0,
+            Instruction::RET => 0x00EE,
+            Instruction::JP(addr) => 0x1000 | (addr & 0x0FFF),
+            Instruction::CALL(addr) => 0x2000 | (addr & 0x0FFF),
+            Instruction::SE(x, byte) => encode_xkk(0x3, x, byte),
+            Instruction::SNE(x, byte) => encode_xkk(0x4, x, byte),
+            Instruction::SE2(x, y) => encode_xyn(0x5, x, y, 0x0),
+            Instruction::LD(x, byte) => encode_xkk(0x6, x, byte),
+            Instruction::ADD(x, byte) => encode_xkk(0x7, x, byte),
+            Instruction::LD2(x, y) => encode_xyn(0x8, x, y, 0x0),
+            Instruction::OR(x, y) => encode_xyn(0x8, x, y, 0x1),
+            Instruction::AND(x, y) => encode_xyn(0x8, x, y, 0x2),
+            Instruction::XOR(x, y) => encode_xyn(0x8, x, y, 0x3),
+            Instruction::ADD2(x, y) => encode_xyn(0x8, x, y, 0x4),
+            Instruction::SUB(x, y) => encode_xyn(0x8, x, y, 0x5),
+            Instruction::SHR(x, y) => encode_xyn(0x8, x, y, 0x6),
+            Instruction::SUBN(x, y) => encode_xyn(0x8, x, y, 0x7),
+            Instruction::SHL(x, y) => encode_xyn(0x8, x, y, 0xE),
+            Instruction::SNE2(x, y) => encode_xyn(0x9, x, y, 0x0),
+            Instruction::LDI(addr) => 0xA000 | (addr & 0x0FFF),
+            Instruction::JPV0(addr) => 0xB000 | (addr & 0x0FFF),
+            Instruction::RND(x, byte) => encode_xkk(0xC, x, byte),
+            Instruction::DRW(x, y, n) => encode_xyn(0xD, x, y, n),
+            Instruction::SKP(x) => encode_xkk(0xE, x, 0x9E),
+            Instruction::SKNP(x) => encode_xkk(0xE, x, 0xA1),
+            Instruction::LD3(x) => encode_xkk(0xF, x, 0x07),
+            Instruction::LD4(x) => encode_xkk(0xF, x, 0x0A),
+            Instruction::LD5(x) => encode_xkk(0xF, x, 0x15),
+            Instruction::LD6(x) => encode_xkk(0xF, x, 0x18),
+            Instruction::ADD3(x) => encode_xkk(0xF, x, 0x1E),
+            Instruction::LD7(x) => encode_xkk(0xF, x, 0x29),
+            Instruction::LD8(x) => encode_xkk(0xF, x, 0x33),
+            Instruction::LD9(x) => encode_xkk(0xF, x, 0x55),
+            Instruction::LD10(x) => encode_xkk(0xF, x, 0x65),
+            Instruction::SCD(n) => 0x00C0 | (n as u16 & 0xF),
+            Instruction::SCR => 0x00FB,
+            Instruction::SCL => 0x00FC,
+            Instruction::EXIT => 0x00FD,
+            Instruction::LOW => 0x00FE,
+            Instruction::HIGH => 0x00FF,
+            Instruction::LD11(x) => encode_xkk(0xF, x, 0x30),
+            Instruction::LD12(x) => encode_xkk(0xF, x, 0x75),
+            Instruction::LD13(x) => encode_xkk(0xF, x, 0x85),
+            Instruction::SCU(n) => 0x00D0 | (n as u16 & 0xF),
+            Instruction::SAVE(x, y) => encode_xyn(0x5, x, y, 0x2),
+            Instruction::LOAD(x, y) => encode_xyn(0x5, x, y, 0x3),
+            Instruction::LDIL => 0xF000,
+            Instruction::PLANE(n) => encode_xkk(0xF, n, 0x01),
+            Instruction::AUDIO => 0xF002,
+            Instruction::PITCH(x) => encode_xkk(0xF, x, 0x3A),
+        }
+    }
+}
+
+/// Formats the instruction as a Cowgod-style mnemonic, such as `LD V1, 0x0A`.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::SYS(addr) => write!(f, "SYS {:#05X}", addr),
+            Instruction::CLS => write!(f, "CLS"),
+            Instruction::RET => write!(f, "RET"),
+            Instruction::JP(addr) => write!(f, "JP {:#05X}", addr),
+            Instruction::CALL(addr) => write!(f, "CALL {:#05X}", addr),
+            Instruction::SE(x, byte) => write!(f, "SE V{:X}, {:#04X}", x, byte),
+            Instruction::SNE(x, byte) => write!(f, "SNE V{:X}, {:#04X}", x, byte),
+            Instruction::SE2(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::LD(x, byte) => write!(f, "LD V{:X}, {:#04X}", x, byte),
+            Instruction::ADD(x, byte) => write!(f, "ADD V{:X}, {:#04X}", x, byte),
+            Instruction::LD2(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::OR(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::AND(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::XOR(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::ADD2(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::SUB(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::SHR(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::SUBN(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::SHL(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SNE2(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LDI(addr) => write!(f, "LD I, {:#05X}", addr),
+            Instruction::JPV0(addr) => write!(f, "JP V0, {:#05X}", addr),
+            Instruction::RND(x, byte) => write!(f, "RND V{:X}, {:#04X}", x, byte),
+            Instruction::DRW(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SKP(x) => write!(f, "SKP V{:X}", x),
+            Instruction::SKNP(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LD3(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::LD4(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::LD5(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::LD6(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::ADD3(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::LD7(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::LD8(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::LD9(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::LD10(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::SCD(n) => write!(f, "SCD {}", n),
+            Instruction::SCR => write!(f, "SCR"),
+            Instruction::SCL => write!(f, "SCL"),
+            Instruction::EXIT => write!(f, "EXIT"),
+            Instruction::LOW => write!(f, "LOW"),
+            Instruction::HIGH => write!(f, "HIGH"),
+            Instruction::LD11(x) => write!(f, "LD HF, V{:X}", x),
+            Instruction::LD12(x) => write!(f, "LD R, V{:X}", x),
+            Instruction::LD13(x) => write!(f, "LD V{:X}, R", x),
+            Instruction::SCU(n) => write!(f, "SCU {}", n),
+            Instruction::SAVE(x, y) => write!(f, "SAVE V{:X}, V{:X}", x, y),
+            Instruction::LOAD(x, y) => write!(f, "LOAD V{:X}, V{:X}", x, y),
+            Instruction::LDIL => write!(f, "LD I, LONG"),
+            Instruction::PLANE(n) => write!(f, "PLANE {}", n),
+            Instruction::AUDIO => write!(f, "AUDIO"),
+            Instruction::PITCH(x) => write!(f, "LD PITCH, V{:X}", x),
+        }
+    }
+}
+
+fn encode_xkk(op: u16, x: u8, byte: u8) -> u16 {
+    (op << 12) | ((x as u16 & 0xF) << 8) | byte as u16
+}
+
+fn encode_xyn(op: u16, x: u8, y: u8, n: u8) -> u16 {
+    (op << 12) | ((x as u16 & 0xF) << 8) | ((y as u16 & 0xF) << 4) | (n as u16 & 0xF)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_cls() {
+        assert_eq!(Instruction::decode(0x00E0), Ok(Instruction::CLS));
+    }
+
+    #[test]
+    fn decode_ret() {
+        assert_eq!(Instruction::decode(0x00EE), Ok(Instruction::RET));
+    }
+
+    #[test]
+    fn decode_sys() {
+        assert_eq!(Instruction::decode(0x0123), Ok(Instruction::SYS(0x123)));
+    }
+
+    #[test]
+    fn decode_add2() {
+        assert_eq!(Instruction::decode(0x8AB4), Ok(Instruction::ADD2(0xA, 0xB)));
+    }
+
+    #[test]
+    fn decode_drw() {
+        assert_eq!(Instruction::decode(0xD125), Ok(Instruction::DRW(1, 2, 5)));
+    }
+
+    #[test]
+    fn decode_ld10() {
+        assert_eq!(Instruction::decode(0xF365), Ok(Instruction::LD10(3)));
+    }
+
+    #[test]
+    fn decode_scd() {
+        assert_eq!(Instruction::decode(0x00C5), Ok(Instruction::SCD(5)));
+    }
+
+    #[test]
+    fn decode_schip() {
+        assert_eq!(Instruction::decode(0x00FB), Ok(Instruction::SCR));
+        assert_eq!(Instruction::decode(0x00FC), Ok(Instruction::SCL));
+        assert_eq!(Instruction::decode(0x00FD), Ok(Instruction::EXIT));
+        assert_eq!(Instruction::decode(0x00FE), Ok(Instruction::LOW));
+        assert_eq!(Instruction::decode(0x00FF), Ok(Instruction::HIGH));
+        assert_eq!(Instruction::decode(0xF230), Ok(Instruction::LD11(2)));
+        assert_eq!(Instruction::decode(0xF375), Ok(Instruction::LD12(3)));
+        assert_eq!(Instruction::decode(0xF485), Ok(Instruction::LD13(4)));
+    }
+
+    #[test]
+    fn decode_xochip() {
+        assert_eq!(Instruction::decode(0x00D3), Ok(Instruction::SCU(3)));
+        assert_eq!(Instruction::decode(0x5122), Ok(Instruction::SAVE(1, 2)));
+        assert_eq!(Instruction::decode(0x5213), Ok(Instruction::LOAD(2, 1)));
+        assert_eq!(Instruction::decode(0xF000), Ok(Instruction::LDIL));
+        assert_eq!(Instruction::decode(0xF301), Ok(Instruction::PLANE(3)));
+        assert_eq!(Instruction::decode(0xF002), Ok(Instruction::AUDIO));
+        assert_eq!(Instruction::decode(0xF53A), Ok(Instruction::PITCH(5)));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Instruction::LD(1, 0x0A).to_string(), "LD V1, 0x0A");
+        assert_eq!(Instruction::DRW(0, 1, 2).to_string(), "DRW V0, V1, 2");
+        assert_eq!(Instruction::JP(0x2A0).to_string(), "JP 0x2A0");
+        assert_eq!(Instruction::LD10(0xF).to_string(), "LD VF, [I]");
+    }
+
+    #[test]
+    fn decode_unknown() {
+        for &opcode in &[0x5121, 0x8128, 0x9121, 0xE1FF, 0xF1FF, 0xF100, 0xF102] {
+            assert_eq!(
+                Instruction::decode(opcode),
+                Err(DecodeError::UnknownOpcode(opcode))
+            );
+        }
+    }
+
+    #[test]
+    fn encode_roundtrip() {
+        // every opcode that decodes must encode back to itself
+        for opcode in 0..=0xFFFF {
+            if let Ok(instr) = Instruction::decode(opcode) {
+                assert_eq!(instr.encode(), opcode, "{:?}", instr);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_roundtrip() {
+        let instrs = [
+            Instruction::SYS(0x123),
+            Instruction::CLS,
+            Instruction::RET,
+            Instruction::JP(0x300),
+            Instruction::CALL(0x400),
+            Instruction::SE(1, 0x10),
+            Instruction::SNE(2, 0x20),
+            Instruction::SE2(3, 4),
+            Instruction::LD(5, 0x0A),
+            Instruction::ADD(6, 0xFF),
+            Instruction::LD2(7, 8),
+            Instruction::OR(9, 0xA),
+            Instruction::AND(0xB, 0xC),
+            Instruction::XOR(0xD, 0xE),
+            Instruction::ADD2(0xF, 0),
+            Instruction::SUB(1, 2),
+            Instruction::SHR(3, 4),
+            Instruction::SUBN(5, 6),
+            Instruction::SHL(7, 8),
+            Instruction::SNE2(9, 0xA),
+            Instruction::LDI(0x555),
+            Instruction::JPV0(0x666),
+            Instruction::RND(0xB, 0x0F),
+            Instruction::DRW(0xC, 0xD, 0xF),
+            Instruction::SKP(0xE),
+            Instruction::SKNP(0xF),
+            Instruction::LD3(0),
+            Instruction::LD4(1),
+            Instruction::LD5(2),
+            Instruction::LD6(3),
+            Instruction::ADD3(4),
+            Instruction::LD7(5),
+            Instruction::LD8(6),
+            Instruction::LD9(7),
+            Instruction::LD10(8),
+            Instruction::SCD(0xA),
+            Instruction::SCR,
+            Instruction::SCL,
+            Instruction::EXIT,
+            Instruction::LOW,
+            Instruction::HIGH,
+            Instruction::LD11(9),
+            Instruction::LD12(7),
+            Instruction::LD13(7),
+            Instruction::SCU(0xB),
+            Instruction::SAVE(1, 5),
+            Instruction::LOAD(5, 1),
+            Instruction::LDIL,
+            Instruction::PLANE(2),
+            Instruction::AUDIO,
+            Instruction::PITCH(6),
+        ];
+
+        for instr in instrs.iter() {
+            assert_eq!(Instruction::decode(instr.encode()), Ok(*instr));
+        }
+    }
+}
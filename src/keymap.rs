@@ -0,0 +1,158 @@
+use super::keys::Key;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+/// The standard layout, mapping the left side of a QWERTY keyboard onto the
+/// COSMAC VIP keypad:
+///
+/// ```text
+/// 1 2 3 4        1 2 3 C
+/// Q W E R   ->   4 5 6 D
+/// A S D F        7 8 9 E
+/// Z X C V        A 0 B F
+/// ```
+const DEFAULT_KEYMAP: &str = "
+1 = Key1
+2 = Key2
+3 = Key3
+C = Key4
+4 = Q
+5 = W
+6 = E
+D = R
+7 = A
+8 = S
+9 = D
+E = F
+A = Z
+0 = X
+B = C
+F = V
+";
+
+/// Maps host key names onto CHIP-8 keys.
+///
+/// Host keys are named like the frontend names them, which for the ggez
+/// renderer is the name of the `KeyCode` variant (`Key1`, `Q`, `Numpad0`, ...).
+#[derive(Clone, Debug)]
+pub struct Keymap {
+    keys: HashMap<String, Key>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct KeymapError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for KeymapError {}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap::parse(DEFAULT_KEYMAP).unwrap()
+    }
+}
+
+impl Keymap {
+    /// Parses a keymap with one `<chip8 key> = <host key>` binding per line,
+    /// where the CHIP-8 key is a hex digit. Lines starting with `#` are
+    /// comments. A CHIP-8 key may be bound to several host keys, and keys
+    /// that are not mentioned are left unbound.
+    pub fn parse(source: &str) -> Result<Keymap, KeymapError> {
+        let mut keys = HashMap::new();
+
+        for (i, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = |message: String| KeymapError {
+                line: i + 1,
+                message,
+            };
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            let host_key = match parts.next() {
+                Some(host_key) => host_key.trim(),
+                None => return Err(error(format!("expected '=' in \"{}\"", line))),
+            };
+
+            let key = u8::from_str_radix(key, 16)
+                .ok()
+                .filter(|_| key.len() == 1)
+                .and_then(Key::from_num)
+                .ok_or_else(|| error(format!("invalid CHIP-8 key \"{}\"", key)))?;
+            if host_key.is_empty() {
+                return Err(error(format!("missing host key for {:X}", key.to_num())));
+            }
+
+            keys.insert(host_key.to_string(), key);
+        }
+
+        Ok(Keymap { keys })
+    }
+
+    pub fn get(&self, host_key: &str) -> Option<Key> {
+        self.keys.get(host_key).copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_layout() {
+        let keymap = Keymap::default();
+
+        assert_eq!(keymap.get("Key1"), Some(Key::Key1));
+        assert_eq!(keymap.get("Key4"), Some(Key::KeyC));
+        assert_eq!(keymap.get("Q"), Some(Key::Key4));
+        assert_eq!(keymap.get("X"), Some(Key::Key0));
+        assert_eq!(keymap.get("V"), Some(Key::KeyF));
+        assert_eq!(keymap.get("P"), None);
+    }
+
+    #[test]
+    fn parse() {
+        let keymap = Keymap::parse(
+            "# AZERTY\n\
+             4 = A\n\
+             7 = Q\n\
+             7 = Numpad7\n",
+        )
+        .unwrap();
+
+        assert_eq!(keymap.get("A"), Some(Key::Key4));
+        assert_eq!(keymap.get("Q"), Some(Key::Key7));
+        assert_eq!(keymap.get("Numpad7"), Some(Key::Key7));
+        assert_eq!(keymap.get("Z"), None);
+    }
+
+    #[test]
+    fn parse_missing_equals() {
+        let err = Keymap::parse("1 = Key1\n2 Key2").unwrap_err();
+        assert_eq!(err.line, 2);
+    }
+
+    #[test]
+    fn parse_invalid_key() {
+        assert_eq!(Keymap::parse("G = A").unwrap_err().line, 1);
+        assert_eq!(Keymap::parse("10 = A").unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn parse_missing_host_key() {
+        assert_eq!(Keymap::parse("1 =").unwrap_err().line, 1);
+    }
+}
@@ -1,7 +1,7 @@
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
-use std::collections::HashSet;
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash, FromPrimitive, ToPrimitive, Debug)]
 pub enum Key {
@@ -33,48 +33,91 @@ impl Key {
     }
 }
 
-#[derive(Clone)]
-pub struct Keyboard {
-    keys: Arc<(Mutex<HashSet<Key>>, Condvar)>,
+/// The state of the hex keypad, one bit per key.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Keypad {
+    mask: u16,
 }
 
-impl Keyboard {
-    pub fn new() -> Keyboard {
-        Keyboard {
-            keys: Arc::new((Mutex::new(HashSet::new()), Condvar::new())),
-        }
+impl Keypad {
+    pub fn new() -> Keypad {
+        Keypad::default()
     }
 
-    pub fn is_pressed(&self, key: &Key) -> bool {
-        let k = self.keys.0.lock().unwrap();
-        (*k).contains(key)
+    /// Creates a keypad from a mask with bit n set for key n.
+    pub fn from_mask(mask: u16) -> Keypad {
+        Keypad { mask }
     }
 
-    pub fn set_pressed(&self, key: Key) {
-        let mut k = self.keys.0.lock().unwrap();
-        (*k).insert(key);
-        self.keys.1.notify_all();
+    pub fn mask(&self) -> u16 {
+        self.mask
     }
 
-    pub fn set_unpressed(&self, key: Key) {
-        let mut k = self.keys.0.lock().unwrap();
-        (*k).remove(&key);
-        self.keys.1.notify_all();
+    pub fn is_pressed(&self, key: Key) -> bool {
+        self.mask & 1 << key.to_num() != 0
     }
 
-    pub fn wait(&mut self) -> Key {
-        let guard = self.keys.0.lock().unwrap();
-        let current_keys = (*guard).clone();
+    pub fn press(&mut self, key: Key) {
+        self.mask |= 1 << key.to_num();
+    }
+
+    pub fn release(&mut self, key: Key) {
+        self.mask &= !(1 << key.to_num());
+    }
+}
+
+/// A keypad that can be updated from any thread, for frontends that receive
+/// input on a thread of their own. Clones share the same keys.
+#[derive(Clone, Default)]
+pub struct SharedKeypad {
+    mask: Arc<AtomicU16>,
+}
+
+impl SharedKeypad {
+    pub fn new() -> SharedKeypad {
+        SharedKeypad::default()
+    }
+
+    pub fn press(&self, key: Key) {
+        self.mask.fetch_or(1 << key.to_num(), Ordering::Relaxed);
+    }
+
+    pub fn release(&self, key: Key) {
+        self.mask.fetch_and(!(1 << key.to_num()), Ordering::Relaxed);
+    }
+
+    /// Returns the keys pressed right now.
+    pub fn keypad(&self) -> Keypad {
+        Keypad::from_mask(self.mask.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn press_and_release() {
+        let mut keypad = Keypad::new();
+        keypad.press(Key::Key3);
+        keypad.press(Key::KeyF);
+        keypad.release(Key::Key3);
+
+        assert!(!keypad.is_pressed(Key::Key3));
+        assert!(keypad.is_pressed(Key::KeyF));
+        assert_eq!(keypad.mask(), 0x8000);
+    }
 
-        let new_keys = self.keys.1.wait_until(
-            guard,
-            |x| {
-                let result: HashSet<&Key> = x.difference(&current_keys).collect();
-                !result.is_empty()
-            }
-        ).unwrap();
+    #[test]
+    fn shared_keypad() {
+        let shared = SharedKeypad::new();
+        let clone = shared.clone();
+        std::thread::spawn(move || clone.press(Key::KeyA))
+            .join()
+            .unwrap();
 
-        let result: HashSet<&Key> = (*new_keys).difference(&current_keys).collect();
-        **result.iter().nth(0).unwrap()
+        assert!(shared.keypad().is_pressed(Key::KeyA));
+        shared.release(Key::KeyA);
+        assert_eq!(shared.keypad(), Keypad::new());
     }
 }
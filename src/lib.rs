@@ -1,81 +1,274 @@
-#![feature(wait_until)]
-
-mod keys;
+mod asm;
+mod debugger;
+mod disasm;
+mod display;
+mod frontend;
+mod gdb;
+mod history;
+mod instruction;
+mod keymap;
+pub mod keys;
+mod movie;
+mod octo;
+mod quirks;
+#[cfg(feature = "gui")]
 mod render;
-
-use rand::rngs::mock::StepRng;
-use rand::Rng;
+mod rewind;
+mod rng;
+mod state;
+mod trace;
+mod watchpoint;
+
+pub use asm::{assemble, assemble_file, AsmError};
+pub use debugger::Debugger;
+pub use disasm::{disassemble, Line, LineKind};
+pub use display::Display;
+pub use frontend::{Command, Frontend, Headless};
+pub use instruction::{DecodeError, Instruction};
+pub use keymap::{Keymap, KeymapError};
+pub use movie::{Movie, MovieError};
+pub use octo::{compile_octo, OctoError, OctoProgram, Target};
+pub use quirks::{EdgeMode, IndexIncrement, KeyWait, Quirks};
+pub use rewind::Rewind;
+pub use state::StateError;
+pub use trace::Tracer;
+pub use watchpoint::{Change, Register, Watchpoint};
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::TcpListener;
+use std::path::PathBuf;
 use std::thread;
 use std::time::Duration;
 
-const RAM_SIZE: usize = 0x1000;
+const RAM_SIZE: usize = 0x10000;
+/// The memory of the platforms before XO-CHIP.
+const SMALL_RAM_SIZE: usize = 0x1000;
 const STACK_SIZE: usize = 16;
-const DISPLAY_HEIGHT: usize = 32;
 const NUM_REGISTERS: usize = 16;
+const NUM_RPL_FLAGS: usize = 16;
+const AUDIO_PATTERN_SIZE: usize = 16;
+const DEFAULT_PITCH: u8 = 64;
 const MEM_PROGRAM_START: u16 = 0x200;
+const MEM_BIG_FONT_START: usize = 0x50;
+const FRAMES_PER_SECOND: u32 = 60;
+const TIMER_FREQUENCY: u32 = 60;
+const FRAME_DURATION: Duration = Duration::from_micros(16_667);
+
+const FONT: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+/// The SUPER-CHIP 8x10 font, extended with A-F.
+const BIG_FONT: [u8; 160] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x18, 0x3C, 0x66, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFE, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFE, 0xFC, // B
+    0x3C, 0x7E, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7E, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
 
 pub struct Program {
     pub instructions: Vec<Instruction>,
 }
 
-pub enum Instruction {
-    SYS(u16),
-    CLS,
-    RET,
-    JP(u16),
-    CALL(u16),
-    SE(u8, u8),
-    SNE(u8, u8),
-    SE2(u8, u8),
-    LD(u8, u8),
-    ADD(u8, u8),
-    LD2(u8, u8),
-    OR(u8, u8),
-    AND(u8, u8),
-    XOR(u8, u8),
-    ADD2(u8, u8),
-    SUB(u8, u8),
-    SHR(u8, u8),
-    SUBN(u8, u8),
-    SHL(u8, u8),
-    SNE2(u8, u8),
-    LDI(u16),
-    JPV0(u16),
-    RND(u8, u8),
-    DRW(u8, u8, u8),
-    SKP(u8),
-    SKNP(u8),
-    LD3(u8),
-    LD4(u8),
-    LD5(u8),
-    LD6(u8),
-    ADD3(u8),
-    LD7(u8),
-    LD8(u8),
-    LD9(u8),
-    LD10(u8),
+pub struct Config {
+    /// Number of instructions executed per second.
+    pub clock_speed: u32,
+    /// Size of a CHIP-8 pixel on screen.
+    pub scale: f32,
+    pub keymap: Keymap,
+    pub quirks: Quirks,
+    /// The file quick saves are written to. Without one, the quick save is
+    /// only kept in memory.
+    pub state_path: Option<PathBuf>,
+    /// Bytes of snapshots kept for rewinding, or zero to turn rewinding off.
+    pub rewind_capacity: usize,
+    /// The file every executed instruction is traced to, if any.
+    pub trace_path: Option<PathBuf>,
+    /// Seed for the random numbers returned by `RND`. Without one, every run
+    /// gets different numbers.
+    pub seed: Option<u64>,
+    /// The file the keys pressed while running are recorded to as a movie.
+    pub record_path: Option<PathBuf>,
+    /// A movie to replay instead of taking input from the user, until it
+    /// ends. Its seed, clock speed and quirks replace the configured ones.
+    pub replay: Option<Movie>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            clock_speed: 600,
+            scale: 10.0,
+            keymap: Keymap::default(),
+            quirks: Quirks::default(),
+            state_path: None,
+            rewind_capacity: 16 << 20,
+            trace_path: None,
+            seed: None,
+            record_path: None,
+            replay: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LoadError {
+    RomTooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::RomTooLarge { size, max } => write!(
+                f,
+                "ROM is {} bytes, but at most {} bytes fit in memory",
+                size, max
+            ),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+/// An error raised while executing the instruction at `pc`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct VmError {
+    pub pc: u16,
+    pub opcode: u16,
+    pub kind: VmErrorKind,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum VmErrorKind {
+    UnknownOpcode,
+    StackOverflow,
+    StackUnderflow,
+    /// An access to an address outside of memory.
+    MemoryOutOfBounds(usize),
+    /// A key instruction referred to a value that is not a key.
+    InvalidKey(u8),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            VmErrorKind::UnknownOpcode => write!(f, "unknown opcode")?,
+            VmErrorKind::StackOverflow => write!(f, "stack overflow")?,
+            VmErrorKind::StackUnderflow => write!(f, "stack underflow")?,
+            VmErrorKind::MemoryOutOfBounds(addr) => {
+                write!(f, "memory access out of bounds at {:#05X}", addr)?
+            }
+            VmErrorKind::InvalidKey(key) => write!(f, "invalid key {:#04X}", key)?,
+        }
+        write!(f, " (pc: {:#05X}, opcode: {:#06X})", self.pc, self.opcode)
+    }
+}
+
+impl Error for VmError {}
+
+/// The progress of `LD4` while it waits for a key.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub(crate) struct WaitingForKey {
+    /// Keys held since the wait started, which are not new presses.
+    held: u16,
+    /// The key that was pressed, while waiting for it to be released.
+    pressed: Option<u8>,
+}
+
+/// Controls how the delay and sound timers are decremented.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TimerMode {
+    /// The timers are only decremented by calls to `VM::tick_timers`, for
+    /// frontends that drive their own 60 Hz clock. The calls also start the
+    /// frames that the display wait quirk waits for.
+    Manual,
+    /// The timers are decremented at 60 Hz of emulated time, derived from
+    /// the number of executed instructions at the given clock speed.
+    Automatic { clock_speed: u32 },
 }
 
-struct VM {
+pub struct VM {
     memory: [u8; RAM_SIZE],
     stack: [u16; STACK_SIZE],
-    display: [u64; 32],
+    display: Display,
     gen_registers: [u8; NUM_REGISTERS],
     reg_i: u16,
     reg_pc: u16,
     reg_sp: u8,
     reg_delay: u8,
     reg_sound: u8,
-    rng: Box<dyn rand::RngCore>,
+    rpl_flags: [u8; NUM_RPL_FLAGS],
+    /// The XO-CHIP audio pattern, `None` until a program loads one.
+    audio_pattern: Option<[u8; AUDIO_PATTERN_SIZE]>,
+    reg_pitch: u8,
+    rng: rng::Rng,
+    /// The CRC-32 of the loaded ROM, which save states must match.
+    rom_checksum: u32,
+
+    timer_mode: TimerMode,
+    timer_cycles: u32,
+    quirks: Quirks,
+    /// Set once a sprite has been drawn, until the next 60 Hz tick.
+    drawn_this_frame: bool,
+    exited: bool,
+    /// Set while `LD4` waits for a key.
+    waiting_for_key: Option<WaitingForKey>,
+    watchpoints: Vec<Watchpoint>,
+    /// The first memory watchpoint hit by the current instruction.
+    watch_hit: Option<Watchpoint>,
+    history: history::History,
+    tracer: Option<Tracer>,
+
+    keypad: keys::Keypad,
+}
 
-    keyboard: keys::Keyboard,
+impl Default for VM {
+    fn default() -> VM {
+        VM::new()
+    }
 }
 
 impl VM {
+    /// Creates a VM with the COSMAC VIP quirks and `TimerMode::Manual`.
+    /// Since the VIP waits for the next frame after drawing a sprite, a VM
+    /// driven by `step` alone stops at its second `DRW` until
+    /// `tick_timers` is called. Use `set_timer_mode` to have frames pass
+    /// with the executed instructions instead.
     pub fn new() -> VM {
         let memory = create_memory();
         let stack = create_stack();
-        let display = create_display();
+        let display = Display::new();
         let gen_registers = create_gen_registers();
 
         VM {
@@ -84,85 +277,437 @@ impl VM {
             display,
             gen_registers,
             reg_i: 0,
-            reg_pc: 0,
+            reg_pc: MEM_PROGRAM_START,
             reg_sp: 0,
             reg_delay: 0,
             reg_sound: 0,
-            rng: Box::new(rand::thread_rng()),
+            rpl_flags: [0; NUM_RPL_FLAGS],
+            audio_pattern: None,
+            reg_pitch: DEFAULT_PITCH,
+            rng: rng::Rng::from_entropy(),
+            rom_checksum: state::checksum(&[]),
+
+            timer_mode: TimerMode::Manual,
+            timer_cycles: 0,
+            quirks: Quirks::default(),
+            drawn_this_frame: false,
+            exited: false,
+            waiting_for_key: None,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            history: history::History::default(),
+            tracer: None,
+
+            keypad: keys::Keypad::new(),
+        }
+    }
+
+    pub fn keypad(&self) -> &keys::Keypad {
+        &self.keypad
+    }
+
+    /// Returns the keypad, for pressing and releasing keys between steps.
+    pub fn keypad_mut(&mut self) -> &mut keys::Keypad {
+        &mut self.keypad
+    }
+
+    /// Copies the ROM into memory at 0x200 and points PC at its first instruction.
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), LoadError> {
+        let start = MEM_PROGRAM_START as usize;
+        let max = self.memory_size() - start;
+        if rom.len() > max {
+            return Err(LoadError::RomTooLarge {
+                size: rom.len(),
+                max,
+            });
+        }
+
+        self.memory[start..start + rom.len()].copy_from_slice(rom);
+        self.reg_pc = MEM_PROGRAM_START;
+        self.waiting_for_key = None;
+        self.rom_checksum = state::checksum(rom);
+        self.history.clear();
+        Ok(())
+    }
+
+    /// Captures the whole state of the VM, including the keypad, quirks
+    /// and random number generator, in a versioned binary format.
+    pub fn save_state(&self) -> Vec<u8> {
+        state::save(self)
+    }
+
+    /// Restores a state from `save_state`, which must have been saved while
+    /// running the same ROM. The VM is left unchanged on errors.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        state::load(self, state, true)?;
+        self.history.clear();
+        Ok(())
+    }
+
+    /// Keeps up to about `capacity` bytes of what each instruction changed,
+    /// for `step_back`. A capacity of zero turns this off.
+    pub fn set_history(&mut self, capacity: usize) {
+        self.history = history::History::new(capacity);
+    }
+
+    /// Returns `true` while `LD4` (FX0A) waits for a key. Stepping the VM
+    /// keeps checking the keypad until one is chosen.
+    pub fn is_waiting_for_key(&self) -> bool {
+        self.waiting_for_key.is_some()
+    }
+
+    /// Points PC at another address, giving up on a key that `LD4` was
+    /// waiting for at the old one.
+    pub(crate) fn set_pc(&mut self, addr: u16) {
+        if addr != self.reg_pc {
+            self.reg_pc = addr;
+            self.waiting_for_key = None;
+        }
+    }
+
+    /// Reseeds the random number generator behind `RND`. The numbers
+    /// generated from a seed are the same on every platform.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = rng::Rng::from_state(seed);
+    }
+
+    /// Traces every instruction executed from now on, or stops tracing.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Stops tracing and returns the tracer, to read or finish its trace.
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    /// Undoes the last instruction, returning `false` when there is no
+    /// history left. The keypad is left as it is.
+    pub fn step_back(&mut self) -> bool {
+        let mut history = mem::take(&mut self.history);
+        let stepped = history.undo(self);
+        self.history = history;
+        stepped
+    }
+
+    pub fn set_timer_mode(&mut self, mode: TimerMode) {
+        self.timer_mode = mode;
+        self.timer_cycles = 0;
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
 
-            keyboard: keys::Keyboard::new(),
+    /// Returns the number of bytes of memory the program can use, which is
+    /// 64 KiB with the `extended_memory` quirk and 4 KiB without.
+    pub fn memory_size(&self) -> usize {
+        if self.quirks.extended_memory {
+            RAM_SIZE
+        } else {
+            SMALL_RAM_SIZE
         }
     }
 
-    //pub fn run(&mut self, program: Program) {}
+    /// Decrements the delay and sound timers by one, stopping at zero. This
+    /// also starts a new frame for the display wait quirk.
+    pub fn tick_timers(&mut self) {
+        self.reg_delay = self.reg_delay.saturating_sub(1);
+        self.reg_sound = self.reg_sound.saturating_sub(1);
+        self.drawn_this_frame = false;
+    }
+
+    /// Returns whether the program has stopped itself with `EXIT`.
+    pub fn has_exited(&self) -> bool {
+        self.exited
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    /// Returns whether the watchpoint was set.
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|&w| w != watchpoint);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Executes one instruction and returns the first watchpoint it hit.
+    /// An instruction that waits, for a key or for the next frame, leaves PC
+    /// where it is.
+    pub fn step(&mut self) -> Result<Option<Watchpoint>, VmError> {
+        if self.exited {
+            return Ok(None);
+        }
+
+        let mut history = mem::take(&mut self.history);
+        history.begin(self);
+        self.history = history;
+        let result = self.step_instr();
+        match result {
+            Ok(_) => self.history.end(&self.display),
+            // the VM is left at the faulting instruction
+            Err(_) => self.history.cancel(),
+        }
+        result
+    }
+
+    fn step_instr(&mut self) -> Result<Option<Watchpoint>, VmError> {
+        self.watch_hit = None;
+        let registers = self.gen_registers;
+        let reg_i = self.reg_i;
+
+        let opcode = self.fetch()?;
+        let instr = Instruction::decode(opcode).map_err(|_| VmError {
+            pc: self.reg_pc,
+            opcode,
+            kind: VmErrorKind::UnknownOpcode,
+        })?;
+        self.execute(instr)?;
+
+        if let TimerMode::Automatic { clock_speed } = self.timer_mode {
+            let clock_speed = clock_speed.max(1);
+            // accumulate in units of 1/clock_speed so that clock speeds not
+            // divisible by 60 still tick at exactly 60 Hz on average
+            self.timer_cycles += TIMER_FREQUENCY;
+            while self.timer_cycles >= clock_speed {
+                self.timer_cycles -= clock_speed;
+                self.tick_timers();
+            }
+        }
+
+        Ok(self
+            .watch_hit
+            .or_else(|| self.register_watch_hit(&registers, reg_i)))
+    }
+
+    fn register_watch_hit(
+        &self,
+        registers: &[u8; NUM_REGISTERS],
+        reg_i: u16,
+    ) -> Option<Watchpoint> {
+        self.watchpoints
+            .iter()
+            .copied()
+            .find(|watchpoint| match *watchpoint {
+                Watchpoint::Register(Register::V(x), change) => change.matches(
+                    registers[x as usize] as u16,
+                    self.gen_registers[x as usize] as u16,
+                ),
+                Watchpoint::Register(Register::I, change) => change.matches(reg_i, self.reg_i),
+                _ => false,
+            })
+    }
+
+    pub fn run_for(&mut self, cycles: usize) -> Result<(), VmError> {
+        for _ in 0..cycles {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    fn fetch(&self) -> Result<u16, VmError> {
+        let pc = self.reg_pc as usize;
+        if pc + 1 >= self.memory_size() {
+            return Err(VmError {
+                pc: self.reg_pc,
+                opcode: 0,
+                kind: VmErrorKind::MemoryOutOfBounds(pc + 1),
+            });
+        }
+        Ok((self.memory[pc] as u16) << 8 | self.memory[pc + 1] as u16)
+    }
+
+    /// Fails if `len` bytes starting at `start` do not fit in memory.
+    fn check_memory(&self, start: usize, len: usize) -> Result<(), VmErrorKind> {
+        let size = self.memory_size();
+        if start + len > size {
+            Err(VmErrorKind::MemoryOutOfBounds(start.max(size)))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Instructions access memory only through `read_memory` and
+    // `write_memory`, so that every access is seen by the watchpoints.
+    fn read_memory(&mut self, start: usize, len: usize) -> Result<Vec<u8>, VmErrorKind> {
+        self.check_memory(start, len)?;
+        self.watch_memory(start, len, false);
+        Ok(self.memory[start..start + len].to_vec())
+    }
+
+    fn write_memory(&mut self, start: usize, bytes: &[u8]) -> Result<(), VmErrorKind> {
+        self.check_memory(start, bytes.len())?;
+        self.watch_memory(start, bytes.len(), true);
+        self.history
+            .write(start, &self.memory[start..start + bytes.len()]);
+        self.memory[start..start + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn watch_memory(&mut self, start: usize, len: usize, write: bool) {
+        if self.watch_hit.is_some() {
+            return;
+        }
+        let range = start..start + len;
+        self.watch_hit = self
+            .watchpoints
+            .iter()
+            .copied()
+            .find(|watchpoint| match *watchpoint {
+                Watchpoint::Read(addr) => !write && range.contains(&(addr as usize)),
+                Watchpoint::Write(addr) => write && range.contains(&(addr as usize)),
+                Watchpoint::Register(..) => false,
+            });
+    }
+
+    pub fn execute(&mut self, instr: Instruction) -> Result<(), VmError> {
+        if self.tracer.is_some() {
+            return self.execute_traced(instr);
+        }
+        let pc = self.reg_pc;
+        self.execute_instr(instr).map_err(|kind| VmError {
+            pc,
+            opcode: instr.encode(),
+            kind,
+        })
+    }
+
+    fn execute_traced(&mut self, instr: Instruction) -> Result<(), VmError> {
+        let pc = self.reg_pc;
+        let was_waiting = self.waiting_for_key.is_some();
+        let before = self.trace_registers();
+        let result = self.execute_instr(instr).map_err(|kind| VmError {
+            pc,
+            opcode: instr.encode(),
+            kind,
+        });
+
+        // a wait for a key repeats the instruction every cycle, which is
+        // only worth a line when it begins and when it ends
+        if was_waiting && self.waiting_for_key.is_some() && result.is_ok() {
+            return result;
+        }
+        let after = self.trace_registers();
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(pc, instr, &before, &after, result.as_ref().err());
+        }
+        result
+    }
+
+    fn finish_key_wait(&mut self, x: u8, key: u8, next: u16) {
+        self.gen_registers[x as usize] = key;
+        self.waiting_for_key = None;
+        self.reg_pc = next;
+    }
+
+    fn trace_registers(&self) -> trace::Registers {
+        trace::Registers {
+            gen_registers: self.gen_registers,
+            reg_i: self.reg_i,
+            reg_sp: self.reg_sp,
+        }
+    }
+
+    // State must not be modified before an error is returned, so that the VM
+    // is left at the faulting instruction.
+    fn execute_instr(&mut self, instr: Instruction) -> Result<(), VmErrorKind> {
+        // the instruction after this one must be in the address space, unless
+        // this one never moves on to it
+        let next = match instr {
+            Instruction::RET | Instruction::JP(_) | Instruction::JPV0(_) | Instruction::EXIT => {
+                self.reg_pc
+            }
+            _ => self.pc_after(2)?,
+        };
 
-    pub fn execute(&mut self, instr: Instruction) {
         match instr {
+            Instruction::SYS(_) => {
+                // machine code routines are not supported, so this is a no-op
+                self.reg_pc = next;
+            }
             Instruction::CLS => {
-                for row in self.display.iter_mut() {
-                    *row = 0;
-                }
-                self.reg_pc += 1;
+                self.display.clear();
+                self.reg_pc = next;
             }
             Instruction::RET => {
-                self.reg_pc = self.stack[self.reg_sp as usize];
+                if self.reg_sp == 0 {
+                    return Err(VmErrorKind::StackUnderflow);
+                }
                 self.reg_sp -= 1;
+                self.reg_pc = self.stack[self.reg_sp as usize];
             }
             Instruction::JP(addr) => {
                 self.reg_pc = addr;
             }
             Instruction::CALL(addr) => {
+                if self.reg_sp as usize >= STACK_SIZE {
+                    return Err(VmErrorKind::StackOverflow);
+                }
+                self.stack[self.reg_sp as usize] = next;
                 self.reg_sp += 1;
-                self.stack[self.reg_sp as usize] = self.reg_pc;
                 self.reg_pc = addr;
             }
             Instruction::SE(x, byte) => {
                 if self.gen_registers[x as usize] == byte {
-                    self.reg_pc += 2;
+                    self.skip_next()?;
                 } else {
-                    self.reg_pc += 1;
+                    self.reg_pc = next;
                 }
             }
             Instruction::SNE(x, byte) => {
                 if self.gen_registers[x as usize] != byte {
-                    self.reg_pc += 2;
+                    self.skip_next()?;
                 } else {
-                    self.reg_pc += 1;
+                    self.reg_pc = next;
                 }
             }
             Instruction::SE2(x, y) => {
                 if self.gen_registers[x as usize] == self.gen_registers[y as usize] {
-                    self.reg_pc += 2;
+                    self.skip_next()?;
                 } else {
-                    self.reg_pc += 1;
+                    self.reg_pc = next;
                 }
             }
             Instruction::LD(x, byte) => {
                 self.gen_registers[x as usize] = byte;
-                self.reg_pc += 1;
+                self.reg_pc = next;
             }
             Instruction::ADD(x, byte) => {
-                self.gen_registers[x as usize] += byte;
-                self.reg_pc += 1;
+                self.gen_registers[x as usize] = self.gen_registers[x as usize].wrapping_add(byte);
+                self.reg_pc = next;
             }
             Instruction::LD2(x, y) => {
                 self.gen_registers[x as usize] = self.gen_registers[y as usize];
-                self.reg_pc += 1;
+                self.reg_pc = next;
             }
             Instruction::OR(x, y) => {
-                self.gen_registers[x as usize] =
-                    self.gen_registers[x as usize] | self.gen_registers[y as usize];
-                self.reg_pc += 1;
+                self.gen_registers[x as usize] |= self.gen_registers[y as usize];
+                if self.quirks.logic_resets_vf {
+                    self.gen_registers[0xF] = 0;
+                }
+                self.reg_pc = next;
             }
             Instruction::AND(x, y) => {
-                self.gen_registers[x as usize] =
-                    self.gen_registers[x as usize] & self.gen_registers[y as usize];
-                self.reg_pc += 1;
+                self.gen_registers[x as usize] &= self.gen_registers[y as usize];
+                if self.quirks.logic_resets_vf {
+                    self.gen_registers[0xF] = 0;
+                }
+                self.reg_pc = next;
             }
             Instruction::XOR(x, y) => {
-                self.gen_registers[x as usize] =
-                    self.gen_registers[x as usize] ^ self.gen_registers[y as usize];
-                self.reg_pc += 1;
+                self.gen_registers[x as usize] ^= self.gen_registers[y as usize];
+                if self.quirks.logic_resets_vf {
+                    self.gen_registers[0xF] = 0;
+                }
+                self.reg_pc = next;
             }
             Instruction::ADD2(x, y) => {
                 let result =
@@ -174,718 +719,1371 @@ impl VM {
                     self.gen_registers[x as usize] = result as u8;
                     self.gen_registers[0xF] = 0;
                 }
-                self.reg_pc += 1;
+                self.reg_pc = next;
             }
             Instruction::SUB(x, y) => {
-                // TODO: Not sure this is the proper way to do subtraction
-                if self.gen_registers[x as usize] > self.gen_registers[y as usize] {
-                    let result = self.gen_registers[x as usize] - self.gen_registers[y as usize];
-                    self.gen_registers[x as usize] = result;
-                    self.gen_registers[0xF] = 1;
-                } else {
-                    let result = self.gen_registers[y as usize] - self.gen_registers[x as usize];
-                    self.gen_registers[x as usize] = result;
-                    self.gen_registers[0xF] = 0;
-                }
-                self.reg_pc += 1;
+                let vx = self.gen_registers[x as usize];
+                let vy = self.gen_registers[y as usize];
+                self.gen_registers[x as usize] = vx.wrapping_sub(vy);
+                // VF is set when there is no borrow
+                self.gen_registers[0xF] = (vx >= vy) as u8;
+                self.reg_pc = next;
             }
-            Instruction::SHR(x, _) => {
-                if self.gen_registers[x as usize] % 2 == 0 {
-                    self.gen_registers[0xF] = 0;
-                } else {
-                    self.gen_registers[0xF] = 1;
-                }
-                self.gen_registers[x as usize] = self.gen_registers[x as usize] >> 1;
-                self.reg_pc += 1;
+            Instruction::SHR(x, y) => {
+                let value = self.shift_operand(x, y);
+                self.gen_registers[x as usize] = value >> 1;
+                self.gen_registers[0xF] = value & 0b1;
+                self.reg_pc = next;
             }
             Instruction::SUBN(x, y) => {
-                // TODO: Not sure this is the proper way to do subtraction
-                if self.gen_registers[y as usize] > self.gen_registers[x as usize] {
-                    let result = self.gen_registers[y as usize] - self.gen_registers[x as usize];
-                    self.gen_registers[x as usize] = result;
-                    self.gen_registers[0xF] = 1;
-                } else {
-                    let result = self.gen_registers[x as usize] - self.gen_registers[y as usize];
-                    self.gen_registers[x as usize] = result;
-                    self.gen_registers[0xF] = 0;
-                }
-                self.reg_pc += 1;
+                let vx = self.gen_registers[x as usize];
+                let vy = self.gen_registers[y as usize];
+                self.gen_registers[x as usize] = vy.wrapping_sub(vx);
+                // VF is set when there is no borrow
+                self.gen_registers[0xF] = (vy >= vx) as u8;
+                self.reg_pc = next;
             }
-            Instruction::SHL(x, _) => {
-                if self.gen_registers[x as usize] >= 0b10000000 {
-                    self.gen_registers[0xF] = 1;
-                } else {
-                    self.gen_registers[0xF] = 0;
-                }
-                self.gen_registers[x as usize] = self.gen_registers[x as usize] << 1;
-                self.reg_pc += 1;
+            Instruction::SHL(x, y) => {
+                let value = self.shift_operand(x, y);
+                self.gen_registers[x as usize] = value << 1;
+                self.gen_registers[0xF] = value >> 7;
+                self.reg_pc = next;
             }
             Instruction::SNE2(x, y) => {
                 if self.gen_registers[x as usize] != self.gen_registers[y as usize] {
-                    self.reg_pc += 2;
+                    self.skip_next()?;
                 } else {
-                    self.reg_pc += 1;
+                    self.reg_pc = next;
                 }
             }
             Instruction::LDI(addr) => {
                 self.reg_i = addr;
-                self.reg_pc += 1;
+                self.reg_pc = next;
             }
             Instruction::JPV0(addr) => {
-                self.reg_pc = addr + self.gen_registers[0] as u16;
+                let x = if self.quirks.jump_with_vx {
+                    (addr >> 8) & 0xF
+                } else {
+                    0
+                };
+                self.reg_pc = addr + self.gen_registers[x as usize] as u16;
             }
             Instruction::RND(x, byte) => {
-                let value = (*(self.rng)).next_u32() as u8;
+                let value = self.rng.next_u8();
                 self.gen_registers[x as usize] = value & byte;
-                self.reg_pc += 1;
+                self.reg_pc = next;
             }
             Instruction::DRW(x, y, n) => {
-                let vx = self.gen_registers[x as usize];
-                let vy = self.gen_registers[y as usize];
-                let start = self.reg_i as usize;
-
-                for i in 0..n {
-                    let data = self.memory[start + i as usize];
-                    let sprite_row = create_sprite_mask(data, vx);
-                    let result = self.display[x as usize] ^ sprite_row;
-
-                    if sprite_row & result == sprite_row {
-                        self.gen_registers[0xF] = 0;
-                    } else {
-                        self.gen_registers[0xF] = 1;
-                    }
-
-                    self.display[(vy + i) as usize] = result;
+                if self.quirks.display_wait && self.drawn_this_frame {
+                    // try again on the next frame
+                    return Ok(());
                 }
-                self.reg_pc += 1;
+
+                // DXY0 draws a 16x16 sprite, and each selected plane reads
+                // its own sprite
+                let (sprite_width, len) = if n == 0 { (16, 32) } else { (8, n as usize) };
+                let planes = self.display.selected_planes().count_ones() as usize;
+                let len = len * planes;
+                let sprite = self.read_memory(self.reg_i as usize, len)?;
+
+                let vx = self.gen_registers[x as usize] as usize;
+                let vy = self.gen_registers[y as usize] as usize;
+                let collision =
+                    self.display
+                        .draw(vx, vy, &sprite, sprite_width, self.quirks.sprite_edge);
+
+                self.gen_registers[0xF] = collision as u8;
+                self.drawn_this_frame = true;
+                self.reg_pc = next;
             }
             Instruction::SKP(x) => {
                 let key_num = self.gen_registers[x as usize];
-                let key = keys::Key::from_num(key_num).unwrap();
-                if self.keyboard.is_pressed(&key) {
-                    self.reg_pc += 2;
+                let key = keys::Key::from_num(key_num).ok_or(VmErrorKind::InvalidKey(key_num))?;
+                if self.keypad.is_pressed(key) {
+                    self.skip_next()?;
                 } else {
-                    self.reg_pc += 1;
+                    self.reg_pc = next;
                 }
             }
             Instruction::SKNP(x) => {
                 let key_num = self.gen_registers[x as usize];
-                let key = keys::Key::from_num(key_num).unwrap();
-                if self.keyboard.is_pressed(&key) {
-                    self.reg_pc += 1;
+                let key = keys::Key::from_num(key_num).ok_or(VmErrorKind::InvalidKey(key_num))?;
+                if self.keypad.is_pressed(key) {
+                    self.reg_pc = next;
                 } else {
-                    self.reg_pc += 2;
+                    self.skip_next()?;
                 }
             }
             Instruction::LD3(x) => {
                 self.gen_registers[x as usize] = self.reg_delay;
-                self.reg_pc += 1;
+                self.reg_pc = next;
             }
             Instruction::LD4(x) => {
-                let key = self.keyboard.wait();
-                self.gen_registers[x as usize] = key.to_num();
-                self.reg_pc += 1;
+                // the instruction is repeated until a key has been chosen, so
+                // that timers keep running and the frontend gets frames
+                let keys = self.keypad.mask();
+                let mut waiting = self.waiting_for_key.unwrap_or(WaitingForKey {
+                    held: keys,
+                    pressed: None,
+                });
+                // keys held when the wait started only count once released
+                waiting.held &= keys;
+                if waiting.pressed.is_none() {
+                    let new_keys = keys & !waiting.held;
+                    if new_keys != 0 {
+                        waiting.pressed = Some(new_keys.trailing_zeros() as u8);
+                    }
+                }
+
+                match (waiting.pressed, self.quirks.key_wait) {
+                    (Some(key), KeyWait::Press) => self.finish_key_wait(x, key, next),
+                    (Some(key), KeyWait::Release) if keys & 1 << key == 0 => {
+                        self.finish_key_wait(x, key, next)
+                    }
+                    _ => self.waiting_for_key = Some(waiting),
+                }
             }
             Instruction::LD5(x) => {
                 self.reg_delay = self.gen_registers[x as usize];
-                self.reg_pc += 1;
+                self.reg_pc = next;
             }
             Instruction::LD6(x) => {
                 self.reg_sound = self.gen_registers[x as usize];
-                self.reg_pc += 1;
+                self.reg_pc = next;
             }
             Instruction::ADD3(x) => {
-                self.reg_i = self.reg_i + self.gen_registers[x as usize] as u16;
-                self.reg_pc += 1;
+                self.reg_i = self
+                    .reg_i
+                    .wrapping_add(self.gen_registers[x as usize] as u16);
+                self.reg_pc = next;
             }
             Instruction::LD7(x) => {
-                let d = self.gen_registers[x as usize];
+                let d = self.gen_registers[x as usize] & 0xF;
                 self.reg_i = digit(d) as u16;
-                self.reg_pc += 1;
+                self.reg_pc = next;
             }
             Instruction::LD8(x) => {
-                let i = self.reg_i as usize;
                 let v = self.gen_registers[x as usize];
 
                 let hundred = v / 100;
                 let ten = (v - (100 * hundred)) / 10;
                 let one = v - (100 * hundred) - (10 * ten);
 
-                self.memory[i] = hundred;
-                self.memory[i + 1] = ten;
-                self.memory[i + 2] = one;
-                self.reg_pc += 1;
+                self.write_memory(self.reg_i as usize, &[hundred, ten, one])?;
+                self.reg_pc = next;
             }
             Instruction::LD9(x) => {
-                for i in 0..x + 1 {
-                    self.memory[(self.reg_i + i as u16) as usize] = self.gen_registers[i as usize];
-                }
-                self.reg_pc += 1;
+                let registers = self.gen_registers;
+                self.write_memory(self.reg_i as usize, &registers[..=x as usize])?;
+                self.increment_index(x);
+                self.reg_pc = next;
             }
             Instruction::LD10(x) => {
-                for i in 0..x + 1 {
-                    self.gen_registers[i as usize] = self.memory[(self.reg_i + i as u16) as usize];
+                let values = self.read_memory(self.reg_i as usize, x as usize + 1)?;
+                self.gen_registers[..=x as usize].copy_from_slice(&values);
+                self.increment_index(x);
+                self.reg_pc = next;
+            }
+            Instruction::SCD(n) => {
+                self.display.scroll_down(n as usize);
+                self.reg_pc = next;
+            }
+            Instruction::SCR => {
+                self.display.scroll_right(4);
+                self.reg_pc = next;
+            }
+            Instruction::SCL => {
+                self.display.scroll_left(4);
+                self.reg_pc = next;
+            }
+            Instruction::EXIT => {
+                self.exited = true;
+            }
+            Instruction::LOW => {
+                self.display.set_hires(false);
+                self.reg_pc = next;
+            }
+            Instruction::HIGH => {
+                self.display.set_hires(true);
+                self.reg_pc = next;
+            }
+            Instruction::LD11(x) => {
+                let d = self.gen_registers[x as usize] & 0xF;
+                self.reg_i = big_digit(d) as u16;
+                self.reg_pc = next;
+            }
+            Instruction::LD12(x) => {
+                let n = x as usize + 1;
+                self.rpl_flags[..n].copy_from_slice(&self.gen_registers[..n]);
+                self.reg_pc = next;
+            }
+            Instruction::LD13(x) => {
+                let n = x as usize + 1;
+                self.gen_registers[..n].copy_from_slice(&self.rpl_flags[..n]);
+                self.reg_pc = next;
+            }
+            Instruction::SCU(n) => {
+                self.display.scroll_up(n as usize);
+                self.reg_pc = next;
+            }
+            Instruction::SAVE(x, y) => {
+                let values: Vec<u8> = register_range(x, y)
+                    .into_iter()
+                    .map(|reg| self.gen_registers[reg])
+                    .collect();
+                self.write_memory(self.reg_i as usize, &values)?;
+                self.reg_pc = next;
+            }
+            Instruction::LOAD(x, y) => {
+                let registers = register_range(x, y);
+                let values = self.read_memory(self.reg_i as usize, registers.len())?;
+                for (reg, value) in registers.into_iter().zip(values) {
+                    self.gen_registers[reg] = value;
                 }
-                self.reg_pc += 1;
+                self.reg_pc = next;
             }
-            _ => {}
+            Instruction::LDIL => {
+                let addr = self.read_memory(self.reg_pc as usize + 2, 2)?;
+                let next = self.pc_after(4)?;
+                self.reg_i = (addr[0] as u16) << 8 | addr[1] as u16;
+                self.reg_pc = next;
+            }
+            Instruction::PLANE(n) => {
+                self.display.select_planes(n);
+                self.reg_pc = next;
+            }
+            Instruction::AUDIO => {
+                let values = self.read_memory(self.reg_i as usize, AUDIO_PATTERN_SIZE)?;
+                let mut pattern = [0; AUDIO_PATTERN_SIZE];
+                pattern.copy_from_slice(&values);
+                self.audio_pattern = Some(pattern);
+                self.reg_pc = next;
+            }
+            Instruction::PITCH(x) => {
+                self.reg_pitch = self.gen_registers[x as usize];
+                self.reg_pc = next;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Skips the next instruction, which for `LDIL` includes its address.
+    fn skip_next(&mut self) -> Result<(), VmErrorKind> {
+        let next = self.reg_pc as usize + 2;
+        let long = next + 1 < self.memory_size()
+            && self.memory[next] == 0xF0
+            && self.memory[next + 1] == 0x00;
+        self.reg_pc = self.pc_after(if long { 6 } else { 4 })?;
+        Ok(())
+    }
+
+    /// Returns the address `len` bytes past PC, which must not run off the
+    /// end of the 16-bit address space.
+    fn pc_after(&self, len: u16) -> Result<u16, VmErrorKind> {
+        self.reg_pc
+            .checked_add(len)
+            .ok_or(VmErrorKind::MemoryOutOfBounds(
+                self.reg_pc as usize + len as usize,
+            ))
+    }
+
+    fn shift_operand(&self, x: u8, y: u8) -> u8 {
+        if self.quirks.shift_in_place {
+            self.gen_registers[x as usize]
+        } else {
+            self.gen_registers[y as usize]
+        }
+    }
+
+    fn increment_index(&mut self, x: u8) {
+        match self.quirks.index_increment {
+            IndexIncrement::XPlusOne => self.reg_i = self.reg_i.wrapping_add(x as u16 + 1),
+            IndexIncrement::X => self.reg_i = self.reg_i.wrapping_add(x as u16),
+            IndexIncrement::Unchanged => {}
         }
     }
 }
 
 fn digit(digit: u8) -> usize {
-    (digit * 5) as usize
+    digit as usize * 5
 }
 
-fn create_memory() -> [u8; RAM_SIZE] {
-    let mut array = [0; RAM_SIZE];
+fn big_digit(digit: u8) -> usize {
+    MEM_BIG_FONT_START + digit as usize * 10
+}
 
-    array[digit(0) + 0] = 0xF0;
-    array[digit(0) + 1] = 0x90;
-    array[digit(0) + 2] = 0x90;
-    array[digit(0) + 3] = 0x90;
-    array[digit(0) + 4] = 0xF0;
-
-    array[digit(1) + 0] = 0x20;
-    array[digit(1) + 1] = 0x60;
-    array[digit(1) + 2] = 0x20;
-    array[digit(1) + 3] = 0x20;
-    array[digit(1) + 4] = 0x70;
-
-    array[digit(2) + 0] = 0xF0;
-    array[digit(2) + 1] = 0x10;
-    array[digit(2) + 2] = 0xF0;
-    array[digit(2) + 3] = 0x80;
-    array[digit(2) + 4] = 0xF0;
-
-    array[digit(3) + 0] = 0xF0;
-    array[digit(3) + 1] = 0x10;
-    array[digit(3) + 2] = 0xF0;
-    array[digit(3) + 3] = 0x10;
-    array[digit(3) + 4] = 0xF0;
-
-    array[digit(4) + 0] = 0x90;
-    array[digit(4) + 1] = 0x90;
-    array[digit(4) + 2] = 0xF0;
-    array[digit(4) + 3] = 0x10;
-    array[digit(4) + 4] = 0x10;
-
-    array[digit(5) + 0] = 0xF0;
-    array[digit(5) + 1] = 0x80;
-    array[digit(5) + 2] = 0xF0;
-    array[digit(5) + 3] = 0x10;
-    array[digit(5) + 4] = 0xF0;
-
-    array[digit(6) + 0] = 0xF0;
-    array[digit(6) + 1] = 0x80;
-    array[digit(6) + 2] = 0xF0;
-    array[digit(6) + 3] = 0x90;
-    array[digit(6) + 4] = 0xF0;
-
-    array[digit(7) + 0] = 0xF0;
-    array[digit(7) + 1] = 0x10;
-    array[digit(7) + 2] = 0x20;
-    array[digit(7) + 3] = 0x40;
-    array[digit(7) + 4] = 0x40;
-
-    array[digit(8) + 0] = 0xF0;
-    array[digit(8) + 1] = 0x90;
-    array[digit(8) + 2] = 0xF0;
-    array[digit(8) + 3] = 0x90;
-    array[digit(8) + 4] = 0xF0;
-
-    array[digit(9) + 0] = 0xF0;
-    array[digit(9) + 1] = 0x90;
-    array[digit(9) + 2] = 0xF0;
-    array[digit(9) + 3] = 0x10;
-    array[digit(9) + 4] = 0xF0;
-
-    array[digit(10) + 0] = 0xF0;
-    array[digit(10) + 1] = 0x90;
-    array[digit(10) + 2] = 0xF0;
-    array[digit(10) + 3] = 0x90;
-    array[digit(10) + 4] = 0x90;
-
-    array[digit(11) + 0] = 0xE0;
-    array[digit(11) + 1] = 0x90;
-    array[digit(11) + 2] = 0xE0;
-    array[digit(11) + 3] = 0x90;
-    array[digit(11) + 4] = 0xE0;
-
-    array[digit(12) + 0] = 0xF0;
-    array[digit(12) + 1] = 0x80;
-    array[digit(12) + 2] = 0x80;
-    array[digit(12) + 3] = 0x80;
-    array[digit(12) + 4] = 0xF0;
-
-    array[digit(13) + 0] = 0xE0;
-    array[digit(13) + 1] = 0x90;
-    array[digit(13) + 2] = 0x90;
-    array[digit(13) + 3] = 0x90;
-    array[digit(13) + 4] = 0xE0;
-
-    array[digit(14) + 0] = 0xF0;
-    array[digit(14) + 1] = 0x80;
-    array[digit(14) + 2] = 0xF0;
-    array[digit(14) + 3] = 0x80;
-    array[digit(14) + 4] = 0xF0;
-
-    array[digit(15) + 0] = 0xF0;
-    array[digit(15) + 1] = 0x80;
-    array[digit(15) + 2] = 0xF0;
-    array[digit(15) + 3] = 0x80;
-    array[digit(15) + 4] = 0x80;
+/// Returns the registers VX through VY, in reverse order if X is greater
+/// than Y.
+fn register_range(x: u8, y: u8) -> Vec<usize> {
+    let (x, y) = (x as usize, y as usize);
+    if x <= y {
+        (x..=y).collect()
+    } else {
+        (y..=x).rev().collect()
+    }
+}
 
+fn create_memory() -> [u8; RAM_SIZE] {
+    let mut array = [0; RAM_SIZE];
+    array[digit(0)..digit(0) + FONT.len()].copy_from_slice(&FONT);
+    array[big_digit(0)..big_digit(0) + BIG_FONT.len()].copy_from_slice(&BIG_FONT);
     array
 }
 
 fn create_stack() -> [u16; STACK_SIZE] {
-    let array = [0; STACK_SIZE];
-    array
+    [0; STACK_SIZE]
 }
 
-fn create_display() -> [u64; DISPLAY_HEIGHT] {
-    let array = [0; DISPLAY_HEIGHT];
-    array
+fn create_gen_registers() -> [u8; NUM_REGISTERS] {
+    [0; NUM_REGISTERS]
 }
 
-fn create_gen_registers() -> [u8; NUM_REGISTERS] {
-    let array = [0; NUM_REGISTERS];
-    array
+/// Runs the ROM with the ggez renderer, or without any output when built
+/// without the `gui` feature.
+pub fn run(rom: &[u8], mut config: Config) -> Result<(), Box<dyn Error>> {
+    let replay = config.replay.take();
+    if let Some(movie) = &replay {
+        // the ROM is loaded the way it was when the movie was recorded
+        movie.apply(rom, &mut config)?;
+    }
+    let mut vm = load_vm(rom, &config)?;
+    let result = run_session(&mut vm, &config, replay.as_ref());
+    finish_session(&mut vm, result)
 }
 
-fn create_sprite_mask(sprite: u8, x: u8) -> u64 {
-    (sprite as u64) << (64 - 8 - x)
+fn run_session(vm: &mut VM, config: &Config, replay: Option<&Movie>) -> Result<(), Box<dyn Error>> {
+    let mut frontend = create_frontend(config);
+    if let Some(movie) = replay {
+        let input = Input::Replay { movie, frame: 0 };
+        run_frames(vm, config, &mut frontend, input)?;
+    } else if let Some(path) = &config.record_path {
+        let mut movie = Movie::new(vm, config);
+        let result = run_frames(vm, config, &mut frontend, Input::Record(&mut movie));
+        // a movie of a session that failed is still worth keeping
+        fs::write(path, movie.to_bytes())?;
+        result?;
+    } else {
+        run_with(vm, config, &mut frontend)?;
+    }
+    Ok(())
 }
 
-pub fn run() {
+fn load_vm(rom: &[u8], config: &Config) -> Result<VM, Box<dyn Error>> {
     let mut vm = VM::new();
-    let renderer = render::Renderer::new(vm.keyboard.clone());
+    vm.set_quirks(config.quirks);
+    vm.load_rom(rom)?;
+    if let Some(seed) = config.seed {
+        vm.set_seed(seed);
+    }
+    if let Some(path) = &config.trace_path {
+        vm.set_tracer(Some(Tracer::to_file(path)?));
+    }
+    Ok(vm)
+}
 
-    let mut x = 0;
+/// Flushes the trace of a session that ended with `result`, however it
+/// ended. An error from the session is reported before one from the trace.
+fn finish_session<E: Into<Box<dyn Error>>>(
+    vm: &mut VM,
+    result: Result<(), E>,
+) -> Result<(), Box<dyn Error>> {
+    let trace = match vm.take_tracer() {
+        Some(tracer) => tracer.finish(),
+        None => Ok(()),
+    };
+    result.map_err(Into::into)?;
+    trace?;
+    Ok(())
+}
+
+#[cfg(feature = "gui")]
+fn create_frontend(config: &Config) -> render::Renderer {
+    render::Renderer::new(config.scale, config.keymap.clone())
+}
 
-    vm.execute(Instruction::LD4(1));
+#[cfg(not(feature = "gui"))]
+fn create_frontend(_config: &Config) -> Headless {
+    Headless
+}
 
-    loop {
-        vm.reg_i = 0x200;
-        vm.gen_registers[0] = 10 + (x % 32);
-        vm.gen_registers[1] = 20;
-        vm.memory[0x200] = 5;
-        vm.execute(Instruction::DRW(0, 1, 2));
+/// Loads the ROM into a debugger and reads commands from stdin until the
+/// user quits.
+pub fn debug(rom: &[u8], config: Config) -> Result<(), Box<dyn Error>> {
+    let mut vm = load_vm(rom, &config)?;
+    vm.set_timer_mode(TimerMode::Automatic {
+        clock_speed: config.clock_speed,
+    });
+
+    let stdin = io::stdin();
+    let mut debugger = Debugger::new(vm);
+    let result = debugger.repl(stdin.lock(), io::stdout());
+    finish_session(&mut debugger.into_vm(), result)
+}
 
-        renderer.render(vm.display);
-        thread::sleep(Duration::from_millis(100));
+/// Waits for GDB to connect to the listener and lets it control the VM
+/// over the remote serial protocol. The ROM keeps running if GDB detaches.
+pub fn serve_gdb(rom: &[u8], config: Config, listener: TcpListener) -> Result<(), Box<dyn Error>> {
+    let mut vm = load_vm(rom, &config)?;
+    vm.set_timer_mode(TimerMode::Automatic {
+        clock_speed: config.clock_speed,
+    });
 
-        x += 1;
-    }
+    let result = serve_session(&mut vm, &config, listener);
+    finish_session(&mut vm, result)
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+fn serve_session(
+    vm: &mut VM,
+    config: &Config,
+    listener: TcpListener,
+) -> Result<(), Box<dyn Error>> {
+    let (stream, _) = listener.accept()?;
 
-    fn create_vm() -> VM {
-        VM::new()
+    let mut frontend = create_frontend(config);
+    if gdb::serve(vm, config, &mut frontend, stream)? {
+        run_with(vm, config, &mut frontend)?;
     }
+    Ok(())
+}
 
-    #[test]
-    fn execute_initial_pc() {
-        let vm = create_vm();
-        assert_eq!(vm.reg_pc, 0);
-    }
+/// Runs the VM at the configured clock speed until the frontend quits.
+pub fn run_with<F: Frontend>(
+    vm: &mut VM,
+    config: &Config,
+    frontend: &mut F,
+) -> Result<(), VmError> {
+    run_frames(vm, config, frontend, Input::Live)
+}
 
-    #[test]
-    fn instr_sys() {
-        let mut vm = create_vm();
-        let instr = Instruction::SYS(1);
-        vm.execute(instr);
+/// Where the keys held in each frame come from.
+enum Input<'a> {
+    /// The keys come from the frontend.
+    Live,
+    /// The keys come from the frontend and are recorded.
+    Record(&'a mut Movie),
+    /// The keys come from the movie, and from the frontend once it has ended.
+    Replay { movie: &'a Movie, frame: usize },
+}
 
-        // this instruction should be ignored
-        assert_eq!(vm.reg_pc, 0);
+impl Input<'_> {
+    /// Returns the keys to hold during the next frame, given the keys held
+    /// on the host.
+    fn next_frame(&mut self, host_keypad: keys::Keypad) -> keys::Keypad {
+        match self {
+            Input::Live => host_keypad,
+            Input::Record(movie) => {
+                movie.push(host_keypad.mask());
+                host_keypad
+            }
+            Input::Replay { movie, frame } => {
+                let keys = movie.frame(*frame);
+                *frame += 1;
+                keys.map_or(host_keypad, keys::Keypad::from_mask)
+            }
+        }
     }
+}
 
-    #[test]
-    fn instr_cls() {
-        let mut vm = create_vm();
-
-        vm.display[0] = 0b1111;
+fn run_frames<F: Frontend>(
+    vm: &mut VM,
+    config: &Config,
+    frontend: &mut F,
+    mut input: Input,
+) -> Result<(), VmError> {
+    let live = matches!(input, Input::Live);
+    // keys only change between frames, so that movies see exactly what the
+    // VM saw
+    let mut host_keypad = vm.keypad;
+
+    vm.set_timer_mode(TimerMode::Automatic {
+        clock_speed: config.clock_speed,
+    });
+    vm.set_quirks(config.quirks);
+
+    let mut frame_cycles = FrameCycles::new(config);
+    let mut sound = Sound::default();
+    let mut quick_save = None;
+    let mut rewind = Rewind::new(config.rewind_capacity);
+    let mut rewinding = false;
+
+    while !vm.has_exited() && frontend.poll_input(&mut host_keypad) {
+        for command in frontend.poll_commands() {
+            match command {
+                Command::QuickSave => save_quick_state(vm, config, &mut quick_save),
+                // going back in time would make the movie diverge
+                _ if !live => {}
+                Command::QuickLoad => load_quick_state(vm, config, &quick_save),
+                Command::StartRewind => rewinding = true,
+                Command::StopRewind => rewinding = false,
+            }
+        }
 
-        let instr = Instruction::CLS;
-        vm.execute(instr);
+        vm.keypad = input.next_frame(host_keypad);
 
-        // should clear display
-        assert_eq!(vm.display[0], 0);
+        // one frame is undone per frame, so rewinding runs at normal speed
+        if rewinding {
+            rewind.rewind(vm);
+            sound.stop(frontend);
+        } else {
+            rewind.record(vm);
+            vm.run_for(frame_cycles.next_frame())?;
+            sound.update(vm, frontend);
+        }
 
-        // should inc PC
-        assert_eq!(vm.reg_pc, 1);
+        frontend.present(&vm.display);
+        thread::sleep(FRAME_DURATION);
     }
 
-    #[test]
-    fn instr_ret() {
-        let mut vm = create_vm();
-
-        let stack_pc = 10;
-        let sp = 5;
-        vm.reg_sp = sp;
-        vm.stack[5] = stack_pc;
-
-        let instr = Instruction::RET;
-        vm.execute(instr);
+    sound.stop(frontend);
+    Ok(())
+}
 
-        assert_eq!(vm.reg_pc, stack_pc);
-        assert_eq!(vm.reg_sp, sp - 1);
+// Failed quick saves and loads are reported without stopping the game.
+fn save_quick_state(vm: &VM, config: &Config, quick_save: &mut Option<Vec<u8>>) {
+    let state = vm.save_state();
+    match &config.state_path {
+        Some(path) => {
+            if let Err(e) = fs::write(path, state) {
+                eprintln!("Unable to save state to {}: {}", path.display(), e);
+            }
+        }
+        None => *quick_save = Some(state),
+    }
+}
+
+fn load_quick_state(vm: &mut VM, config: &Config, quick_save: &Option<Vec<u8>>) {
+    let state = match &config.state_path {
+        Some(path) => match fs::read(path) {
+            Ok(state) => state,
+            Err(e) => {
+                eprintln!("Unable to load state from {}: {}", path.display(), e);
+                return;
+            }
+        },
+        None => match quick_save {
+            Some(state) => state.clone(),
+            None => return,
+        },
+    };
+
+    if let Err(e) = vm.load_state(&state) {
+        eprintln!("Unable to load state: {}", e);
+    }
+}
+
+/// Splits the clock speed into the instructions run in each frame. The
+/// remainder of the division is carried over to the next frames, so a second
+/// of frames runs exactly `clock_speed` instructions.
+struct FrameCycles {
+    clock_speed: u32,
+    /// Instructions owed from previous frames, in 1/60ths.
+    remainder: u32,
+}
+
+impl FrameCycles {
+    fn new(config: &Config) -> FrameCycles {
+        FrameCycles {
+            clock_speed: config.clock_speed,
+            remainder: 0,
+        }
+    }
+
+    /// Returns the number of instructions to run in the next frame.
+    fn next_frame(&mut self) -> usize {
+        let total = self.clock_speed as u64 + self.remainder as u64;
+        self.remainder = (total % FRAMES_PER_SECOND as u64) as u32;
+        (total / FRAMES_PER_SECOND as u64) as usize
+    }
+}
+
+/// What the frontend is playing, so that it is only told about changes.
+#[derive(Default)]
+struct Sound {
+    tone: bool,
+    audio: Option<([u8; AUDIO_PATTERN_SIZE], u8)>,
+}
+
+impl Sound {
+    fn update<F: Frontend>(&mut self, vm: &VM, frontend: &mut F) {
+        let audio = vm.audio_pattern.map(|pattern| (pattern, vm.reg_pitch));
+        if audio != self.audio {
+            if let Some((pattern, pitch)) = audio {
+                frontend.set_audio(&pattern, pitch);
+            }
+            self.audio = audio;
+        }
+
+        let tone = vm.reg_sound > 0;
+        if tone != self.tone {
+            if tone {
+                frontend.play_tone();
+            } else {
+                frontend.stop_tone();
+            }
+            self.tone = tone;
+        }
+    }
+
+    fn stop<F: Frontend>(&mut self, frontend: &mut F) {
+        if self.tone {
+            frontend.stop_tone();
+            self.tone = false;
+        }
+    }
+}
+
+/// Creates a VM with `rom` loaded, for the tests of every module.
+#[cfg(test)]
+fn test_vm(rom: &[u8]) -> VM {
+    let mut vm = VM::new();
+    vm.load_rom(rom).unwrap();
+    vm
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn execute_initial_pc() {
+        let vm = test_vm(&[]);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START);
+    }
+
+    #[test]
+    fn load_rom() {
+        let mut vm = test_vm(&[]);
+        vm.load_rom(&[0x12, 0x34, 0x56]).unwrap();
+
+        assert_eq!(vm.memory[0x200..0x203], [0x12, 0x34, 0x56]);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START);
+    }
+
+    #[test]
+    fn load_rom_stops_key_wait() {
+        let mut vm = test_vm(&[]);
+        vm.execute(Instruction::LD4(1)).unwrap();
+        vm.load_rom(&[0x00, 0xE0]).unwrap();
+
+        assert!(!vm.is_waiting_for_key());
+    }
+
+    #[test]
+    fn load_rom_max_size() {
+        let mut vm = test_vm(&[]);
+        let rom = vec![0xAB; SMALL_RAM_SIZE - 0x200];
+        vm.load_rom(&rom).unwrap();
+
+        assert_eq!(vm.memory[SMALL_RAM_SIZE - 1], 0xAB);
+    }
+
+    #[test]
+    fn load_rom_too_large() {
+        let mut vm = test_vm(&[]);
+        let rom = vec![0; SMALL_RAM_SIZE - 0x200 + 1];
+
+        assert_eq!(
+            vm.load_rom(&rom),
+            Err(LoadError::RomTooLarge {
+                size: SMALL_RAM_SIZE - 0x200 + 1,
+                max: SMALL_RAM_SIZE - 0x200,
+            })
+        );
+    }
+
+    #[test]
+    fn load_rom_extended_memory() {
+        let mut vm = test_vm(&[]);
+        vm.set_quirks(Quirks::MODERN);
+        let rom = vec![0xAB; RAM_SIZE - 0x200];
+        vm.load_rom(&rom).unwrap();
+
+        assert_eq!(vm.memory_size(), RAM_SIZE);
+        assert_eq!(vm.memory[RAM_SIZE - 1], 0xAB);
+    }
+
+    #[test]
+    fn step_fetches_from_pc() {
+        let mut vm = test_vm(&[]);
+        vm.memory[0x200] = 0x6A;
+        vm.memory[0x201] = 0x0B;
+
+        vm.step().unwrap();
+
+        assert_eq!(vm.gen_registers[0xA], 0x0B);
+    }
+
+    #[test]
+    fn step_at_end_of_address_space() {
+        let mut vm = test_vm(&[]);
+        vm.set_quirks(Quirks::MODERN);
+        // LD V0, 1 has no instruction to move on to
+        vm.memory[0xFFFE] = 0x60;
+        vm.memory[0xFFFF] = 0x01;
+        vm.reg_pc = 0xFFFE;
+
+        let err = vm.step().unwrap_err();
+
+        assert_eq!(err.kind, VmErrorKind::MemoryOutOfBounds(RAM_SIZE));
+        assert_eq!(vm.gen_registers[0], 0);
+        assert_eq!(vm.reg_pc, 0xFFFE);
+
+        // JP 0x200 does not need one
+        vm.memory[0xFFFE] = 0x12;
+        vm.memory[0xFFFF] = 0x00;
+        vm.step().unwrap();
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START);
+    }
+
+    #[test]
+    fn skip_past_end_of_address_space() {
+        let mut vm = test_vm(&[]);
+        vm.set_quirks(Quirks::MODERN);
+        vm.reg_pc = 0xFFFC;
+
+        let err = vm.execute(Instruction::SE(0, 0)).unwrap_err();
+
+        assert_eq!(err.kind, VmErrorKind::MemoryOutOfBounds(RAM_SIZE));
+        assert_eq!(vm.reg_pc, 0xFFFC);
+        vm.execute(Instruction::SNE(0, 0)).unwrap();
+        assert_eq!(vm.reg_pc, 0xFFFE);
+    }
+
+    #[test]
+    fn step_unknown_opcode() {
+        let mut vm = test_vm(&[]);
+        vm.memory[0x200] = 0xFF;
+        vm.memory[0x201] = 0xFF;
+
+        assert_eq!(
+            vm.step(),
+            Err(VmError {
+                pc: MEM_PROGRAM_START,
+                opcode: 0xFFFF,
+                kind: VmErrorKind::UnknownOpcode,
+            })
+        );
+    }
+
+    #[test]
+    fn step_pc_out_of_bounds() {
+        let mut vm = test_vm(&[]);
+        vm.reg_pc = (SMALL_RAM_SIZE - 1) as u16;
+
+        assert_eq!(
+            vm.step().unwrap_err().kind,
+            VmErrorKind::MemoryOutOfBounds(SMALL_RAM_SIZE)
+        );
+    }
+
+    #[test]
+    fn run_for_cycles() {
+        // LD V0, 0x05; ADD V0, 0x01; JP 0x202
+        let mut vm = test_vm(&[0x60, 0x05, 0x70, 0x01, 0x12, 0x02]);
+
+        vm.run_for(5).unwrap();
+
+        assert_eq!(vm.gen_registers[0], 0x07);
+        assert_eq!(vm.reg_pc, 0x202);
+    }
+
+    #[test]
+    fn tick_timers() {
+        let mut vm = test_vm(&[]);
+        vm.reg_delay = 2;
+        vm.reg_sound = 1;
+
+        vm.tick_timers();
+        assert_eq!(vm.reg_delay, 1);
+        assert_eq!(vm.reg_sound, 0);
+
+        // should stop at zero
+        vm.tick_timers();
+        assert_eq!(vm.reg_delay, 0);
+        assert_eq!(vm.reg_sound, 0);
+    }
+
+    #[test]
+    fn timers_manual() {
+        let mut vm = test_vm(&[]);
+        vm.reg_delay = 10;
+
+        // JP 0x200
+        vm.load_rom(&[0x12, 0x00]).unwrap();
+        vm.run_for(100).unwrap();
+
+        assert_eq!(vm.reg_delay, 10);
+    }
+
+    #[test]
+    fn timers_automatic() {
+        let mut vm = test_vm(&[]);
+        vm.set_timer_mode(TimerMode::Automatic { clock_speed: 600 });
+        vm.reg_delay = 10;
+        vm.reg_sound = 10;
+
+        // JP 0x200
+        vm.load_rom(&[0x12, 0x00]).unwrap();
+        vm.run_for(59).unwrap();
+        assert_eq!(vm.reg_delay, 5);
+
+        vm.run_for(1).unwrap();
+        assert_eq!(vm.reg_delay, 4);
+        assert_eq!(vm.reg_sound, 4);
+    }
+
+    #[test]
+    fn timers_automatic_uneven_clock() {
+        let mut vm = test_vm(&[]);
+        vm.set_timer_mode(TimerMode::Automatic { clock_speed: 700 });
+        vm.reg_delay = 100;
+
+        // JP 0x200
+        vm.load_rom(&[0x12, 0x00]).unwrap();
+        vm.run_for(700).unwrap();
+
+        // one second of emulated time
+        assert_eq!(vm.reg_delay, 40);
+    }
+
+    #[test]
+    fn frame_cycles_carry_remainder() {
+        for &clock_speed in [700, 600, 30].iter() {
+            let config = Config {
+                clock_speed,
+                ..Config::default()
+            };
+            let mut frame_cycles = FrameCycles::new(&config);
+            let cycles: Vec<usize> = (0..60).map(|_| frame_cycles.next_frame()).collect();
+
+            assert_eq!(cycles.iter().sum::<usize>(), clock_speed as usize);
+            let (min, max) = (cycles.iter().min(), cycles.iter().max());
+            assert!(max.unwrap() - min.unwrap() <= 1);
+        }
+    }
+
+    #[test]
+    fn instr_sys() {
+        let mut vm = test_vm(&[]);
+        let instr = Instruction::SYS(1);
+        vm.execute(instr).unwrap();
+
+        // this instruction should be ignored
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_cls() {
+        let mut vm = test_vm(&[]);
+
+        vm.display[0] = 0b1111;
+
+        let instr = Instruction::CLS;
+        vm.execute(instr).unwrap();
+
+        // should clear display
+        assert_eq!(vm.display[0], 0);
+
+        // should inc PC
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_ret() {
+        let mut vm = test_vm(&[]);
+
+        let stack_pc = 0x302;
+        let sp = 5;
+        vm.reg_sp = sp;
+        vm.stack[4] = stack_pc;
+
+        let instr = Instruction::RET;
+        vm.execute(instr).unwrap();
+
+        assert_eq!(vm.reg_pc, stack_pc);
+        assert_eq!(vm.reg_sp, sp - 1);
+    }
+
+    #[test]
+    fn instr_ret_underflow() {
+        let mut vm = test_vm(&[]);
+
+        let err = vm.execute(Instruction::RET).unwrap_err();
+
+        assert_eq!(
+            err,
+            VmError {
+                pc: MEM_PROGRAM_START,
+                opcode: 0x00EE,
+                kind: VmErrorKind::StackUnderflow,
+            }
+        );
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START);
     }
 
     #[test]
     fn instr_jp() {
-        let mut vm = create_vm();
-        let addr = 10;
+        let mut vm = test_vm(&[]);
+        let addr = 0x300;
         let instr = Instruction::JP(addr);
-        vm.execute(instr);
+        vm.execute(instr).unwrap();
 
         assert_eq!(vm.reg_pc, addr);
     }
 
     #[test]
     fn instr_call() {
-        let mut vm = create_vm();
-        vm.reg_pc = 5;
+        let mut vm = test_vm(&[]);
+        vm.reg_pc = 0x250;
 
-        let addr = 10;
+        let addr = 0x300;
         let instr = Instruction::CALL(addr);
-        vm.execute(instr);
+        vm.execute(instr).unwrap();
 
+        // should push the address of the next instruction
         assert_eq!(vm.reg_sp, 1);
-        assert_eq!(vm.stack[1], 5);
+        assert_eq!(vm.stack[0], 0x252);
         assert_eq!(vm.reg_pc, addr);
     }
 
+    #[test]
+    fn instr_call_ret() {
+        let mut vm = test_vm(&[]);
+
+        vm.execute(Instruction::CALL(0x300)).unwrap();
+        vm.execute(Instruction::RET).unwrap();
+
+        assert_eq!(vm.reg_sp, 0);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_call_overflow() {
+        let mut vm = test_vm(&[]);
+
+        for _ in 0..STACK_SIZE {
+            vm.execute(Instruction::CALL(0x300)).unwrap();
+        }
+        let err = vm.execute(Instruction::CALL(0x300)).unwrap_err();
+
+        assert_eq!(err.kind, VmErrorKind::StackOverflow);
+        assert_eq!(err.pc, 0x300);
+        assert_eq!(err.opcode, 0x2300);
+        assert_eq!(vm.reg_sp as usize, STACK_SIZE);
+    }
+
     #[test]
     fn instr_se_skip() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[2] = 10;
 
-        vm.execute(Instruction::SE(2, 10));
+        vm.execute(Instruction::SE(2, 10)).unwrap();
 
-        assert_eq!(vm.reg_pc, 2);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 4);
     }
 
     #[test]
     fn instr_se_noskip() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[2] = 9;
 
-        vm.execute(Instruction::SE(2, 10));
+        vm.execute(Instruction::SE(2, 10)).unwrap();
 
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_sne_skip() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[2] = 9;
 
-        vm.execute(Instruction::SNE(2, 10));
+        vm.execute(Instruction::SNE(2, 10)).unwrap();
 
-        assert_eq!(vm.reg_pc, 2);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 4);
     }
 
     #[test]
     fn instr_sne_noskip() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[2] = 10;
 
-        vm.execute(Instruction::SNE(2, 10));
+        vm.execute(Instruction::SNE(2, 10)).unwrap();
 
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_se2_skip() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 10;
         vm.gen_registers[2] = 10;
 
-        vm.execute(Instruction::SE2(1, 2));
+        vm.execute(Instruction::SE2(1, 2)).unwrap();
 
-        assert_eq!(vm.reg_pc, 2);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 4);
     }
 
     #[test]
     fn instr_se2_noskip() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 9;
         vm.gen_registers[2] = 10;
 
-        vm.execute(Instruction::SE2(1, 2));
+        vm.execute(Instruction::SE2(1, 2)).unwrap();
 
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_ld() {
-        let mut vm = create_vm();
-        vm.execute(Instruction::LD(3, 10));
+        let mut vm = test_vm(&[]);
+        vm.execute(Instruction::LD(3, 10)).unwrap();
 
         assert_eq!(vm.gen_registers[3], 10);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_add() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 1;
 
-        vm.execute(Instruction::ADD(1, 10));
+        vm.execute(Instruction::ADD(1, 10)).unwrap();
 
         assert_eq!(vm.gen_registers[1], 11);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_add_wraps() {
+        let mut vm = test_vm(&[]);
+        vm.gen_registers[1] = 0xFF;
+        vm.gen_registers[0xF] = 2; // carry flag should be left untouched
+
+        vm.execute(Instruction::ADD(1, 2)).unwrap();
+
+        assert_eq!(vm.gen_registers[1], 1);
+        assert_eq!(vm.gen_registers[0xF], 2);
     }
 
     #[test]
     fn instr_ld2() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 9;
         vm.gen_registers[2] = 10;
-        vm.execute(Instruction::LD2(1, 2));
+        vm.execute(Instruction::LD2(1, 2)).unwrap();
 
         assert_eq!(vm.gen_registers[1], 10);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_or() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 0b001;
         vm.gen_registers[2] = 0b011;
-        vm.execute(Instruction::OR(1, 2));
+        vm.execute(Instruction::OR(1, 2)).unwrap();
 
         assert_eq!(vm.gen_registers[1], 0b011);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_and() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 0b001;
         vm.gen_registers[2] = 0b011;
-        vm.execute(Instruction::AND(1, 2));
+        vm.execute(Instruction::AND(1, 2)).unwrap();
 
         assert_eq!(vm.gen_registers[1], 0b001);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_xor() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 0b001;
         vm.gen_registers[2] = 0b011;
-        vm.execute(Instruction::XOR(1, 2));
+        vm.execute(Instruction::XOR(1, 2)).unwrap();
 
         assert_eq!(vm.gen_registers[1], 0b010);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_logic_resets_vf() {
+        let mut vm = test_vm(&[]);
+        vm.gen_registers[0xF] = 2;
+        vm.execute(Instruction::OR(1, 2)).unwrap();
+        assert_eq!(vm.gen_registers[0xF], 0);
+
+        vm.set_quirks(Quirks::CHIP_48);
+        vm.gen_registers[0xF] = 2;
+        vm.execute(Instruction::AND(1, 2)).unwrap();
+        assert_eq!(vm.gen_registers[0xF], 2);
     }
 
     #[test]
     fn instr_add2_nooverflow() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 0b10000000;
         vm.gen_registers[2] = 0b01111111;
         vm.gen_registers[0xF] = 2; // to make sure register is set to zero
-        vm.execute(Instruction::ADD2(1, 2));
+        vm.execute(Instruction::ADD2(1, 2)).unwrap();
 
         assert_eq!(vm.gen_registers[1], 0b11111111);
         assert_eq!(vm.gen_registers[0xF], 0);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_add2_overflow() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 0b10000000;
         vm.gen_registers[2] = 0b10000001;
-        vm.execute(Instruction::ADD2(1, 2));
+        vm.execute(Instruction::ADD2(1, 2)).unwrap();
 
         assert_eq!(vm.gen_registers[1], 0b00000001);
         assert_eq!(vm.gen_registers[0xF], 1);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_sub_noborrow() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 3;
         vm.gen_registers[2] = 2;
-        vm.execute(Instruction::SUB(1, 2));
+        vm.execute(Instruction::SUB(1, 2)).unwrap();
 
         assert_eq!(vm.gen_registers[1], 1);
         assert_eq!(vm.gen_registers[0xF], 1);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_sub_borrow() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 2;
         vm.gen_registers[2] = 3;
         vm.gen_registers[0xF] = 2; // to make sure register is set to zero
-        vm.execute(Instruction::SUB(1, 2));
+        vm.execute(Instruction::SUB(1, 2)).unwrap();
 
-        assert_eq!(vm.gen_registers[1], 1);
+        assert_eq!(vm.gen_registers[1], 255);
         assert_eq!(vm.gen_registers[0xF], 0);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_sub_equal() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 3;
         vm.gen_registers[2] = 3;
-        vm.execute(Instruction::SUB(1, 2));
+        vm.execute(Instruction::SUB(1, 2)).unwrap();
 
         assert_eq!(vm.gen_registers[1], 0);
-        assert_eq!(vm.gen_registers[0xF], 0);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.gen_registers[0xF], 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_shr_odd() {
-        let mut vm = create_vm();
-        vm.gen_registers[1] = 0b111;
-        vm.execute(Instruction::SHR(1, 2));
+        let mut vm = test_vm(&[]);
+        vm.gen_registers[2] = 0b111;
+        vm.execute(Instruction::SHR(1, 2)).unwrap();
 
         assert_eq!(vm.gen_registers[1], 0b11);
         assert_eq!(vm.gen_registers[0xF], 1);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_shr_even() {
-        let mut vm = create_vm();
-        vm.gen_registers[1] = 0b100;
+        let mut vm = test_vm(&[]);
+        vm.gen_registers[2] = 0b100;
         vm.gen_registers[0xF] = 2; // to make sure register is set to zero
-        vm.execute(Instruction::SHR(1, 2));
+        vm.execute(Instruction::SHR(1, 2)).unwrap();
 
         assert_eq!(vm.gen_registers[1], 0b10);
         assert_eq!(vm.gen_registers[0xF], 0);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_shr_in_place() {
+        let mut vm = test_vm(&[]);
+        vm.set_quirks(Quirks::SUPER_CHIP);
+        vm.gen_registers[1] = 0b111;
+        vm.gen_registers[2] = 0b1000;
+        vm.execute(Instruction::SHR(1, 2)).unwrap();
+
+        assert_eq!(vm.gen_registers[1], 0b11);
+        assert_eq!(vm.gen_registers[0xF], 1);
+    }
+
+    #[test]
+    fn instr_shr_vf_target() {
+        let mut vm = test_vm(&[]);
+        vm.gen_registers[1] = 0b11;
+        vm.execute(Instruction::SHR(0xF, 1)).unwrap();
+
+        // the flag wins over the result
+        assert_eq!(vm.gen_registers[0xF], 1);
     }
 
     #[test]
     fn instr_subn_noborrow() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 2;
         vm.gen_registers[2] = 3;
-        vm.execute(Instruction::SUBN(1, 2));
+        vm.execute(Instruction::SUBN(1, 2)).unwrap();
 
         assert_eq!(vm.gen_registers[1], 1);
         assert_eq!(vm.gen_registers[0xF], 1);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_subn_borrow() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 3;
         vm.gen_registers[2] = 2;
         vm.gen_registers[0xF] = 2; // to make sure register is set to zero
-        vm.execute(Instruction::SUBN(1, 2));
+        vm.execute(Instruction::SUBN(1, 2)).unwrap();
 
-        assert_eq!(vm.gen_registers[1], 1);
+        assert_eq!(vm.gen_registers[1], 255);
         assert_eq!(vm.gen_registers[0xF], 0);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_subn_even() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 3;
         vm.gen_registers[2] = 3;
-        vm.gen_registers[0xF] = 2; // to make sure register is set to zero
-        vm.execute(Instruction::SUBN(1, 2));
+        vm.gen_registers[0xF] = 2;
+        vm.execute(Instruction::SUBN(1, 2)).unwrap();
 
         assert_eq!(vm.gen_registers[1], 0);
-        assert_eq!(vm.gen_registers[0xF], 0);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.gen_registers[0xF], 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_shr_nooverflow() {
-        let mut vm = create_vm();
-        vm.gen_registers[1] = 0b11000000;
-        vm.execute(Instruction::SHL(1, 2));
+        let mut vm = test_vm(&[]);
+        vm.gen_registers[2] = 0b11000000;
+        vm.execute(Instruction::SHL(1, 2)).unwrap();
 
         assert_eq!(vm.gen_registers[1], 0b10000000);
         assert_eq!(vm.gen_registers[0xF], 1);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_shr_overflow() {
-        let mut vm = create_vm();
-        vm.gen_registers[1] = 0b01000000;
+        let mut vm = test_vm(&[]);
+        vm.gen_registers[2] = 0b01000000;
         vm.gen_registers[0xF] = 2; // to make sure register is set to zero
-        vm.execute(Instruction::SHL(1, 2));
+        vm.execute(Instruction::SHL(1, 2)).unwrap();
 
         assert_eq!(vm.gen_registers[1], 0b10000000);
         assert_eq!(vm.gen_registers[0xF], 0);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_shl_in_place() {
+        let mut vm = test_vm(&[]);
+        vm.set_quirks(Quirks::SUPER_CHIP);
+        vm.gen_registers[1] = 0b11000000;
+        vm.gen_registers[2] = 0b1;
+        vm.execute(Instruction::SHL(1, 2)).unwrap();
+
+        assert_eq!(vm.gen_registers[1], 0b10000000);
+        assert_eq!(vm.gen_registers[0xF], 1);
     }
 
     #[test]
     fn instr_sne2_skip() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 8;
         vm.gen_registers[2] = 9;
 
-        vm.execute(Instruction::SNE2(1, 2));
+        vm.execute(Instruction::SNE2(1, 2)).unwrap();
 
-        assert_eq!(vm.reg_pc, 2);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 4);
     }
 
     #[test]
     fn instr_sne2_noskip() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[1] = 10;
         vm.gen_registers[2] = 10;
 
-        vm.execute(Instruction::SNE2(1, 2));
+        vm.execute(Instruction::SNE2(1, 2)).unwrap();
 
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_ldi() {
-        let mut vm = create_vm();
-        vm.execute(Instruction::LDI(0x555));
+        let mut vm = test_vm(&[]);
+        vm.execute(Instruction::LDI(0x555)).unwrap();
 
         assert_eq!(vm.reg_i, 0x555);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_jpv0() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.gen_registers[0] = 3;
-        vm.execute(Instruction::JPV0(0x300));
+        vm.execute(Instruction::JPV0(0x300)).unwrap();
 
         assert_eq!(vm.reg_pc, 0x303);
     }
 
+    #[test]
+    fn instr_jpv0_with_vx() {
+        let mut vm = test_vm(&[]);
+        vm.set_quirks(Quirks::CHIP_48);
+        vm.gen_registers[0] = 3;
+        vm.gen_registers[3] = 4;
+        vm.execute(Instruction::JPV0(0x300)).unwrap();
+
+        assert_eq!(vm.reg_pc, 0x304);
+    }
+
     #[test]
     fn instr_rnd() {
-        let mut vm = create_vm();
-        vm.rng = Box::new(StepRng::new(0b110, 1));
+        let mut vm = test_vm(&[]);
+        vm.rng = rng::Rng::from_state(1);
+        let value = rng::Rng::from_state(1).next_u8();
 
-        vm.execute(Instruction::RND(1, 0b101));
+        vm.execute(Instruction::RND(1, 0b101)).unwrap();
 
-        assert_eq!(vm.gen_registers[1], 0b100);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.gen_registers[1], value & 0b101);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn seeded_rnd() {
+        let mut a = test_vm(&[]);
+        let mut b = test_vm(&[]);
+        a.set_seed(1234);
+        b.set_seed(1234);
+
+        for _ in 0..10 {
+            a.execute(Instruction::RND(0, 0xFF)).unwrap();
+            b.execute(Instruction::RND(0, 0xFF)).unwrap();
+            assert_eq!(a.gen_registers[0], b.gen_registers[0]);
+        }
     }
 
     #[test]
     fn instr_drw() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
 
         let sprite1 = 0b11 << 3;
         let sprite2 = 0b11 << 2;
@@ -901,162 +2099,362 @@ mod test {
         vm.gen_registers[x as usize] = vx;
         vm.gen_registers[y as usize] = vy;
 
-        vm.execute(Instruction::DRW(x, y, n));
+        vm.execute(Instruction::DRW(x, y, n)).unwrap();
 
-        let expected1 = create_sprite_mask(sprite1, vx);
-        let expected2 = create_sprite_mask(sprite2, vx);
+        let expected1 = display::sprite_mask(sprite1 as u16, 8, vx as usize, 64, EdgeMode::Clip);
+        let expected2 = display::sprite_mask(sprite2 as u16, 8, vx as usize, 64, EdgeMode::Clip);
 
         assert_eq!(vm.display[vy as usize], expected1);
         assert_eq!(vm.display[(vy + 1) as usize], expected2);
         assert_eq!(vm.gen_registers[0xF], 0);
 
         // should inc PC
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_drw_collision() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
 
         vm.display[0] = 0b1;
         vm.memory[MEM_PROGRAM_START as usize] = 0b1;
         vm.reg_i = MEM_PROGRAM_START;
-        vm.gen_registers[0 as usize] = 56;
-        vm.gen_registers[1 as usize] = 0;
+        vm.gen_registers[0] = 56;
+        vm.gen_registers[1] = 0;
 
-        vm.execute(Instruction::DRW(0, 1, 1));
+        vm.execute(Instruction::DRW(0, 1, 1)).unwrap();
 
         assert_eq!(vm.display[0], 0);
         assert_eq!(vm.gen_registers[0xF], 1);
     }
 
+    #[test]
+    fn instr_drw_collision_any_row() {
+        let mut vm = test_vm(&[]);
+
+        // only the first row collides
+        vm.display[0] = 0b1;
+        vm.memory[MEM_PROGRAM_START as usize] = 0b1;
+        vm.memory[MEM_PROGRAM_START as usize + 1] = 0b1;
+        vm.reg_i = MEM_PROGRAM_START;
+        vm.gen_registers[0] = 56;
+        vm.gen_registers[1] = 0;
+
+        vm.execute(Instruction::DRW(0, 1, 2)).unwrap();
+
+        assert_eq!(vm.display[0], 0);
+        assert_eq!(vm.display[1], 0b1);
+        assert_eq!(vm.gen_registers[0xF], 1);
+    }
+
+    #[test]
+    fn instr_drw_wraps_start_position() {
+        let mut vm = test_vm(&[]);
+
+        vm.memory[MEM_PROGRAM_START as usize] = 0b10000000;
+        vm.reg_i = MEM_PROGRAM_START;
+        vm.gen_registers[0] = 64 + 3;
+        vm.gen_registers[1] = 32 + 4;
+
+        vm.execute(Instruction::DRW(0, 1, 1)).unwrap();
+
+        assert_eq!(vm.display[4], 1 << (63 - 3));
+    }
+
+    #[test]
+    fn instr_drw_clip() {
+        let mut vm = test_vm(&[]);
+
+        vm.memory[MEM_PROGRAM_START as usize] = 0xFF;
+        vm.memory[MEM_PROGRAM_START as usize + 1] = 0xFF;
+        vm.reg_i = MEM_PROGRAM_START;
+        vm.gen_registers[0] = 60;
+        vm.gen_registers[1] = 31;
+
+        vm.execute(Instruction::DRW(0, 1, 2)).unwrap();
+
+        assert_eq!(vm.display[31], 0b1111);
+        assert_eq!(vm.display[0], 0);
+    }
+
+    #[test]
+    fn instr_drw_wrap() {
+        let mut vm = test_vm(&[]);
+        vm.set_quirks(Quirks {
+            sprite_edge: EdgeMode::Wrap,
+            ..Quirks::default()
+        });
+
+        vm.memory[MEM_PROGRAM_START as usize] = 0xFF;
+        vm.memory[MEM_PROGRAM_START as usize + 1] = 0xFF;
+        vm.reg_i = MEM_PROGRAM_START;
+        vm.gen_registers[0] = 60;
+        vm.gen_registers[1] = 31;
+
+        vm.execute(Instruction::DRW(0, 1, 2)).unwrap();
+
+        let expected = 0b1111 | (0b1111 << 60);
+        assert_eq!(vm.display[31], expected);
+        assert_eq!(vm.display[0], expected);
+    }
+
+    #[test]
+    fn instr_drw_display_wait() {
+        let mut vm = test_vm(&[]);
+        vm.memory[MEM_PROGRAM_START as usize] = 0b1;
+        vm.reg_i = MEM_PROGRAM_START;
+
+        vm.execute(Instruction::DRW(0, 1, 1)).unwrap();
+        vm.execute(Instruction::DRW(0, 1, 1)).unwrap();
+
+        // the second sprite waits for the next frame
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+        assert_eq!(vm.display[0], 1 << 56);
+
+        vm.tick_timers();
+        vm.execute(Instruction::DRW(0, 1, 1)).unwrap();
+
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 4);
+        assert_eq!(vm.display[0], 0);
+    }
+
+    #[test]
+    fn step_display_wait_needs_ticks() {
+        // DRW V0, V0, 1 twice
+        let mut vm = test_vm(&[0xD0, 0x01, 0xD0, 0x01]);
+
+        for _ in 0..10 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+
+        vm.tick_timers();
+        vm.step().unwrap();
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 4);
+    }
+
+    #[test]
+    fn instr_drw_no_display_wait() {
+        let mut vm = test_vm(&[]);
+        vm.set_quirks(Quirks::MODERN);
+        vm.memory[MEM_PROGRAM_START as usize] = 0b1;
+        vm.reg_i = MEM_PROGRAM_START;
+
+        vm.execute(Instruction::DRW(0, 1, 1)).unwrap();
+        vm.execute(Instruction::DRW(0, 1, 1)).unwrap();
+
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 4);
+    }
+
+    #[test]
+    fn instr_drw_out_of_bounds() {
+        let mut vm = test_vm(&[]);
+        vm.reg_i = (SMALL_RAM_SIZE - 1) as u16;
+
+        let err = vm.execute(Instruction::DRW(0, 1, 2)).unwrap_err();
+
+        assert_eq!(err.kind, VmErrorKind::MemoryOutOfBounds(SMALL_RAM_SIZE));
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START);
+    }
+
     #[test]
     fn instr_skp_pressed() {
-        let mut vm = create_vm();
-        vm.keyboard = keys::Keyboard::new();
-        vm.keyboard.set_pressed(keys::Key::Key3);
+        let mut vm = test_vm(&[]);
+        vm.keypad.press(keys::Key::Key3);
         vm.gen_registers[1] = 3;
 
-        vm.execute(Instruction::SKP(1));
+        vm.execute(Instruction::SKP(1)).unwrap();
 
-        assert_eq!(vm.reg_pc, 2);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 4);
     }
 
     #[test]
     fn instr_skp_notpressed() {
-        let mut vm = create_vm();
-        vm.keyboard = keys::Keyboard::new();
-        vm.keyboard.set_pressed(keys::Key::Key2);
+        let mut vm = test_vm(&[]);
+        vm.keypad.press(keys::Key::Key2);
         vm.gen_registers[1] = 3;
 
-        vm.execute(Instruction::SKP(1));
+        vm.execute(Instruction::SKP(1)).unwrap();
 
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_sknp_pressed() {
-        let mut vm = create_vm();
-        vm.keyboard = keys::Keyboard::new();
-        vm.keyboard.set_pressed(keys::Key::Key3);
+        let mut vm = test_vm(&[]);
+        vm.keypad.press(keys::Key::Key3);
         vm.gen_registers[1] = 3;
 
-        vm.execute(Instruction::SKNP(1));
-        assert_eq!(vm.reg_pc, 1);
+        vm.execute(Instruction::SKNP(1)).unwrap();
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_sknp_notpressed() {
-        let mut vm = create_vm();
-        vm.keyboard = keys::Keyboard::new();
-        vm.keyboard.set_pressed(keys::Key::Key2);
+        let mut vm = test_vm(&[]);
+        vm.keypad.press(keys::Key::Key2);
         vm.gen_registers[1] = 3;
 
-        vm.execute(Instruction::SKNP(1));
+        vm.execute(Instruction::SKNP(1)).unwrap();
 
-        assert_eq!(vm.reg_pc, 2);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 4);
+    }
+
+    #[test]
+    fn instr_skp_invalid_key() {
+        let mut vm = test_vm(&[]);
+        vm.gen_registers[1] = 0x10;
+
+        let err = vm.execute(Instruction::SKP(1)).unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::InvalidKey(0x10));
+
+        let err = vm.execute(Instruction::SKNP(1)).unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::InvalidKey(0x10));
     }
 
     #[test]
     fn instr_ld3() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.reg_delay = 3;
-        vm.execute(Instruction::LD3(1));
+        vm.execute(Instruction::LD3(1)).unwrap();
         assert_eq!(vm.gen_registers[1], 3);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
-    // TODO: This test will probably fail occasionally - can we do better?
     #[test]
     fn instr_ld4() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
 
-        let keyboard2 = vm.keyboard.clone();
-        thread::spawn(move || {
-            keyboard2.set_pressed(keys::Key::Key4);
+        vm.execute(Instruction::LD4(1)).unwrap();
+        assert!(vm.is_waiting_for_key());
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START);
+
+        // the key counts once it has been pressed and released
+        vm.keypad.press(keys::Key::Key4);
+        vm.execute(Instruction::LD4(1)).unwrap();
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START);
+        vm.keypad.release(keys::Key::Key4);
+        vm.execute(Instruction::LD4(1)).unwrap();
+
+        assert!(!vm.is_waiting_for_key());
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+        assert_eq!(vm.gen_registers[1], 4);
+    }
+
+    #[test]
+    fn instr_ld4_press() {
+        let mut vm = test_vm(&[]);
+        vm.set_quirks(Quirks {
+            key_wait: KeyWait::Press,
+            ..Quirks::default()
         });
 
-        vm.execute(Instruction::LD4(1));
+        vm.execute(Instruction::LD4(1)).unwrap();
+        vm.keypad.press(keys::Key::Key4);
+        vm.execute(Instruction::LD4(1)).unwrap();
 
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
         assert_eq!(vm.gen_registers[1], 4);
     }
 
+    #[test]
+    fn instr_ld4_held_key() {
+        let mut vm = test_vm(&[]);
+        vm.keypad.press(keys::Key::Key4);
+
+        // a key held when the wait starts has to be released and pressed again
+        vm.execute(Instruction::LD4(1)).unwrap();
+        vm.keypad.release(keys::Key::Key4);
+        vm.execute(Instruction::LD4(1)).unwrap();
+        assert!(vm.is_waiting_for_key());
+
+        vm.keypad.press(keys::Key::Key4);
+        vm.execute(Instruction::LD4(1)).unwrap();
+        vm.keypad.release(keys::Key::Key4);
+        vm.execute(Instruction::LD4(1)).unwrap();
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_ld4_timers_keep_running() {
+        // LD V1, K
+        let mut vm = test_vm(&[0xF1, 0x0A]);
+        vm.set_timer_mode(TimerMode::Automatic {
+            clock_speed: TIMER_FREQUENCY,
+        });
+        vm.reg_delay = 10;
+
+        vm.run_for(4).unwrap();
+
+        assert_eq!(vm.reg_delay, 6);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START);
+    }
+
     #[test]
     fn instr_ld5() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.reg_delay = 3;
         vm.gen_registers[1] = 4;
-        vm.execute(Instruction::LD5(1));
+        vm.execute(Instruction::LD5(1)).unwrap();
         assert_eq!(vm.reg_delay, 4);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_ld6() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.reg_sound = 3;
         vm.gen_registers[1] = 4;
-        vm.execute(Instruction::LD6(1));
+        vm.execute(Instruction::LD6(1)).unwrap();
         assert_eq!(vm.reg_sound, 4);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_add3() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.reg_i = 3;
         vm.gen_registers[1] = 4;
-        vm.execute(Instruction::ADD3(1));
+        vm.execute(Instruction::ADD3(1)).unwrap();
         assert_eq!(vm.reg_i, 7);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_ld7() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.reg_i = 3;
         vm.gen_registers[1] = 4;
-        vm.execute(Instruction::LD7(1));
+        vm.execute(Instruction::LD7(1)).unwrap();
         assert_eq!(vm.reg_i, digit(4) as u16);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
     }
 
     #[test]
     fn instr_ld8() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         vm.reg_i = 3;
         vm.gen_registers[1] = 123;
-        vm.execute(Instruction::LD8(1));
+        vm.execute(Instruction::LD8(1)).unwrap();
         assert_eq!(vm.memory[3], 1);
         assert_eq!(vm.memory[4], 2);
         assert_eq!(vm.memory[5], 3);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_ld8_out_of_bounds() {
+        let mut vm = test_vm(&[]);
+        vm.reg_i = (SMALL_RAM_SIZE - 2) as u16;
+
+        let err = vm.execute(Instruction::LD8(1)).unwrap_err();
+
+        assert_eq!(err.kind, VmErrorKind::MemoryOutOfBounds(SMALL_RAM_SIZE));
     }
 
     #[test]
     fn instr_ld9() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         let start = MEM_PROGRAM_START as usize;
         vm.reg_i = start as u16;
         vm.gen_registers[0] = 4;
@@ -1064,18 +2462,51 @@ mod test {
         vm.gen_registers[2] = 6;
         vm.gen_registers[3] = 7;
 
-        vm.execute(Instruction::LD9(2));
+        vm.execute(Instruction::LD9(2)).unwrap();
 
         assert_eq!(vm.memory[start], 4);
         assert_eq!(vm.memory[start + 1], 5);
         assert_eq!(vm.memory[start + 2], 6);
         assert_eq!(vm.memory[start + 3], 0);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_ld9_index_increment() {
+        let quirks = [
+            (Quirks::COSMAC_VIP, 0x303),
+            (Quirks::CHIP_48, 0x302),
+            (Quirks::SUPER_CHIP, 0x300),
+        ];
+
+        for &(quirks, expected) in quirks.iter() {
+            let mut vm = test_vm(&[]);
+            vm.set_quirks(quirks);
+            vm.reg_i = 0x300;
+
+            vm.execute(Instruction::LD9(2)).unwrap();
+            assert_eq!(vm.reg_i, expected);
+
+            vm.reg_i = 0x300;
+            vm.execute(Instruction::LD10(2)).unwrap();
+            assert_eq!(vm.reg_i, expected);
+        }
+    }
+
+    #[test]
+    fn instr_ld9_out_of_bounds() {
+        let mut vm = test_vm(&[]);
+        vm.reg_i = (SMALL_RAM_SIZE - 2) as u16;
+
+        let err = vm.execute(Instruction::LD9(2)).unwrap_err();
+
+        assert_eq!(err.kind, VmErrorKind::MemoryOutOfBounds(SMALL_RAM_SIZE));
+        assert_eq!(vm.memory[SMALL_RAM_SIZE - 1], 0);
     }
 
     #[test]
     fn instr_ld10() {
-        let mut vm = create_vm();
+        let mut vm = test_vm(&[]);
         let start = MEM_PROGRAM_START as usize;
         vm.reg_i = start as u16;
         vm.memory[start] = 4;
@@ -1083,12 +2514,429 @@ mod test {
         vm.memory[start + 2] = 6;
         vm.memory[start + 3] = 7;
 
-        vm.execute(Instruction::LD10(2));
+        vm.execute(Instruction::LD10(2)).unwrap();
 
         assert_eq!(vm.gen_registers[0], 4);
         assert_eq!(vm.gen_registers[1], 5);
         assert_eq!(vm.gen_registers[2], 6);
         assert_eq!(vm.gen_registers[3], 0);
-        assert_eq!(vm.reg_pc, 1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_ld10_out_of_bounds() {
+        let mut vm = test_vm(&[]);
+        vm.reg_i = (SMALL_RAM_SIZE - 1) as u16;
+
+        let err = vm.execute(Instruction::LD10(1)).unwrap_err();
+
+        assert_eq!(err.kind, VmErrorKind::MemoryOutOfBounds(SMALL_RAM_SIZE));
+    }
+
+    #[test]
+    fn instr_scd() {
+        let mut vm = test_vm(&[]);
+        vm.display[0] = 0b1;
+
+        vm.execute(Instruction::SCD(3)).unwrap();
+
+        assert_eq!(vm.display[0], 0);
+        assert_eq!(vm.display[3], 0b1);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_scr() {
+        let mut vm = test_vm(&[]);
+        vm.display[0] = 0xF0;
+
+        vm.execute(Instruction::SCR).unwrap();
+
+        assert_eq!(vm.display[0], 0x0F);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_scl() {
+        let mut vm = test_vm(&[]);
+        vm.display[0] = 0x0F | (1 << 63);
+
+        vm.execute(Instruction::SCL).unwrap();
+
+        assert_eq!(vm.display[0], 0xF0);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_exit() {
+        let mut vm = test_vm(&[0x00, 0xFD, 0x60, 0x01]);
+
+        vm.step().unwrap();
+        vm.step().unwrap();
+
+        assert!(vm.has_exited());
+        assert_eq!(vm.gen_registers[0], 0);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START);
+    }
+
+    #[test]
+    fn instr_high_low() {
+        let mut vm = test_vm(&[]);
+        vm.display[0] = 0b1;
+
+        vm.execute(Instruction::HIGH).unwrap();
+        assert!(vm.display.is_hires());
+        assert_eq!(vm.display[0], 0);
+
+        vm.display[0] = 0b1;
+        vm.execute(Instruction::LOW).unwrap();
+        assert!(!vm.display.is_hires());
+        assert_eq!(vm.display[0], 0);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 4);
+    }
+
+    #[test]
+    fn instr_drw_16x16() {
+        let mut vm = test_vm(&[]);
+        vm.set_quirks(Quirks::SUPER_CHIP);
+        vm.display.set_hires(true);
+
+        let start = MEM_PROGRAM_START as usize;
+        for i in 0..32 {
+            vm.memory[start + i] = 0xFF;
+        }
+        vm.reg_i = MEM_PROGRAM_START;
+        vm.gen_registers[0] = 0;
+        vm.gen_registers[1] = 0;
+
+        vm.execute(Instruction::DRW(0, 1, 0)).unwrap();
+
+        assert_eq!(vm.display[0], 0xFFFF << 112);
+        assert_eq!(vm.display[15], 0xFFFF << 112);
+        assert_eq!(vm.display[16], 0);
+        assert_eq!(vm.gen_registers[0xF], 0);
+    }
+
+    #[test]
+    fn instr_ld11() {
+        let mut vm = test_vm(&[]);
+        vm.gen_registers[0] = 9;
+
+        vm.execute(Instruction::LD11(0)).unwrap();
+
+        assert_eq!(vm.reg_i, (MEM_BIG_FONT_START + 90) as u16);
+        assert_eq!(
+            &vm.memory[vm.reg_i as usize..vm.reg_i as usize + 10],
+            &BIG_FONT[90..100]
+        );
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_ld12_ld13() {
+        let mut vm = test_vm(&[]);
+        vm.gen_registers[0] = 1;
+        vm.gen_registers[1] = 2;
+        vm.gen_registers[2] = 3;
+
+        vm.execute(Instruction::LD12(1)).unwrap();
+        vm.gen_registers = [0; NUM_REGISTERS];
+        vm.execute(Instruction::LD13(2)).unwrap();
+
+        assert_eq!(vm.gen_registers[0], 1);
+        assert_eq!(vm.gen_registers[1], 2);
+        assert_eq!(vm.gen_registers[2], 0);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 4);
+    }
+
+    #[test]
+    fn instr_scu() {
+        let mut vm = test_vm(&[]);
+        vm.display[3] = 0b1;
+
+        vm.execute(Instruction::SCU(3)).unwrap();
+
+        assert_eq!(vm.display[0], 0b1);
+        assert_eq!(vm.display[3], 0);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_save() {
+        let mut vm = test_vm(&[]);
+        let start = 0x300;
+        vm.reg_i = start as u16;
+        vm.gen_registers[1] = 1;
+        vm.gen_registers[2] = 2;
+        vm.gen_registers[3] = 3;
+
+        vm.execute(Instruction::SAVE(1, 3)).unwrap();
+        assert_eq!(vm.memory[start..start + 4], [1, 2, 3, 0]);
+
+        // X > Y saves in reverse order
+        vm.execute(Instruction::SAVE(3, 1)).unwrap();
+        assert_eq!(vm.memory[start..start + 4], [3, 2, 1, 0]);
+
+        assert_eq!(vm.reg_i, start as u16);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 4);
+    }
+
+    #[test]
+    fn instr_load() {
+        let mut vm = test_vm(&[]);
+        let start = 0x300;
+        vm.reg_i = start as u16;
+        vm.memory[start] = 4;
+        vm.memory[start + 1] = 5;
+
+        vm.execute(Instruction::LOAD(6, 5)).unwrap();
+
+        assert_eq!(vm.gen_registers[6], 4);
+        assert_eq!(vm.gen_registers[5], 5);
+        assert_eq!(vm.reg_i, start as u16);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_save_out_of_bounds() {
+        let mut vm = test_vm(&[]);
+        vm.reg_i = (SMALL_RAM_SIZE - 1) as u16;
+
+        let err = vm.execute(Instruction::SAVE(0, 1)).unwrap_err();
+
+        assert_eq!(err.kind, VmErrorKind::MemoryOutOfBounds(SMALL_RAM_SIZE));
+    }
+
+    #[test]
+    fn instr_ldil() {
+        let mut vm = test_vm(&[0xF0, 0x00, 0xAB, 0xCD]);
+
+        vm.step().unwrap();
+
+        assert_eq!(vm.reg_i, 0xABCD);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 4);
+    }
+
+    #[test]
+    fn instr_skip_ldil() {
+        let mut vm = test_vm(&[0x30, 0x00, 0xF0, 0x00, 0xAB, 0xCD]);
+
+        vm.step().unwrap();
+
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 6);
+    }
+
+    #[test]
+    fn instr_drw_planes() {
+        let mut vm = test_vm(&[]);
+        vm.set_quirks(Quirks::MODERN);
+        vm.memory[0x300] = 0x80;
+        vm.memory[0x301] = 0xC0;
+        vm.reg_i = 0x300;
+
+        vm.execute(Instruction::PLANE(3)).unwrap();
+        vm.execute(Instruction::DRW(0, 0, 1)).unwrap();
+
+        assert_eq!(vm.display.color(0, 0), 0b11);
+        assert_eq!(vm.display.color(1, 0), 0b10);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 4);
+    }
+
+    #[test]
+    fn instr_audio() {
+        let mut vm = test_vm(&[]);
+        for i in 0..AUDIO_PATTERN_SIZE {
+            vm.memory[0x300 + i] = i as u8;
+        }
+        vm.reg_i = 0x300;
+        assert_eq!(vm.audio_pattern, None);
+
+        vm.execute(Instruction::AUDIO).unwrap();
+
+        let pattern = vm.audio_pattern.unwrap();
+        assert_eq!(pattern[0], 0);
+        assert_eq!(pattern[15], 15);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn instr_pitch() {
+        let mut vm = test_vm(&[]);
+        assert_eq!(vm.reg_pitch, DEFAULT_PITCH);
+        vm.gen_registers[2] = 112;
+
+        vm.execute(Instruction::PITCH(2)).unwrap();
+
+        assert_eq!(vm.reg_pitch, 112);
+        assert_eq!(vm.reg_pc, MEM_PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn watch_memory_write() {
+        let mut vm = test_vm(&[0xA3, 0x00, 0xF2, 0x55, 0xF2, 0x33]);
+        vm.add_watchpoint(Watchpoint::Write(0x302));
+
+        assert_eq!(vm.step(), Ok(None));
+        assert_eq!(vm.step(), Ok(Some(Watchpoint::Write(0x302))));
+        // LD B, V2 writes 3 bytes starting at the increased I
+        assert_eq!(vm.step(), Ok(None));
+    }
+
+    #[test]
+    fn watch_memory_read() {
+        let mut vm = test_vm(&[0xA3, 0x00, 0xD0, 0x15, 0xF1, 0x65]);
+        vm.add_watchpoint(Watchpoint::Read(0x301));
+        vm.add_watchpoint(Watchpoint::Write(0x301));
+
+        assert_eq!(vm.step(), Ok(None));
+        assert_eq!(vm.step(), Ok(Some(Watchpoint::Read(0x301))));
+        assert_eq!(vm.step(), Ok(Some(Watchpoint::Read(0x301))));
+    }
+
+    #[test]
+    fn watch_register() {
+        let mut vm = test_vm(&[0x63, 0x05, 0x63, 0x05, 0x73, 0x01, 0xA2, 0x34]);
+        vm.add_watchpoint(Watchpoint::Register(Register::V(3), Change::From(5)));
+        vm.add_watchpoint(Watchpoint::Register(Register::I, Change::Any));
+
+        // setting the same value is not a change
+        assert_eq!(vm.step(), Ok(None));
+        assert_eq!(vm.step(), Ok(None));
+        assert_eq!(
+            vm.step(),
+            Ok(Some(Watchpoint::Register(Register::V(3), Change::From(5))))
+        );
+        assert_eq!(
+            vm.step(),
+            Ok(Some(Watchpoint::Register(Register::I, Change::Any)))
+        );
+    }
+
+    #[test]
+    fn remove_watchpoint() {
+        let mut vm = test_vm(&[]);
+        vm.add_watchpoint(Watchpoint::Read(0x300));
+        vm.add_watchpoint(Watchpoint::Read(0x300));
+        assert_eq!(vm.watchpoints(), [Watchpoint::Read(0x300)]);
+
+        assert!(vm.remove_watchpoint(Watchpoint::Read(0x300)));
+        assert!(!vm.remove_watchpoint(Watchpoint::Read(0x300)));
+        assert!(vm.watchpoints().is_empty());
+    }
+
+    #[test]
+    fn step_back() {
+        let mut vm = test_vm(&[0x60, 0x05, 0xFF, 0xFF]);
+        assert!(!vm.step_back());
+
+        vm.set_history(1 << 20);
+        vm.step().unwrap();
+        // a failed instruction is not recorded
+        vm.step().unwrap_err();
+        assert!(vm.step_back());
+
+        assert_eq!(vm.gen_registers[0], 0);
+        assert_eq!(vm.reg_pc, 0x200);
+        assert!(!vm.step_back());
+    }
+
+    #[test]
+    fn trace_flushed_after_error() {
+        let path =
+            std::env::temp_dir().join(format!("chip8-trace-error-{}.log", std::process::id()));
+        let mut vm = test_vm(&[0x00, 0xEE]);
+        vm.set_tracer(Some(Tracer::to_file(&path).unwrap()));
+
+        let result = vm.step().map(|_| ());
+        let err = finish_session(&mut vm, result).unwrap_err();
+        let trace = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(err.to_string().starts_with("stack underflow"));
+        assert!(trace.contains("error: stack underflow"));
+        assert!(vm.take_tracer().is_none());
+    }
+
+    /// Gives a command on chosen frames and quits after the last one.
+    struct ScriptedFrontend {
+        frame: usize,
+        commands: Vec<(usize, Command)>,
+        /// The keys to hold from a frame on, as a mask.
+        keys: Vec<(usize, u16)>,
+        frames: usize,
+    }
+
+    impl Frontend for ScriptedFrontend {
+        fn present(&mut self, _display: &Display) {
+            self.frame += 1;
+        }
+
+        fn poll_input(&mut self, keypad: &mut keys::Keypad) -> bool {
+            for &(_, keys) in self.keys.iter().filter(|(at, _)| *at == self.frame) {
+                *keypad = keys::Keypad::from_mask(keys);
+            }
+            self.frame < self.frames
+        }
+
+        fn poll_commands(&mut self) -> Vec<Command> {
+            let frame = self.frame;
+            self.commands
+                .iter()
+                .filter(|(at, _)| *at == frame)
+                .map(|&(_, command)| command)
+                .collect()
+        }
+
+        fn play_tone(&mut self) {}
+
+        fn stop_tone(&mut self) {}
+
+        fn set_audio(&mut self, _pattern: &[u8; 16], _pitch: u8) {}
+    }
+
+    #[test]
+    fn quick_save_and_load() {
+        // ADD V0, 1; JP 0x200
+        let mut vm = test_vm(&[0x70, 0x01, 0x12, 0x00]);
+        let config = Config {
+            clock_speed: FRAMES_PER_SECOND,
+            ..Config::default()
+        };
+        let mut frontend = ScriptedFrontend {
+            frame: 0,
+            commands: vec![(1, Command::QuickSave), (3, Command::QuickLoad)],
+            keys: Vec::new(),
+            frames: 4,
+        };
+
+        run_with(&mut vm, &config, &mut frontend).unwrap();
+
+        // saved after the first ADD, then loaded and continued with JP
+        assert_eq!(vm.gen_registers[0], 1);
+        assert_eq!(vm.reg_pc, 0x200);
+    }
+
+    #[test]
+    fn record_and_replay() {
+        // RND V0, 0xFF; LD V1, 5; SKP V1; JP 0x200; ADD V2, V0; JP 0x200
+        let rom = [
+            0xC0, 0xFF, 0x61, 0x05, 0xE1, 0x9E, 0x12, 0x00, 0x82, 0x04, 0x12, 0x00,
+        ];
+        let mut vm = test_vm(&rom);
+        let config = Config::default();
+        let mut frontend = ScriptedFrontend {
+            frame: 0,
+            commands: vec![(4, Command::StartRewind)],
+            keys: vec![(2, 1 << 5), (5, 0)],
+            frames: 8,
+        };
+        let mut movie = Movie::new(&mut vm, &config);
+
+        run_frames(&mut vm, &config, &mut frontend, Input::Record(&mut movie)).unwrap();
+        assert_eq!(movie.len(), 8);
+        assert_ne!(vm.gen_registers[2], 0);
+
+        let movie = Movie::parse(&movie.to_bytes()).unwrap();
+        let replayed = movie.replay(&rom).unwrap();
+        assert_eq!(replayed.save_state(), vm.save_state());
     }
 }
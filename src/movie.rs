@@ -0,0 +1,269 @@
+use super::keys::Keypad;
+use super::state::{self, QUIRKS_SIZE};
+use super::{load_vm, Config, FrameCycles, TimerMode, VM};
+
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"C8MV";
+/// Increased whenever the header or the encoding of frames changes.
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = MAGIC.len() + 1 + 4 + 8 + 4 + QUIRKS_SIZE;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MovieError {
+    /// The data does not start like a movie.
+    NotAMovie,
+    /// The movie was recorded by a different version of the emulator.
+    UnsupportedVersion(u8),
+    /// The movie was recorded while running a different ROM.
+    WrongRom,
+    /// The header is cut short, a frame is missing its second byte, or the
+    /// quirks hold values no quirk can have.
+    Corrupt,
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::NotAMovie => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "unsupported movie version {}", version)
+            }
+            MovieError::WrongRom => write!(f, "movie is for a different ROM"),
+            MovieError::Corrupt => write!(f, "movie is corrupt"),
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+/// The keys held in every frame of a session, together with everything else
+/// the session depended on. Replaying a movie from power-on reproduces the
+/// session exactly.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Movie {
+    rom_checksum: u32,
+    seed: u64,
+    clock_speed: u32,
+    quirks: [u8; QUIRKS_SIZE],
+    /// A mask of the held keys per frame, with bit n set for key n.
+    frames: Vec<u16>,
+}
+
+impl Movie {
+    /// Starts a movie of the ROM loaded into `vm`, reseeding it with the
+    /// configured seed or a random one. Frames are added with `push`.
+    pub fn new(vm: &mut VM, config: &Config) -> Movie {
+        let seed = config.seed.unwrap_or_else(rand::random);
+        vm.set_seed(seed);
+        Movie {
+            rom_checksum: vm.rom_checksum,
+            seed,
+            clock_speed: config.clock_speed,
+            quirks: state::encode_quirks(config.quirks),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Encodes the movie as a header holding the magic bytes, version, ROM
+    /// checksum, seed, clock speed and quirks, followed by two bytes per
+    /// frame with the held keys. Numbers are big-endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_SIZE + 2 * self.frames.len());
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&self.rom_checksum.to_be_bytes());
+        out.extend_from_slice(&self.seed.to_be_bytes());
+        out.extend_from_slice(&self.clock_speed.to_be_bytes());
+        out.extend_from_slice(&self.quirks);
+        for keys in self.frames.iter() {
+            out.extend_from_slice(&keys.to_be_bytes());
+        }
+        out
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Movie, MovieError> {
+        if !data.starts_with(MAGIC) {
+            return Err(MovieError::NotAMovie);
+        }
+        if data.len() < HEADER_SIZE {
+            return Err(MovieError::Corrupt);
+        }
+        let version = data[MAGIC.len()];
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        let (header, frames) = data[MAGIC.len() + 1..].split_at(HEADER_SIZE - MAGIC.len() - 1);
+        let quirks = header[16..].try_into().unwrap();
+        if state::decode_quirks(quirks).is_none() || frames.len() % 2 != 0 {
+            return Err(MovieError::Corrupt);
+        }
+
+        Ok(Movie {
+            rom_checksum: u32::from_be_bytes(header[0..4].try_into().unwrap()),
+            seed: u64::from_be_bytes(header[4..12].try_into().unwrap()),
+            clock_speed: u32::from_be_bytes(header[12..16].try_into().unwrap()),
+            quirks: *quirks,
+            frames: frames
+                .chunks(2)
+                .map(|keys| u16::from_be_bytes([keys[0], keys[1]]))
+                .collect(),
+        })
+    }
+
+    /// Returns the number of recorded frames.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Replays the whole movie on the ROM as fast as possible, returning the
+    /// VM as the session ended.
+    pub fn replay(&self, rom: &[u8]) -> Result<VM, Box<dyn Error>> {
+        let mut config = Config::default();
+        self.apply(rom, &mut config)?;
+        let mut vm = load_vm(rom, &config)?;
+        vm.set_timer_mode(TimerMode::Automatic {
+            clock_speed: config.clock_speed,
+        });
+
+        let mut frame_cycles = FrameCycles::new(&config);
+        for &keys in self.frames.iter() {
+            if vm.has_exited() {
+                break;
+            }
+            vm.keypad = Keypad::from_mask(keys);
+            vm.run_for(frame_cycles.next_frame())?;
+        }
+        Ok(vm)
+    }
+
+    /// Sets up `config` to replay the movie on `rom` from power-on, before
+    /// the ROM is loaded.
+    pub(crate) fn apply(&self, rom: &[u8], config: &mut Config) -> Result<(), MovieError> {
+        if state::checksum(rom) != self.rom_checksum {
+            return Err(MovieError::WrongRom);
+        }
+        config.seed = Some(self.seed);
+        config.clock_speed = self.clock_speed;
+        config.quirks = state::decode_quirks(&self.quirks).ok_or(MovieError::Corrupt)?;
+        Ok(())
+    }
+
+    pub(crate) fn frame(&self, frame: usize) -> Option<u16> {
+        self.frames.get(frame).copied()
+    }
+
+    /// Adds a frame during which the keys in the mask were held, with bit n
+    /// set for key n.
+    pub fn push(&mut self, keys: u16) {
+        self.frames.push(keys);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{test_vm, Quirks, RAM_SIZE};
+
+    // RND V0, 0xFF; JP 0x200
+    const ROM: [u8; 4] = [0xC0, 0xFF, 0x12, 0x00];
+
+    fn create_movie() -> Movie {
+        let config = Config {
+            seed: Some(42),
+            clock_speed: 1000,
+            quirks: Quirks::SUPER_CHIP,
+            ..Config::default()
+        };
+        let mut movie = Movie::new(&mut test_vm(&ROM), &config);
+        movie.push(0);
+        movie.push(0x8001);
+        movie
+    }
+
+    #[test]
+    fn to_bytes_and_parse() {
+        let movie = create_movie();
+        let bytes = movie.to_bytes();
+
+        assert_eq!(bytes.len(), HEADER_SIZE + 4);
+        assert_eq!(Movie::parse(&bytes), Ok(movie));
+    }
+
+    #[test]
+    fn invalid_movies() {
+        let bytes = create_movie().to_bytes();
+
+        assert_eq!(Movie::parse(b"not a movie"), Err(MovieError::NotAMovie));
+
+        let mut newer = bytes.clone();
+        newer[MAGIC.len()] = VERSION + 1;
+        assert_eq!(
+            Movie::parse(&newer),
+            Err(MovieError::UnsupportedVersion(VERSION + 1))
+        );
+
+        assert_eq!(
+            Movie::parse(&bytes[..HEADER_SIZE - 1]),
+            Err(MovieError::Corrupt)
+        );
+        assert_eq!(
+            Movie::parse(&bytes[..bytes.len() - 1]),
+            Err(MovieError::Corrupt)
+        );
+        let mut quirks = bytes;
+        quirks[HEADER_SIZE - 1] = 7;
+        assert_eq!(Movie::parse(&quirks), Err(MovieError::Corrupt));
+    }
+
+    #[test]
+    fn apply() {
+        let movie = create_movie();
+        let mut config = Config::default();
+
+        movie.apply(&ROM, &mut config).unwrap();
+        assert_eq!(config.seed, Some(42));
+        assert_eq!(config.clock_speed, 1000);
+        assert_eq!(config.quirks, Quirks::SUPER_CHIP);
+    }
+
+    #[test]
+    fn replay_with_extended_memory() {
+        // JP 0x200, padded past the 4 KiB a ROM can fill without the quirk
+        let mut rom = vec![0; 0x2000];
+        rom[..2].copy_from_slice(&[0x12, 0x00]);
+        let config = Config {
+            quirks: Quirks::MODERN,
+            ..Config::default()
+        };
+        let mut vm = VM::new();
+        vm.set_quirks(config.quirks);
+        vm.load_rom(&rom).unwrap();
+        let mut movie = Movie::new(&mut vm, &config);
+        movie.push(0);
+
+        let replayed = movie.replay(&rom).unwrap();
+        assert_eq!(replayed.memory_size(), RAM_SIZE);
+    }
+
+    #[test]
+    fn wrong_rom() {
+        let movie = create_movie();
+
+        assert_eq!(
+            movie.apply(&[0x00, 0xE0], &mut Config::default()),
+            Err(MovieError::WrongRom)
+        );
+    }
+}
@@ -0,0 +1,1247 @@
+use super::instruction::Instruction;
+use super::{MEM_PROGRAM_START, RAM_SIZE, SMALL_RAM_SIZE};
+
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+
+/// Limits macro expansion, so that a macro invoking itself fails instead of
+/// expanding forever.
+const MAX_MACRO_EXPANSIONS: usize = 10_000;
+
+/// The platform an Octo program is compiled for. Statements for a later
+/// platform are rejected when compiling for an earlier one.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Target {
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl Target {
+    /// Looks up a target by name: `chip8`, `schip` or `xochip`.
+    pub fn from_name(name: &str) -> Option<Target> {
+        match name {
+            "chip8" => Some(Target::Chip8),
+            "schip" => Some(Target::SuperChip),
+            "xochip" => Some(Target::XoChip),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of bytes of memory on the platform.
+    fn memory_size(self) -> usize {
+        match self {
+            Target::Chip8 | Target::SuperChip => SMALL_RAM_SIZE,
+            Target::XoChip => RAM_SIZE,
+        }
+    }
+}
+
+/// An error at a 1-based line and column of the Octo source.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct OctoError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for OctoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for OctoError {}
+
+/// A compiled Octo program.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct OctoProgram {
+    /// The ROM to be loaded at 0x200.
+    pub rom: Vec<u8>,
+    /// Every emitted instruction and its address, in address order.
+    pub instructions: Vec<(u16, Instruction)>,
+}
+
+/// Compiles Octo source for the given target.
+///
+/// The program starts with a jump to the `main` label. Supported are the
+/// Octo statements, `if ... then`, `if ... begin ... else ... end`,
+/// `loop ... while ... again`, and the `:`, `:alias`, `:const`, `:calc`,
+/// `:macro`, `:byte`, `:org`, `:unpack` and `:breakpoint` directives. `:calc`
+/// works on integers and, like Octo, evaluates operators right to left
+/// without precedence.
+pub fn compile_octo(source: &str, target: Target) -> Result<OctoProgram, OctoError> {
+    let mut compiler = Compiler::new(source, target);
+    compiler.compile()?;
+    compiler.finish()
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error(&self, message: String) -> OctoError {
+        OctoError {
+            line: self.line,
+            column: self.column,
+            message,
+        }
+    }
+}
+
+/// How a label address is written into an instruction once it is known.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum FixupKind {
+    /// The low 12 bits of an opcode.
+    Addr,
+    /// The 16-bit word following `i := long`.
+    Long,
+    /// The operands of the two `vN := NN` instructions emitted by `:unpack`,
+    /// with the nibble to put in the high byte, or `None` for a 16-bit
+    /// address.
+    Unpack(Option<u8>),
+}
+
+struct Fixup {
+    offset: usize,
+    kind: FixupKind,
+    token: Token,
+}
+
+enum Block {
+    If { jump: usize },
+    Else { jump: usize },
+    Loop { start: u16, exits: Vec<usize> },
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// A condition compiled to the instructions that set it up, and a skip
+/// instruction for when it holds and when it does not.
+struct Condition {
+    setup: Vec<Instruction>,
+    skip_if_true: Instruction,
+    skip_if_false: Instruction,
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>,
+    target: Target,
+    rom: Vec<u8>,
+    offset: usize,
+    instructions: Vec<usize>,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, i64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: HashMap<String, Vec<Fixup>>,
+    blocks: Vec<(Block, Token)>,
+    expansions: usize,
+    last: Token,
+}
+
+impl Compiler {
+    fn new(source: &str, target: Target) -> Compiler {
+        Compiler {
+            tokens: tokenize(source),
+            target,
+            rom: Vec::new(),
+            offset: 0,
+            instructions: Vec::new(),
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: HashMap::new(),
+            blocks: Vec::new(),
+            expansions: 0,
+            last: Token {
+                text: String::new(),
+                line: 1,
+                column: 1,
+            },
+        }
+    }
+
+    fn compile(&mut self) -> Result<(), OctoError> {
+        let start = self.last.clone();
+        self.address(Instruction::JP(0), FixupKind::Addr, "main", &start)?;
+
+        while let Some(token) = self.next_token() {
+            self.statement(token)?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<OctoProgram, OctoError> {
+        if let Some((block, token)) = self.blocks.pop() {
+            let expected = match block {
+                Block::If { .. } | Block::Else { .. } => "end",
+                Block::Loop { .. } => "again",
+            };
+            return Err(token.error(format!("missing {}", expected)));
+        }
+
+        if let Some(fixups) = self.fixups.remove("main") {
+            return Err(fixups[0]
+                .token
+                .error("the program is missing a main label".to_string()));
+        }
+        if let Some(fixup) = self.fixups.values().flatten().next() {
+            return Err(fixup
+                .token
+                .error(format!("undefined name {}", fixup.token.text)));
+        }
+
+        let mut offsets = self.instructions.clone();
+        offsets.sort_unstable();
+        offsets.dedup();
+        // `:org` may have overwritten instructions with data, which are then
+        // no longer listed
+        let instructions = offsets
+            .into_iter()
+            .filter_map(|offset| {
+                let opcode = (self.rom[offset] as u16) << 8 | self.rom[offset + 1] as u16;
+                let addr = MEM_PROGRAM_START + offset as u16;
+                Instruction::decode(opcode).ok().map(|instr| (addr, instr))
+            })
+            .collect();
+
+        Ok(OctoProgram {
+            rom: self.rom,
+            instructions,
+        })
+    }
+
+    fn next_token(&mut self) -> Option<Token> {
+        let token = self.tokens.pop_front()?;
+        self.last = token.clone();
+        Some(token)
+    }
+
+    fn expect_token(&mut self) -> Result<Token, OctoError> {
+        self.next_token()
+            .ok_or_else(|| self.last.error("unexpected end of file".to_string()))
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), OctoError> {
+        let token = self.expect_token()?;
+        if token.text != text {
+            return Err(token.error(format!("expected {}, found {}", text, token.text)));
+        }
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn require(&self, target: Target, token: &Token) -> Result<(), OctoError> {
+        if self.target < target {
+            let name = match target {
+                Target::Chip8 => "CHIP-8",
+                Target::SuperChip => "SUPER-CHIP",
+                Target::XoChip => "XO-CHIP",
+            };
+            return Err(token.error(format!("{} requires the {} target", token.text, name)));
+        }
+        Ok(())
+    }
+
+    fn addr(&self) -> u16 {
+        MEM_PROGRAM_START + self.offset as u16
+    }
+
+    fn emit(&mut self, bytes: &[u8], token: &Token) -> Result<(), OctoError> {
+        let end = self.offset + bytes.len();
+        if MEM_PROGRAM_START as usize + end > self.target.memory_size() {
+            return Err(token.error("the program does not fit in memory".to_string()));
+        }
+        if self.rom.len() < end {
+            self.rom.resize(end, 0);
+        }
+        self.rom[self.offset..end].copy_from_slice(bytes);
+        self.offset = end;
+        Ok(())
+    }
+
+    fn inst(&mut self, instr: Instruction, token: &Token) -> Result<(), OctoError> {
+        self.instructions.push(self.offset);
+        self.emit(&instr.encode().to_be_bytes(), token)
+    }
+
+    /// Emits an instruction that refers to an address, which may be a label
+    /// that is defined later.
+    fn address(
+        &mut self,
+        instr: Instruction,
+        kind: FixupKind,
+        name: &str,
+        token: &Token,
+    ) -> Result<(), OctoError> {
+        let offset = self.offset;
+        match kind {
+            FixupKind::Addr => self.inst(instr, token)?,
+            FixupKind::Long => {
+                self.inst(instr, token)?;
+                self.emit(&[0, 0], token)?;
+            }
+            FixupKind::Unpack(_) => {
+                self.inst(Instruction::LD(0, 0), token)?;
+                self.inst(Instruction::LD(1, 0), token)?;
+            }
+        }
+
+        let fixup = Fixup {
+            offset,
+            kind,
+            token: token.clone(),
+        };
+        if let Some(&addr) = self.labels.get(name) {
+            self.patch(&fixup, addr)
+        } else if is_name(name) && !self.constants.contains_key(name) {
+            self.fixups.entry(name.to_string()).or_default().push(fixup);
+            Ok(())
+        } else {
+            let addr = self.value(token)?;
+            if !(0..=0xFFFF).contains(&addr) {
+                return Err(token.error(format!("{} is out of range", addr)));
+            }
+            self.patch(&fixup, addr as u16)
+        }
+    }
+
+    fn patch(&mut self, fixup: &Fixup, addr: u16) -> Result<(), OctoError> {
+        let offset = fixup.offset;
+        match fixup.kind {
+            FixupKind::Addr => {
+                if addr > 0xFFF {
+                    return Err(fixup
+                        .token
+                        .error(format!("address {:#X} is out of range", addr)));
+                }
+                self.rom[offset] = (self.rom[offset] & 0xF0) | (addr >> 8) as u8;
+                self.rom[offset + 1] = addr as u8;
+            }
+            FixupKind::Long => {
+                self.rom[offset + 2] = (addr >> 8) as u8;
+                self.rom[offset + 3] = addr as u8;
+            }
+            FixupKind::Unpack(nibble) => {
+                let high = match nibble {
+                    Some(_) if addr > 0xFFF => {
+                        return Err(fixup
+                            .token
+                            .error(format!("address {:#X} is out of range", addr)))
+                    }
+                    Some(nibble) => nibble << 4 | (addr >> 8) as u8,
+                    None => (addr >> 8) as u8,
+                };
+                self.rom[offset + 1] = high;
+                self.rom[offset + 3] = addr as u8;
+            }
+        }
+        Ok(())
+    }
+
+    fn define_label(&mut self, token: &Token) -> Result<(), OctoError> {
+        self.check_name(token)?;
+        let addr = self.addr();
+        self.labels.insert(token.text.clone(), addr);
+        for fixup in self.fixups.remove(&token.text).unwrap_or_default() {
+            self.patch(&fixup, addr)?;
+        }
+        Ok(())
+    }
+
+    fn check_name(&self, token: &Token) -> Result<(), OctoError> {
+        if !is_name(&token.text) || register(&token.text).is_some() {
+            return Err(token.error(format!("invalid name {}", token.text)));
+        }
+        if self.labels.contains_key(&token.text)
+            || self.constants.contains_key(&token.text)
+            || self.macros.contains_key(&token.text)
+        {
+            return Err(token.error(format!("{} is already defined", token.text)));
+        }
+        Ok(())
+    }
+
+    /// Parses a number or the name of a constant.
+    fn value(&self, token: &Token) -> Result<i64, OctoError> {
+        number(&token.text)
+            .or_else(|| self.constants.get(&token.text).copied())
+            .ok_or_else(|| token.error(format!("expected a number, found {}", token.text)))
+    }
+
+    fn ranged(&mut self, min: i64, max: i64) -> Result<i64, OctoError> {
+        let token = self.expect_token()?;
+        let value = self.value(&token)?;
+        if value < min || value > max {
+            return Err(token.error(format!("{} is out of range", value)));
+        }
+        Ok(value)
+    }
+
+    fn byte(&mut self) -> Result<u8, OctoError> {
+        Ok(self.ranged(-128, 255)? as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, OctoError> {
+        Ok(self.ranged(0, 15)? as u8)
+    }
+
+    fn register(&mut self) -> Result<u8, OctoError> {
+        let token = self.expect_token()?;
+        self.register_of(&token)
+            .ok_or_else(|| token.error(format!("expected a register, found {}", token.text)))
+    }
+
+    fn register_of(&self, token: &Token) -> Option<u8> {
+        register(&token.text).or_else(|| self.aliases.get(&token.text).copied())
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), OctoError> {
+        if let Some(x) = self.register_of(&token) {
+            return self.register_statement(x);
+        }
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.expect_token()?;
+                self.define_label(&name)?;
+            }
+            ":alias" => {
+                let name = self.expect_token()?;
+                if !is_name(&name.text) || register(&name.text).is_some() {
+                    return Err(name.error(format!("invalid name {}", name.text)));
+                }
+                let x = self.register()?;
+                self.aliases.insert(name.text, x);
+            }
+            ":const" => {
+                let name = self.expect_token()?;
+                self.check_name(&name)?;
+                let value_token = self.expect_token()?;
+                let value = self.value(&value_token)?;
+                self.constants.insert(name.text, value);
+            }
+            ":calc" => {
+                let name = self.expect_token()?;
+                self.check_name(&name)?;
+                let expr = self.braced()?;
+                let value = self.calc(&expr, &name)?;
+                self.constants.insert(name.text, value);
+            }
+            ":macro" => self.define_macro()?,
+            ":byte" => {
+                let value = if self.peek() == Some("{") {
+                    let expr = self.braced()?;
+                    self.calc(&expr, &token)?
+                } else {
+                    self.ranged(-128, 255)?
+                };
+                self.emit(&[value as u8], &token)?;
+            }
+            ":org" => {
+                let addr_token = self.expect_token()?;
+                let addr = self.value(&addr_token)?;
+                if addr < MEM_PROGRAM_START as i64 || addr >= self.target.memory_size() as i64 {
+                    return Err(addr_token.error(format!("{:#X} is out of range", addr)));
+                }
+                self.offset = (addr - MEM_PROGRAM_START as i64) as usize;
+            }
+            ":unpack" => {
+                let kind = if self.peek() == Some("long") {
+                    self.require(Target::XoChip, &self.last.clone())?;
+                    self.next_token();
+                    FixupKind::Unpack(None)
+                } else {
+                    FixupKind::Unpack(Some(self.nibble()?))
+                };
+                let name = self.expect_token()?;
+                self.address(Instruction::LD(0, 0), kind, &name.text, &name)?;
+            }
+            ":breakpoint" => {
+                self.expect_token()?;
+            }
+            ":call" => {
+                let name = self.expect_token()?;
+                self.address(Instruction::CALL(0), FixupKind::Addr, &name.text, &name)?;
+            }
+            "clear" => self.inst(Instruction::CLS, &token)?,
+            "return" | ";" => self.inst(Instruction::RET, &token)?,
+            "exit" => {
+                self.require(Target::SuperChip, &token)?;
+                self.inst(Instruction::EXIT, &token)?;
+            }
+            "hires" | "lores" => {
+                self.require(Target::SuperChip, &token)?;
+                let instr = if token.text == "hires" {
+                    Instruction::HIGH
+                } else {
+                    Instruction::LOW
+                };
+                self.inst(instr, &token)?;
+            }
+            "scroll-down" => {
+                self.require(Target::SuperChip, &token)?;
+                let n = self.nibble()?;
+                self.inst(Instruction::SCD(n), &token)?;
+            }
+            "scroll-up" => {
+                self.require(Target::XoChip, &token)?;
+                let n = self.nibble()?;
+                self.inst(Instruction::SCU(n), &token)?;
+            }
+            "scroll-left" => {
+                self.require(Target::SuperChip, &token)?;
+                self.inst(Instruction::SCL, &token)?;
+            }
+            "scroll-right" => {
+                self.require(Target::SuperChip, &token)?;
+                self.inst(Instruction::SCR, &token)?;
+            }
+            "jump" | "jump0" | "native" => {
+                let instr = match token.text.as_str() {
+                    "jump" => Instruction::JP(0),
+                    "jump0" => Instruction::JPV0(0),
+                    _ => Instruction::SYS(0),
+                };
+                let name = self.expect_token()?;
+                self.address(instr, FixupKind::Addr, &name.text, &name)?;
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.inst(Instruction::DRW(x, y, n), &token)?;
+            }
+            "load" | "save" => self.load_save(&token)?,
+            "loadflags" | "saveflags" => {
+                self.require(Target::SuperChip, &token)?;
+                let x = self.register()?;
+                let instr = if token.text == "loadflags" {
+                    Instruction::LD13(x)
+                } else {
+                    Instruction::LD12(x)
+                };
+                self.inst(instr, &token)?;
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.inst(Instruction::LD8(x), &token)?;
+            }
+            "plane" => {
+                self.require(Target::XoChip, &token)?;
+                let n = self.nibble()?;
+                self.inst(Instruction::PLANE(n), &token)?;
+            }
+            "audio" => {
+                self.require(Target::XoChip, &token)?;
+                self.inst(Instruction::AUDIO, &token)?;
+            }
+            "i" => self.index_statement()?,
+            "delay" | "buzzer" | "pitch" => {
+                if token.text == "pitch" {
+                    self.require(Target::XoChip, &token)?;
+                }
+                self.expect(":=")?;
+                let x = self.register()?;
+                let instr = match token.text.as_str() {
+                    "delay" => Instruction::LD5(x),
+                    "buzzer" => Instruction::LD6(x),
+                    _ => Instruction::PITCH(x),
+                };
+                self.inst(instr, &token)?;
+            }
+            "if" => self.if_statement()?,
+            "else" => match self.blocks.pop() {
+                Some((Block::If { jump }, _)) => {
+                    let end = self.offset;
+                    self.inst(Instruction::JP(0), &token)?;
+                    self.patch_here(jump, &token)?;
+                    self.blocks.push((Block::Else { jump: end }, token));
+                }
+                _ => return Err(token.error("else without if ... begin".to_string())),
+            },
+            "end" => match self.blocks.pop() {
+                Some((Block::If { jump }, _)) | Some((Block::Else { jump }, _)) => {
+                    self.patch_here(jump, &token)?
+                }
+                _ => return Err(token.error("end without if ... begin".to_string())),
+            },
+            "loop" => {
+                let start = self.addr();
+                self.blocks.push((
+                    Block::Loop {
+                        start,
+                        exits: Vec::new(),
+                    },
+                    token,
+                ));
+            }
+            "while" => {
+                let condition = self.condition()?;
+                self.setup(&condition, &token)?;
+                self.inst(condition.skip_if_true, &token)?;
+                let exit = self.offset;
+                self.inst(Instruction::JP(0), &token)?;
+                match self
+                    .blocks
+                    .iter_mut()
+                    .rev()
+                    .find_map(|(block, _)| match block {
+                        Block::Loop { exits, .. } => Some(exits),
+                        _ => None,
+                    }) {
+                    Some(exits) => exits.push(exit),
+                    None => return Err(token.error("while outside of a loop".to_string())),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some((Block::Loop { start, exits }, _)) => {
+                    self.inst(Instruction::JP(start), &token)?;
+                    for exit in exits {
+                        self.patch_here(exit, &token)?;
+                    }
+                }
+                _ => return Err(token.error("again without loop".to_string())),
+            },
+            "{" | "}" | "then" | "begin" => {
+                return Err(token.error(format!("unexpected {}", token.text)))
+            }
+            _ if self.macros.contains_key(&token.text) => self.expand_macro(&token)?,
+            _ if number(&token.text).is_some() || self.constants.contains_key(&token.text) => {
+                let value = self.rhs_byte(&token)?;
+                self.emit(&[value], &token)?;
+            }
+            _ if is_name(&token.text) => {
+                self.address(Instruction::CALL(0), FixupKind::Addr, &token.text, &token)?
+            }
+            _ => return Err(token.error(format!("unexpected {}", token.text))),
+        }
+
+        Ok(())
+    }
+
+    /// Points the jump at `offset` to the current address.
+    fn patch_here(&mut self, offset: usize, token: &Token) -> Result<(), OctoError> {
+        let fixup = Fixup {
+            offset,
+            kind: FixupKind::Addr,
+            token: token.clone(),
+        };
+        let addr = self.addr();
+        self.patch(&fixup, addr)
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), OctoError> {
+        let op = self.expect_token()?;
+        let rhs = self.expect_token()?;
+
+        if let Some(y) = self.register_of(&rhs) {
+            let instr = match op.text.as_str() {
+                ":=" => Instruction::LD2(x, y),
+                "+=" => Instruction::ADD2(x, y),
+                "-=" => Instruction::SUB(x, y),
+                "=-" => Instruction::SUBN(x, y),
+                "|=" => Instruction::OR(x, y),
+                "&=" => Instruction::AND(x, y),
+                "^=" => Instruction::XOR(x, y),
+                ">>=" => Instruction::SHR(x, y),
+                "<<=" => Instruction::SHL(x, y),
+                _ => return Err(op.error(format!("unknown operator {}", op.text))),
+            };
+            return self.inst(instr, &op);
+        }
+
+        let instr = match (op.text.as_str(), rhs.text.as_str()) {
+            (":=", "random") => Instruction::RND(x, self.byte()?),
+            (":=", "delay") => Instruction::LD3(x),
+            (":=", "key") => Instruction::LD4(x),
+            (":=", _) => Instruction::LD(x, self.rhs_byte(&rhs)?),
+            ("+=", _) => Instruction::ADD(x, self.rhs_byte(&rhs)?),
+            ("-=", _) => Instruction::ADD(x, self.rhs_byte(&rhs)?.wrapping_neg()),
+            _ => return Err(op.error(format!("unknown operator {}", op.text))),
+        };
+        self.inst(instr, &op)
+    }
+
+    fn rhs_byte(&self, token: &Token) -> Result<u8, OctoError> {
+        let value = self.value(token)?;
+        if !(-128..=255).contains(&value) {
+            return Err(token.error(format!("{} is out of range", value)));
+        }
+        Ok(value as u8)
+    }
+
+    fn index_statement(&mut self) -> Result<(), OctoError> {
+        let op = self.expect_token()?;
+        match op.text.as_str() {
+            "+=" => {
+                let x = self.register()?;
+                self.inst(Instruction::ADD3(x), &op)
+            }
+            ":=" => {
+                let rhs = self.expect_token()?;
+                match rhs.text.as_str() {
+                    "hex" => {
+                        let x = self.register()?;
+                        self.inst(Instruction::LD7(x), &rhs)
+                    }
+                    "bighex" => {
+                        self.require(Target::SuperChip, &rhs)?;
+                        let x = self.register()?;
+                        self.inst(Instruction::LD11(x), &rhs)
+                    }
+                    "long" => {
+                        self.require(Target::XoChip, &rhs)?;
+                        let name = self.expect_token()?;
+                        self.address(Instruction::LDIL, FixupKind::Long, &name.text, &name)
+                    }
+                    _ => self.address(Instruction::LDI(0), FixupKind::Addr, &rhs.text, &rhs),
+                }
+            }
+            _ => Err(op.error(format!("unknown operator {}", op.text))),
+        }
+    }
+
+    fn load_save(&mut self, token: &Token) -> Result<(), OctoError> {
+        let x = self.register()?;
+        let instr = if self.peek() == Some("-") {
+            self.require(Target::XoChip, token)?;
+            self.next_token();
+            let y = self.register()?;
+            if token.text == "load" {
+                Instruction::LOAD(x, y)
+            } else {
+                Instruction::SAVE(x, y)
+            }
+        } else if token.text == "load" {
+            Instruction::LD10(x)
+        } else {
+            Instruction::LD9(x)
+        };
+        self.inst(instr, token)
+    }
+
+    fn if_statement(&mut self) -> Result<(), OctoError> {
+        let token = self.last.clone();
+        let condition = self.condition()?;
+        self.setup(&condition, &token)?;
+
+        let keyword = self.expect_token()?;
+        match keyword.text.as_str() {
+            "then" => self.inst(condition.skip_if_false, &keyword),
+            "begin" => {
+                self.inst(condition.skip_if_true, &keyword)?;
+                let jump = self.offset;
+                self.inst(Instruction::JP(0), &keyword)?;
+                self.blocks.push((Block::If { jump }, token));
+                Ok(())
+            }
+            _ => Err(keyword.error(format!("expected then or begin, found {}", keyword.text))),
+        }
+    }
+
+    fn setup(&mut self, condition: &Condition, token: &Token) -> Result<(), OctoError> {
+        for &instr in &condition.setup {
+            self.inst(instr, token)?;
+        }
+        Ok(())
+    }
+
+    /// Parses `vX key`, `vX -key` or `vX <op> <vY or byte>`.
+    fn condition(&mut self) -> Result<Condition, OctoError> {
+        let x = self.register()?;
+        let op = self.expect_token()?;
+
+        let simple = |skip_if_true, skip_if_false| Condition {
+            setup: Vec::new(),
+            skip_if_true,
+            skip_if_false,
+        };
+
+        match op.text.as_str() {
+            "key" => return Ok(simple(Instruction::SKP(x), Instruction::SKNP(x))),
+            "-key" => return Ok(simple(Instruction::SKNP(x), Instruction::SKP(x))),
+            _ => {}
+        }
+
+        let rhs = self.expect_token()?;
+        let y = self.register_of(&rhs);
+        let byte = match y {
+            Some(_) => 0,
+            None => self.rhs_byte(&rhs)?,
+        };
+
+        let condition = match (op.text.as_str(), y) {
+            ("==", Some(y)) => simple(Instruction::SE2(x, y), Instruction::SNE2(x, y)),
+            ("!=", Some(y)) => simple(Instruction::SNE2(x, y), Instruction::SE2(x, y)),
+            ("==", None) => simple(Instruction::SE(x, byte), Instruction::SNE(x, byte)),
+            ("!=", None) => simple(Instruction::SNE(x, byte), Instruction::SE(x, byte)),
+            ("<", _) | (">", _) | ("<=", _) | (">=", _) => {
+                // VF is set to whether a >= b, with a and b chosen so that
+                // the condition holds when VF equals `expected`
+                let (lhs_first, expected) = match op.text.as_str() {
+                    ">=" => (true, 1),
+                    "<" => (true, 0),
+                    "<=" => (false, 1),
+                    _ => (false, 0),
+                };
+                let setup = match (y, lhs_first) {
+                    (Some(y), true) => vec![Instruction::LD2(0xF, x), Instruction::SUB(0xF, y)],
+                    (Some(y), false) => vec![Instruction::LD2(0xF, y), Instruction::SUB(0xF, x)],
+                    (None, true) => vec![Instruction::LD(0xF, byte), Instruction::SUBN(0xF, x)],
+                    (None, false) => vec![Instruction::LD(0xF, byte), Instruction::SUB(0xF, x)],
+                };
+                Condition {
+                    setup,
+                    skip_if_true: Instruction::SE(0xF, expected),
+                    skip_if_false: Instruction::SNE(0xF, expected),
+                }
+            }
+            _ => return Err(op.error(format!("unknown comparison {}", op.text))),
+        };
+
+        Ok(condition)
+    }
+
+    /// Reads the tokens between `{` and the matching `}`.
+    fn braced(&mut self) -> Result<Vec<Token>, OctoError> {
+        self.expect("{")?;
+        let mut tokens = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.expect_token()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(tokens);
+                    }
+                }
+                _ => {}
+            }
+            tokens.push(token);
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), OctoError> {
+        let name = self.expect_token()?;
+        self.check_name(&name)?;
+
+        let mut params = Vec::new();
+        while self.peek().is_some_and(|text| text != "{") {
+            params.push(self.expect_token()?.text);
+        }
+        let body = self.braced()?;
+
+        self.macros.insert(name.text, Macro { params, body });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, token: &Token) -> Result<(), OctoError> {
+        self.expansions += 1;
+        if self.expansions > MAX_MACRO_EXPANSIONS {
+            return Err(token.error(format!("{} expands forever", token.text)));
+        }
+
+        let params = self.macros[&token.text].params.clone();
+        let mut args = HashMap::new();
+        for param in params {
+            let arg = self.expect_token()?;
+            args.insert(param, arg.text);
+        }
+
+        let body = &self.macros[&token.text].body;
+        for body_token in body.iter().rev() {
+            let mut body_token = body_token.clone();
+            if let Some(arg) = args.get(&body_token.text) {
+                body_token.text = arg.clone();
+            }
+            self.tokens.push_front(body_token);
+        }
+        Ok(())
+    }
+
+    /// Evaluates a `:calc` expression.
+    fn calc(&self, tokens: &[Token], name: &Token) -> Result<i64, OctoError> {
+        let mut pos = 0;
+        let value = self.calc_expr(tokens, &mut pos, name)?;
+        match tokens.get(pos) {
+            Some(token) => Err(token.error(format!("unexpected {}", token.text))),
+            None => Ok(value),
+        }
+    }
+
+    fn calc_expr(&self, tokens: &[Token], pos: &mut usize, name: &Token) -> Result<i64, OctoError> {
+        let lhs = self.calc_term(tokens, pos, name)?;
+        let op = match tokens.get(*pos) {
+            Some(op) if op.text != ")" => op,
+            _ => return Ok(lhs),
+        };
+        *pos += 1;
+        let rhs = self.calc_expr(tokens, pos, name)?;
+
+        let value = match op.text.as_str() {
+            "+" => lhs.wrapping_add(rhs),
+            "-" => lhs.wrapping_sub(rhs),
+            "*" => lhs.wrapping_mul(rhs),
+            "/" | "%" if rhs == 0 => return Err(op.error("division by zero".to_string())),
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "&" => lhs & rhs,
+            "|" => lhs | rhs,
+            "^" => lhs ^ rhs,
+            "<<" => lhs.wrapping_shl(rhs as u32),
+            ">>" => lhs.wrapping_shr(rhs as u32),
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            _ => return Err(op.error(format!("unknown operator {}", op.text))),
+        };
+        Ok(value)
+    }
+
+    fn calc_term(&self, tokens: &[Token], pos: &mut usize, name: &Token) -> Result<i64, OctoError> {
+        let token = match tokens.get(*pos) {
+            Some(token) => token,
+            None => return Err(name.error("incomplete expression".to_string())),
+        };
+        *pos += 1;
+
+        match token.text.as_str() {
+            "(" => {
+                let value = self.calc_expr(tokens, pos, name)?;
+                match tokens.get(*pos) {
+                    Some(close) if close.text == ")" => {
+                        *pos += 1;
+                        Ok(value)
+                    }
+                    _ => Err(token.error("missing )".to_string())),
+                }
+            }
+            "-" => Ok(self.calc_term(tokens, pos, name)?.wrapping_neg()),
+            "~" => Ok(!self.calc_term(tokens, pos, name)?),
+            "!" => Ok((self.calc_term(tokens, pos, name)? == 0) as i64),
+            "HERE" => Ok(self.addr() as i64),
+            text => match self.labels.get(text) {
+                Some(&addr) => Ok(addr as i64),
+                None => self.value(token),
+            },
+        }
+    }
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let mut start = None;
+        for (j, c) in line
+            .char_indices()
+            .chain(std::iter::once((line.len(), ' ')))
+        {
+            if c.is_whitespace() {
+                if let Some(s) = start.take() {
+                    tokens.push_back(Token {
+                        text: line[s..j].to_string(),
+                        line: i + 1,
+                        column: s + 1,
+                    });
+                }
+            } else if start.is_none() {
+                if c == '#' {
+                    break;
+                }
+                start = Some(j);
+            }
+        }
+    }
+
+    tokens
+}
+
+fn is_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn register(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+fn number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn compile(source: &str) -> Vec<u8> {
+        compile_octo(source, Target::XoChip).unwrap().rom
+    }
+
+    fn error(source: &str, target: Target) -> (usize, usize, String) {
+        let err = compile_octo(source, target).unwrap_err();
+        (err.line, err.column, err.message)
+    }
+
+    #[test]
+    fn main_jump() {
+        assert_eq!(compile(": main\n  clear\n"), [0x12, 0x02, 0x00, 0xE0]);
+    }
+
+    #[test]
+    fn statements() {
+        let rom = compile(
+            ": main
+               v1 := 0x0A   # comment
+               v2 += v1
+               v3 -= 1
+               i := hex v1
+               sprite v0 v1 5
+               delay := v2
+               if v1 == 3 then v2 := key
+               return",
+        );
+
+        assert_eq!(
+            rom,
+            [
+                0x12, 0x02, 0x61, 0x0A, 0x82, 0x14, 0x73, 0xFF, 0xF1, 0x29, 0xD0, 0x15, 0xF2, 0x15,
+                0x41, 0x03, 0xF2, 0x0A, 0x00, 0xEE,
+            ]
+        );
+    }
+
+    #[test]
+    fn labels_and_data() {
+        let program = compile_octo(
+            ": main
+               i := sprite
+               sub
+               jump main
+             : sub ;
+             : sprite 0xF0 0x90 -1",
+            Target::Chip8,
+        )
+        .unwrap();
+
+        assert_eq!(
+            program.rom,
+            [0x12, 0x02, 0xA2, 0x0A, 0x22, 0x08, 0x12, 0x02, 0x00, 0xEE, 0xF0, 0x90, 0xFF]
+        );
+        assert_eq!(program.instructions[1], (0x202, Instruction::LDI(0x20A)));
+        assert_eq!(program.instructions.len(), 5);
+    }
+
+    #[test]
+    fn instruction_overwritten_by_data() {
+        let program =
+            compile_octo(": main\n clear\n:org 0x202\n0xFF 0xFF\n", Target::XoChip).unwrap();
+
+        assert_eq!(program.rom, [0x12, 0x02, 0xFF, 0xFF]);
+        assert_eq!(program.instructions, [(0x200, Instruction::JP(0x202))]);
+    }
+
+    #[test]
+    fn comparisons_run_on_vm() {
+        let program = compile_octo(
+            ": main
+               v0 := 5
+               if v0 >= 5 then v1 := 1
+               if v0 > 5 then v2 := 1
+               if v0 <= 5 then v3 := 1
+               if v0 < 5 then v4 := 1
+               if v0 > 4 then v5 := 1
+               if v0 < 6 then v6 := 1
+               v7 := 2
+               v8 := 3
+               v7 -= v8
+               loop again",
+            Target::Chip8,
+        )
+        .unwrap();
+        let mut vm = crate::test_vm(&program.rom);
+
+        vm.run_for(100).unwrap();
+
+        assert_eq!(vm.gen_registers[1..=7], [1, 0, 1, 0, 1, 1, 255]);
+    }
+
+    #[test]
+    fn if_begin_else() {
+        let rom = compile(
+            ": main
+               if v0 != v1 begin
+                 v2 := 1
+               else
+                 v2 := 2
+               end",
+        );
+
+        assert_eq!(
+            rom,
+            [0x12, 0x02, 0x90, 0x10, 0x12, 0x0A, 0x62, 0x01, 0x12, 0x0C, 0x62, 0x02]
+        );
+    }
+
+    #[test]
+    fn loop_while() {
+        let rom = compile(
+            ": main
+               loop
+                 while v0 -key
+                 v1 += 1
+               again",
+        );
+
+        assert_eq!(
+            rom,
+            [0x12, 0x02, 0xE0, 0xA1, 0x12, 0x0A, 0x71, 0x01, 0x12, 0x02]
+        );
+    }
+
+    #[test]
+    fn comparisons() {
+        // VF := V0 - 5 without borrow, so V0 >= 5 when VF is 1
+        assert_eq!(
+            compile(": main if v0 >= 5 then clear"),
+            [0x12, 0x02, 0x6F, 0x05, 0x8F, 0x07, 0x4F, 0x01, 0x00, 0xE0]
+        );
+        assert_eq!(
+            compile(": main if v0 > v1 then clear"),
+            [0x12, 0x02, 0x8F, 0x10, 0x8F, 0x05, 0x4F, 0x00, 0x00, 0xE0]
+        );
+    }
+
+    #[test]
+    fn alias_const_calc() {
+        let rom = compile(
+            ":alias x v3
+             :const SIZE 4
+             :calc AREA { SIZE * ( SIZE + 1 ) }
+             :calc RTL { 2 * 3 + 1 }
+             : main
+               x := AREA
+               x := RTL",
+        );
+
+        assert_eq!(rom, [0x12, 0x02, 0x63, 20, 0x63, 8]);
+    }
+
+    #[test]
+    fn macros() {
+        let rom = compile(
+            ":macro twice reg { reg += 1 reg += 1 }
+             : main
+               twice v4",
+        );
+
+        assert_eq!(rom, [0x12, 0x02, 0x74, 0x01, 0x74, 0x01]);
+    }
+
+    #[test]
+    fn xochip() {
+        let rom = compile(
+            ": main
+               i := long data
+               save v1 - v3
+               plane 3
+               audio
+               pitch := v2
+             : data",
+        );
+
+        assert_eq!(
+            rom,
+            [0x12, 0x02, 0xF0, 0x00, 0x02, 0x0E, 0x51, 0x32, 0xF3, 0x01, 0xF0, 0x02, 0xF2, 0x3A,]
+        );
+    }
+
+    #[test]
+    fn unpack() {
+        assert_eq!(
+            compile(": main :unpack 0xA data : data"),
+            [0x12, 0x02, 0x60, 0xA2, 0x61, 0x06]
+        );
+    }
+
+    #[test]
+    fn target() {
+        assert_eq!(
+            error(": main plane 1", Target::SuperChip),
+            (1, 8, "plane requires the XO-CHIP target".to_string())
+        );
+        assert_eq!(
+            error(": main hires", Target::Chip8),
+            (1, 8, "hires requires the SUPER-CHIP target".to_string())
+        );
+        // only XO-CHIP has more than 4 KiB of memory
+        assert_eq!(
+            error(": main :org 0x1000 0", Target::SuperChip),
+            (1, 13, "0x1000 is out of range".to_string())
+        );
+        assert_eq!(compile(": main :org 0x1000 0").len(), 0xE01);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            error("clear", Target::Chip8).2,
+            "the program is missing a main label"
+        );
+        assert_eq!(
+            error(": main\n  jump nowhere", Target::Chip8),
+            (2, 8, "undefined name nowhere".to_string())
+        );
+        assert_eq!(
+            error(": main\n  v0 := 256", Target::Chip8),
+            (2, 9, "256 is out of range".to_string())
+        );
+        assert_eq!(
+            error(": main\nloop", Target::Chip8),
+            (2, 1, "missing again".to_string())
+        );
+        assert_eq!(
+            error(":macro m { m }\n: main m", Target::Chip8).2,
+            "m expands forever"
+        );
+    }
+}
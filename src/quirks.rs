@@ -0,0 +1,114 @@
+/// Controls what happens to the parts of a sprite that cross the edge of the
+/// display. The start position of a sprite is always wrapped.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EdgeMode {
+    Clip,
+    Wrap,
+}
+
+/// How `LD9` and `LD10` (FX55 and FX65) leave I after storing or loading
+/// registers V0 through VX.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum IndexIncrement {
+    /// I is left pointing after the last register, I = I + X + 1.
+    XPlusOne,
+    /// I is left pointing at the last register, I = I + X.
+    X,
+    Unchanged,
+}
+
+/// When `LD4` (FX0A) stops waiting for a key.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum KeyWait {
+    /// As soon as a key is pressed.
+    Press,
+    /// Once the pressed key has been released again.
+    Release,
+}
+
+/// Behaviors that differ between CHIP-8 interpreters, where ROMs written for
+/// one platform may not run correctly on another.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Quirks {
+    /// `SHR` and `SHL` shift VX in place instead of storing VY shifted in VX.
+    pub shift_in_place: bool,
+    pub index_increment: IndexIncrement,
+    /// `JPV0` jumps to XNN + VX instead of NNN + V0.
+    pub jump_with_vx: bool,
+    /// `OR`, `AND` and `XOR` reset VF to zero.
+    pub logic_resets_vf: bool,
+    /// `DRW` waits for the next 60 Hz tick, so at most one sprite is drawn
+    /// per frame.
+    pub display_wait: bool,
+    pub sprite_edge: EdgeMode,
+    pub key_wait: KeyWait,
+    /// The program has the 64 KiB of memory of XO-CHIP instead of 4 KiB.
+    pub extended_memory: bool,
+}
+
+impl Quirks {
+    /// The original interpreter on the RCA COSMAC VIP.
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift_in_place: false,
+        index_increment: IndexIncrement::XPlusOne,
+        jump_with_vx: false,
+        logic_resets_vf: true,
+        display_wait: true,
+        sprite_edge: EdgeMode::Clip,
+        key_wait: KeyWait::Release,
+        extended_memory: false,
+    };
+
+    /// CHIP-48 on the HP 48 calculators.
+    pub const CHIP_48: Quirks = Quirks {
+        shift_in_place: true,
+        index_increment: IndexIncrement::X,
+        jump_with_vx: true,
+        logic_resets_vf: false,
+        display_wait: false,
+        sprite_edge: EdgeMode::Clip,
+        key_wait: KeyWait::Press,
+        extended_memory: false,
+    };
+
+    /// SUPER-CHIP 1.1 on the HP 48 calculators.
+    pub const SUPER_CHIP: Quirks = Quirks {
+        shift_in_place: true,
+        index_increment: IndexIncrement::Unchanged,
+        jump_with_vx: true,
+        logic_resets_vf: false,
+        display_wait: false,
+        sprite_edge: EdgeMode::Clip,
+        key_wait: KeyWait::Press,
+        extended_memory: false,
+    };
+
+    /// Contemporary interpreters such as Octo, which most new ROMs target.
+    pub const MODERN: Quirks = Quirks {
+        shift_in_place: false,
+        index_increment: IndexIncrement::XPlusOne,
+        jump_with_vx: false,
+        logic_resets_vf: false,
+        display_wait: false,
+        sprite_edge: EdgeMode::Wrap,
+        key_wait: KeyWait::Release,
+        extended_memory: true,
+    };
+
+    /// Looks up a preset by name: `vip`, `chip48`, `schip` or `modern`.
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name {
+            "vip" => Some(Quirks::COSMAC_VIP),
+            "chip48" => Some(Quirks::CHIP_48),
+            "schip" => Some(Quirks::SUPER_CHIP),
+            "modern" => Some(Quirks::MODERN),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::COSMAC_VIP
+    }
+}
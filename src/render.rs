@@ -1,36 +1,84 @@
+use super::display::{LORES_HEIGHT, LORES_WIDTH};
+use super::frontend::{Command, Frontend};
+use super::keymap::Keymap;
 use super::keys;
+use super::Display;
 
 use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
 use std::thread;
 
+use ggez::audio::{self, SoundSource};
 use ggez::conf;
 use ggez::event::{self, EventHandler, KeyCode, KeyMods};
-use ggez::graphics::{self, Color, DrawMode, DrawParam, Mesh, Rect};
+use ggez::graphics::{self, Color, DrawMode, DrawParam, Mesh, Rect, WHITE};
 use ggez::timer;
 use ggez::{Context, ContextBuilder, GameResult};
-use nalgebra;
 
-type Display = [u64; 32];
+const TONE_SAMPLE_RATE: u32 = 22050;
+const TONE_PERIOD: usize = 50; // samples, giving a 441 Hz tone
+const TONE_PERIODS: usize = 44;
+const PATTERN_REPEATS: usize = 32;
+
+/// Colors for the background, plane 0, plane 1 and both planes.
+const PALETTE: [Color; 4] = [
+    Color {
+        r: 1.0,
+        g: 0.0,
+        b: 0.0,
+        a: 1.0,
+    },
+    Color {
+        r: 0.0,
+        g: 1.0,
+        b: 0.0,
+        a: 1.0,
+    },
+    Color {
+        r: 0.0,
+        g: 0.0,
+        b: 1.0,
+        a: 1.0,
+    },
+    Color {
+        r: 1.0,
+        g: 1.0,
+        b: 0.0,
+        a: 1.0,
+    },
+];
+
+enum Message {
+    Frame(Box<Display>),
+    PlayTone,
+    StopTone,
+    SetAudio([u8; 16], u8),
+}
 
 pub struct Renderer {
-    handle: thread::JoinHandle<()>,
-    sender: mpsc::Sender<Display>,
+    sender: mpsc::Sender<Message>,
+    commands: mpsc::Receiver<Command>,
+    keys: keys::SharedKeypad,
+    closed: bool,
 }
 
 impl Renderer {
-    pub fn new(keys: keys::Keyboard) -> Self {
+    pub fn new(scale: f32, keymap: Keymap) -> Self {
+        let keys = keys::SharedKeypad::new();
+        let window_keys = keys.clone();
         let (tx, rx) = mpsc::channel();
+        let (commands_tx, commands_rx) = mpsc::channel();
 
-        let handle = thread::spawn(|| {
-            let c = conf::Conf::new();
+        thread::spawn(move || {
+            let window_mode = conf::WindowMode::default()
+                .dimensions(LORES_WIDTH as f32 * scale, LORES_HEIGHT as f32 * scale);
+            let c = conf::Conf::new().window_mode(window_mode);
             let (ref mut ctx, ref mut event_loop) =
                 &mut ContextBuilder::new("chip8", "Andreas Arvidsson")
                     .conf(c)
                     .build()
                     .expect("Unable to create ggex context!");
 
-            let mut game = MyGame::new(ctx, rx, keys);
+            let mut game = MyGame::new(ctx, rx, commands_tx, window_keys, keymap, scale);
 
             match event::run(ctx, event_loop, &mut game) {
                 Ok(_) => println!("Exited cleanly."),
@@ -38,35 +86,96 @@ impl Renderer {
             }
         });
 
-        Renderer { handle, sender: tx }
+        Renderer {
+            sender: tx,
+            commands: commands_rx,
+            keys,
+            closed: false,
+        }
+    }
+
+    fn send(&mut self, message: Message) {
+        // the receiver is dropped when the window is closed
+        if self.sender.send(message).is_err() {
+            self.closed = true;
+        }
+    }
+}
+
+impl Frontend for Renderer {
+    fn present(&mut self, display: &Display) {
+        self.send(Message::Frame(Box::new(*display)));
+    }
+
+    fn poll_input(&mut self, keypad: &mut keys::Keypad) -> bool {
+        // key events are written to the shared keypad by the window thread
+        *keypad = self.keys.keypad();
+        !self.closed
+    }
+
+    fn poll_commands(&mut self) -> Vec<Command> {
+        self.commands.try_iter().collect()
+    }
+
+    fn play_tone(&mut self) {
+        self.send(Message::PlayTone);
     }
 
-    pub fn render(&self, display: Display) {
-        self.sender.send(display).unwrap();
+    fn stop_tone(&mut self) {
+        self.send(Message::StopTone);
+    }
+
+    fn set_audio(&mut self, pattern: &[u8; 16], pitch: u8) {
+        self.send(Message::SetAudio(*pattern, pitch));
     }
 }
 
 struct MyGame {
     dt: std::time::Duration,
     pixel_mesh: Mesh,
+    hires_pixel_mesh: Mesh,
+    scale: f32,
     display: Display,
-    receiver: mpsc::Receiver<Display>,
-    keys: keys::Keyboard,
+    tone: Option<audio::Source>,
+    playing: bool,
+    receiver: mpsc::Receiver<Message>,
+    commands: mpsc::Sender<Command>,
+    keys: keys::SharedKeypad,
+    keymap: Keymap,
 }
 
 impl MyGame {
-    fn new(ctx: &mut Context, receiver: mpsc::Receiver<Display>, keys: keys::Keyboard) -> MyGame {
+    fn new(
+        ctx: &mut Context,
+        receiver: mpsc::Receiver<Message>,
+        commands: mpsc::Sender<Command>,
+        keys: keys::SharedKeypad,
+        keymap: Keymap,
+        scale: f32,
+    ) -> MyGame {
+        let mut rect = Rect::one();
+        rect.scale(scale, scale);
+        let mesh = Mesh::new_rectangle(ctx, DrawMode::fill(), rect, WHITE).unwrap();
+
+        // hires pixels are drawn at half size in the same window
         let mut rect = Rect::one();
-        rect.scale(10.0, 10.0);
-        let mesh = Mesh::new_rectangle(ctx, DrawMode::fill(), rect, Color::new(0.0, 1.0, 0.0, 1.0))
-            .unwrap();
+        rect.scale(scale / 2.0, scale / 2.0);
+        let hires_mesh = Mesh::new_rectangle(ctx, DrawMode::fill(), rect, WHITE).unwrap();
+
+        let tone = tone_source(ctx, &tone_wav());
 
         MyGame {
             dt: std::time::Duration::new(0, 0),
             pixel_mesh: mesh,
-            display: [0; 32],
+            hires_pixel_mesh: hires_mesh,
+            scale,
+            display: Display::new(),
+            tone,
+            playing: false,
             receiver,
+            commands,
             keys,
+            keymap,
         }
     }
 }
@@ -74,8 +183,33 @@ impl MyGame {
 impl EventHandler for MyGame {
     fn update(&mut self, ctx: &mut Context) -> GameResult<()> {
         self.dt = timer::delta(ctx);
-        if let Ok(display) = self.receiver.try_recv() {
-            self.display = display;
+        while let Ok(message) = self.receiver.try_recv() {
+            match message {
+                Message::Frame(display) => self.display = *display,
+                Message::PlayTone => {
+                    if let Some(tone) = &mut self.tone {
+                        tone.play()?;
+                    }
+                    self.playing = true;
+                }
+                Message::StopTone => {
+                    if let Some(tone) = &mut self.tone {
+                        tone.stop();
+                    }
+                    self.playing = false;
+                }
+                Message::SetAudio(pattern, pitch) => {
+                    if let Some(tone) = &mut self.tone {
+                        tone.stop();
+                    }
+                    self.tone = tone_source(ctx, &pattern_wav(&pattern, pitch));
+                    if self.playing {
+                        if let Some(tone) = &mut self.tone {
+                            tone.play()?;
+                        }
+                    }
+                }
+            }
         }
         Ok(())
     }
@@ -83,17 +217,24 @@ impl EventHandler for MyGame {
     fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
         //println!("delta: {}", self.dt.subsec_nanos());
 
-        graphics::clear(ctx, Color::new(1.0, 0.0, 0.0, 1.0));
+        graphics::clear(ctx, PALETTE[0]);
 
-        for (i, row) in self.display.iter().enumerate() {
-            if *row != 0 {
-                for x in 0..64 {
-                    let mask = (1 as u64) << (63 - x);
-                    if mask & row != 0 {
-                        let my_dest = nalgebra::Point2::new((10 * x) as f32, (i * 10) as f32);
-                        graphics::draw(ctx, &self.pixel_mesh, DrawParam::default().dest(my_dest))
-                            .unwrap();
-                    }
+        let (mesh, pixel_size) = if self.display.is_hires() {
+            (&self.hires_pixel_mesh, self.scale / 2.0)
+        } else {
+            (&self.pixel_mesh, self.scale)
+        };
+
+        for i in 0..self.display.height() {
+            for x in 0..self.display.width() {
+                let color = self.display.color(x, i);
+                if color != 0 {
+                    let my_dest =
+                        nalgebra::Point2::new(x as f32 * pixel_size, i as f32 * pixel_size);
+                    let param = DrawParam::default()
+                        .dest(my_dest)
+                        .color(PALETTE[color as usize]);
+                    graphics::draw(ctx, mesh, param).unwrap();
                 }
             }
         }
@@ -103,16 +244,103 @@ impl EventHandler for MyGame {
 
     fn key_down_event(
         &mut self,
-        _ctx: &mut Context,
+        ctx: &mut Context,
         keycode: KeyCode,
-        keymod: KeyMods,
+        _keymod: KeyMods,
         repeat: bool,
     ) {
-        println!(
-            "Key pressed: {:?}, modifier {:?}, repeat: {}",
-            keycode, keymod, repeat
-        );
-        // TODO: send correct key
-        self.keys.set_pressed(keys::Key::Key3);
+        if keycode == KeyCode::Escape {
+            ggez::quit(ctx);
+        }
+
+        if repeat {
+            return;
+        }
+
+        let command = match keycode {
+            KeyCode::F5 => Some(Command::QuickSave),
+            KeyCode::F9 => Some(Command::QuickLoad),
+            KeyCode::Back => Some(Command::StartRewind),
+            _ => None,
+        };
+        if let Some(command) = command {
+            // the VM thread is gone once it has stopped running
+            let _ = self.commands.send(command);
+            return;
+        }
+
+        if let Some(key) = self.keymap.get(&format!("{:?}", keycode)) {
+            self.keys.press(key);
+        }
+    }
+
+    fn key_up_event(&mut self, _ctx: &mut Context, keycode: KeyCode, _keymod: KeyMods) {
+        if keycode == KeyCode::Back {
+            let _ = self.commands.send(Command::StopRewind);
+            return;
+        }
+
+        if let Some(key) = self.keymap.get(&format!("{:?}", keycode)) {
+            self.keys.release(key);
+        }
+    }
+}
+
+fn tone_source(ctx: &mut Context, wav: &[u8]) -> Option<audio::Source> {
+    // a missing audio device should not prevent the game from running
+    audio::Source::from_data(ctx, audio::SoundData::from_bytes(wav))
+        .map(|mut source| {
+            source.set_repeat(true);
+            source
+        })
+        .ok()
+}
+
+/// Builds a WAV file containing a square wave, short enough to be looped
+/// while the sound timer is active.
+fn tone_wav() -> Vec<u8> {
+    let samples = (0..TONE_PERIOD * TONE_PERIODS)
+        .map(|i| i % TONE_PERIOD < TONE_PERIOD / 2)
+        .collect::<Vec<_>>();
+    wav(TONE_SAMPLE_RATE, &samples)
+}
+
+/// Builds a WAV file playing an XO-CHIP audio pattern, most significant bit
+/// first.
+fn pattern_wav(pattern: &[u8; 16], pitch: u8) -> Vec<u8> {
+    let sample_rate = 4000.0 * 2f64.powf((pitch as f64 - 64.0) / 48.0);
+    // repeated so that the looped source is not too short
+    let samples = pattern
+        .iter()
+        .flat_map(|&byte| (0..8).rev().map(move |bit| byte & (1 << bit) != 0))
+        .cycle()
+        .take(pattern.len() * 8 * PATTERN_REPEATS)
+        .collect::<Vec<_>>();
+    wav(sample_rate as u32, &samples)
+}
+
+/// Builds an 8-bit mono WAV file where each sample is either high or low.
+fn wav(sample_rate: u32, samples: &[bool]) -> Vec<u8> {
+    let len = samples.len();
+    let mut wav = Vec::with_capacity(44 + len);
+
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + len as u32).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes()); // byte rate
+    wav.extend_from_slice(&1u16.to_le_bytes()); // block align
+    wav.extend_from_slice(&8u16.to_le_bytes()); // bits per sample
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&(len as u32).to_le_bytes());
+
+    for &high in samples {
+        wav.push(if high { 0xA0 } else { 0x60 });
     }
+
+    wav
 }
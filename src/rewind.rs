@@ -0,0 +1,248 @@
+use super::state;
+use super::VM;
+
+use std::collections::VecDeque;
+use std::mem;
+
+/// Number of snapshots stored as deltas after each full one.
+const KEYFRAME_INTERVAL: usize = 60;
+/// Bytes compared at once when looking for changes.
+const SKIP_CHUNK: usize = 64;
+/// Bytes used by a run in a delta besides the changed bytes themselves.
+const RUN_OVERHEAD: usize = mem::size_of::<(usize, Vec<u8>)>();
+
+/// A bounded history of VM states, for running backwards.
+///
+/// Every `KEYFRAME_INTERVAL` snapshots a full save state is kept as a
+/// keyframe, and the snapshots in between only keep the bytes that differ
+/// from it. Once the history grows past its capacity, the oldest keyframe is
+/// dropped together with the snapshots that depend on it, though the newest
+/// keyframe is always kept.
+#[derive(Default)]
+pub struct Rewind {
+    capacity: usize,
+    used: usize,
+    groups: VecDeque<Group>,
+}
+
+struct Group {
+    keyframe: Vec<u8>,
+    deltas: Vec<Delta>,
+}
+
+/// The runs of bytes where a snapshot differs from its keyframe.
+struct Delta {
+    runs: Vec<(usize, Vec<u8>)>,
+}
+
+impl Rewind {
+    /// Creates a history using about `capacity` bytes. A capacity of zero
+    /// records nothing.
+    pub fn new(capacity: usize) -> Rewind {
+        Rewind {
+            capacity,
+            used: 0,
+            groups: VecDeque::new(),
+        }
+    }
+
+    /// Returns the number of snapshots that can be restored.
+    pub fn len(&self) -> usize {
+        self.groups.iter().map(|group| group.deltas.len() + 1).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Returns the number of bytes used by the snapshots.
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    pub fn record(&mut self, vm: &VM) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let state = vm.save_state();
+        if let Some(group) = self.groups.back_mut() {
+            if group.deltas.len() < KEYFRAME_INTERVAL && group.keyframe.len() == state.len() {
+                let delta = Delta::new(&group.keyframe, &state);
+                // a delta that changes most of the state is no longer compact
+                if delta.size() < state.len() / 4 {
+                    self.used += delta.size();
+                    group.deltas.push(delta);
+                    self.evict();
+                    return;
+                }
+            }
+        }
+
+        self.used += state.len();
+        self.groups.push_back(Group {
+            keyframe: state,
+            deltas: Vec::new(),
+        });
+        self.evict();
+    }
+
+    /// Restores the newest snapshot and removes it from the history.
+    /// Returns `false` if there was nothing to restore.
+    pub fn rewind(&mut self, vm: &mut VM) -> bool {
+        match self.pop() {
+            Some(state) => state::load(vm, &state, false).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Removes the newest snapshot without restoring it.
+    pub(crate) fn pop(&mut self) -> Option<Vec<u8>> {
+        let group = self.groups.back_mut()?;
+        match group.deltas.pop() {
+            Some(delta) => {
+                self.used -= delta.size();
+                Some(delta.apply(&group.keyframe))
+            }
+            None => {
+                let group = self.groups.pop_back()?;
+                self.used -= group.keyframe.len();
+                Some(group.keyframe)
+            }
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.used > self.capacity && self.groups.len() > 1 {
+            if let Some(group) = self.groups.pop_front() {
+                self.used -= group.size();
+            }
+        }
+    }
+}
+
+impl Group {
+    fn size(&self) -> usize {
+        self.keyframe.len() + self.deltas.iter().map(Delta::size).sum::<usize>()
+    }
+}
+
+impl Delta {
+    fn new(keyframe: &[u8], state: &[u8]) -> Delta {
+        let mut runs = Vec::new();
+        let mut i = 0;
+        while i < state.len() {
+            // most of the state is unchanged, so skip it a chunk at a time
+            let chunk = (i + SKIP_CHUNK).min(state.len());
+            if keyframe[i..chunk] == state[i..chunk] {
+                i = chunk;
+                continue;
+            }
+            if keyframe[i] == state[i] {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < state.len() && keyframe[i] != state[i] {
+                i += 1;
+            }
+            runs.push((start, state[start..i].to_vec()));
+        }
+        Delta { runs }
+    }
+
+    fn apply(&self, keyframe: &[u8]) -> Vec<u8> {
+        let mut state = keyframe.to_vec();
+        for (start, bytes) in self.runs.iter() {
+            state[*start..start + bytes.len()].copy_from_slice(bytes);
+        }
+        state
+    }
+
+    fn size(&self) -> usize {
+        self.runs
+            .iter()
+            .map(|(_, bytes)| bytes.len() + RUN_OVERHEAD)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_vm;
+
+    // ADD V0, 1; LD [I], V0; JP 0x200
+    const ROM: [u8; 6] = [0x70, 0x01, 0xF0, 0x55, 0x12, 0x00];
+
+    #[test]
+    fn rewind_in_order() {
+        let mut vm = test_vm(&ROM);
+        let mut rewind = Rewind::new(1 << 20);
+
+        let mut states = Vec::new();
+        for _ in 0..100 {
+            rewind.record(&vm);
+            states.push(vm.save_state());
+            vm.step().unwrap();
+        }
+        assert_eq!(rewind.len(), 100);
+
+        while let Some(state) = states.pop() {
+            assert!(rewind.rewind(&mut vm));
+            assert_eq!(vm.save_state(), state);
+        }
+        assert!(!rewind.rewind(&mut vm));
+        assert_eq!(rewind.memory_used(), 0);
+    }
+
+    #[test]
+    fn deltas_are_compact() {
+        let mut vm = test_vm(&ROM);
+        let mut rewind = Rewind::new(1 << 20);
+
+        rewind.record(&vm);
+        let keyframe = rewind.memory_used();
+        vm.step().unwrap();
+        rewind.record(&vm);
+
+        assert!(rewind.memory_used() - keyframe < 100);
+    }
+
+    #[test]
+    fn bounded_memory() {
+        let mut vm = test_vm(&ROM);
+        let capacity = 4 * vm.save_state().len();
+        let mut rewind = Rewind::new(capacity);
+
+        for _ in 0..1000 {
+            rewind.record(&vm);
+            vm.step().unwrap();
+        }
+
+        assert!(rewind.memory_used() <= capacity);
+        assert!(rewind.len() > KEYFRAME_INTERVAL);
+        assert!(rewind.len() < 1000);
+    }
+
+    #[test]
+    fn disabled() {
+        let vm = test_vm(&ROM);
+        let mut rewind = Rewind::new(0);
+
+        rewind.record(&vm);
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn keypad_is_not_rewound() {
+        let mut vm = test_vm(&ROM);
+        let mut rewind = Rewind::new(1 << 20);
+
+        rewind.record(&vm);
+        vm.keypad.press(crate::keys::Key::Key5);
+        rewind.rewind(&mut vm);
+
+        assert!(vm.keypad.is_pressed(crate::keys::Key::Key5));
+    }
+}
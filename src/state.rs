@@ -0,0 +1,408 @@
+use super::display::{Display, HIRES_HEIGHT, NUM_PLANES};
+use super::keys::Keypad;
+use super::rng::Rng;
+use super::{
+    EdgeMode, IndexIncrement, KeyWait, Quirks, WaitingForKey, AUDIO_PATTERN_SIZE, NUM_REGISTERS,
+    NUM_RPL_FLAGS, RAM_SIZE, STACK_SIZE, VM,
+};
+
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"C8ST";
+/// Increased whenever the layout below changes.
+const VERSION: u8 = 1;
+const NUM_KEYS: u8 = 16;
+/// Bytes taken by the quirks in states and movies.
+pub(crate) const QUIRKS_SIZE: usize = 8;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum StateError {
+    /// The data does not start like a save state.
+    NotAState,
+    /// The state was saved by a different version of the emulator.
+    UnsupportedVersion(u8),
+    /// The state was saved while running a different ROM.
+    WrongRom,
+    /// The data ends early or holds values the VM cannot have.
+    Corrupt,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::WrongRom => write!(f, "save state is for a different ROM"),
+            StateError::Corrupt => write!(f, "save state is corrupt"),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+/// The CRC-32 of a ROM, which ties a save state to the ROM it was saved
+/// from.
+pub(crate) fn checksum(rom: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in rom {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+// All values are stored big-endian, in the order they are written here.
+pub(crate) fn save(vm: &VM) -> Vec<u8> {
+    let mut out = Vec::with_capacity(RAM_SIZE + 4096);
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    out.extend_from_slice(&vm.rom_checksum.to_be_bytes());
+
+    out.extend_from_slice(&vm.memory);
+    for addr in vm.stack.iter() {
+        out.extend_from_slice(&addr.to_be_bytes());
+    }
+    out.extend_from_slice(&vm.gen_registers);
+    out.extend_from_slice(&vm.reg_i.to_be_bytes());
+    out.extend_from_slice(&vm.reg_pc.to_be_bytes());
+    out.push(vm.reg_sp);
+    out.push(vm.reg_delay);
+    out.push(vm.reg_sound);
+    out.extend_from_slice(&vm.rpl_flags);
+    out.push(vm.audio_pattern.is_some() as u8);
+    out.extend_from_slice(&vm.audio_pattern.unwrap_or_default());
+    out.push(vm.reg_pitch);
+    out.extend_from_slice(&vm.rng.state().to_be_bytes());
+    out.extend_from_slice(&vm.timer_cycles.to_be_bytes());
+    out.push(vm.drawn_this_frame as u8);
+    out.push(vm.exited as u8);
+    let waiting = vm.waiting_for_key.unwrap_or_default();
+    out.push(vm.waiting_for_key.is_some() as u8);
+    out.extend_from_slice(&waiting.held.to_be_bytes());
+    out.push(waiting.pressed.is_some() as u8);
+    out.push(waiting.pressed.unwrap_or_default());
+
+    out.push(vm.display.is_hires() as u8);
+    out.push(vm.display.selected_planes());
+    for plane in vm.display.planes().iter() {
+        for row in plane.iter() {
+            out.extend_from_slice(&row.to_be_bytes());
+        }
+    }
+
+    out.extend_from_slice(&vm.keypad.mask().to_be_bytes());
+    out.extend_from_slice(&encode_quirks(vm.quirks));
+
+    out
+}
+
+pub(crate) fn encode_quirks(quirks: Quirks) -> [u8; QUIRKS_SIZE] {
+    [
+        quirks.shift_in_place as u8,
+        match quirks.index_increment {
+            IndexIncrement::XPlusOne => 0,
+            IndexIncrement::X => 1,
+            IndexIncrement::Unchanged => 2,
+        },
+        quirks.jump_with_vx as u8,
+        quirks.logic_resets_vf as u8,
+        quirks.display_wait as u8,
+        match quirks.sprite_edge {
+            EdgeMode::Clip => 0,
+            EdgeMode::Wrap => 1,
+        },
+        match quirks.key_wait {
+            KeyWait::Press => 0,
+            KeyWait::Release => 1,
+        },
+        quirks.extended_memory as u8,
+    ]
+}
+
+/// Returns `None` if the bytes hold values no quirk can have.
+pub(crate) fn decode_quirks(bytes: &[u8; QUIRKS_SIZE]) -> Option<Quirks> {
+    let flag = |byte: u8| match byte {
+        0 => Some(false),
+        1 => Some(true),
+        _ => None,
+    };
+    Some(Quirks {
+        shift_in_place: flag(bytes[0])?,
+        index_increment: match bytes[1] {
+            0 => IndexIncrement::XPlusOne,
+            1 => IndexIncrement::X,
+            2 => IndexIncrement::Unchanged,
+            _ => return None,
+        },
+        jump_with_vx: flag(bytes[2])?,
+        logic_resets_vf: flag(bytes[3])?,
+        display_wait: flag(bytes[4])?,
+        sprite_edge: match bytes[5] {
+            0 => EdgeMode::Clip,
+            1 => EdgeMode::Wrap,
+            _ => return None,
+        },
+        key_wait: match bytes[6] {
+            0 => KeyWait::Press,
+            1 => KeyWait::Release,
+            _ => return None,
+        },
+        extended_memory: flag(bytes[7])?,
+    })
+}
+
+/// Replaces the state of the VM with a saved one, optionally leaving the
+/// keypad as it is. The VM is left unchanged if the state cannot be loaded.
+pub(crate) fn load(vm: &mut VM, state: &[u8], keypad: bool) -> Result<(), StateError> {
+    let mut r = Reader { data: state };
+    if r.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(StateError::NotAState);
+    }
+    let version = r.u8()?;
+    if version != VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+    if r.u32()? != vm.rom_checksum {
+        return Err(StateError::WrongRom);
+    }
+
+    let memory = r.bytes(RAM_SIZE)?;
+    let mut stack = [0; STACK_SIZE];
+    for addr in stack.iter_mut() {
+        *addr = r.u16()?;
+    }
+    let gen_registers = r.bytes(NUM_REGISTERS)?;
+    let reg_i = r.u16()?;
+    let reg_pc = r.u16()?;
+    let reg_sp = r.u8()?;
+    if reg_sp as usize > STACK_SIZE {
+        return Err(StateError::Corrupt);
+    }
+    let reg_delay = r.u8()?;
+    let reg_sound = r.u8()?;
+    let rpl_flags = r.bytes(NUM_RPL_FLAGS)?;
+    let has_audio = r.bool()?;
+    let audio_pattern = r.bytes(AUDIO_PATTERN_SIZE)?;
+    let reg_pitch = r.u8()?;
+    let rng = Rng::from_state(r.u64()?);
+    let timer_cycles = r.u32()?;
+    let drawn_this_frame = r.bool()?;
+    let exited = r.bool()?;
+    let waiting = r.bool()?;
+    let held = r.u16()?;
+    let has_pressed = r.bool()?;
+    let pressed = r.u8()?;
+    if pressed >= NUM_KEYS {
+        return Err(StateError::Corrupt);
+    }
+    let waiting_for_key = if waiting {
+        Some(WaitingForKey {
+            held,
+            pressed: if has_pressed { Some(pressed) } else { None },
+        })
+    } else {
+        None
+    };
+
+    let hires = r.bool()?;
+    let selected_planes = r.u8()?;
+    if selected_planes >= 1 << NUM_PLANES {
+        return Err(StateError::Corrupt);
+    }
+    let mut planes = [[0; HIRES_HEIGHT]; NUM_PLANES];
+    for plane in planes.iter_mut() {
+        for row in plane.iter_mut() {
+            *row = r.u128()?;
+        }
+    }
+
+    let keys = r.u16()?;
+
+    let quirks =
+        decode_quirks(r.bytes(QUIRKS_SIZE)?.try_into().unwrap()).ok_or(StateError::Corrupt)?;
+
+    if !r.data.is_empty() {
+        return Err(StateError::Corrupt);
+    }
+
+    vm.memory.copy_from_slice(memory);
+    vm.stack = stack;
+    vm.gen_registers.copy_from_slice(gen_registers);
+    vm.reg_i = reg_i;
+    vm.reg_pc = reg_pc;
+    vm.reg_sp = reg_sp;
+    vm.reg_delay = reg_delay;
+    vm.reg_sound = reg_sound;
+    vm.rpl_flags.copy_from_slice(rpl_flags);
+    vm.audio_pattern = if has_audio {
+        audio_pattern.try_into().ok()
+    } else {
+        None
+    };
+    vm.reg_pitch = reg_pitch;
+    vm.rng = rng;
+    vm.timer_cycles = timer_cycles;
+    vm.drawn_this_frame = drawn_this_frame;
+    vm.exited = exited;
+    vm.waiting_for_key = waiting_for_key;
+    vm.display = Display::from_planes(hires, selected_planes, planes);
+    if keypad {
+        vm.keypad = Keypad::from_mask(keys);
+    }
+    vm.quirks = quirks;
+
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Corrupt);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn u128(&mut self) -> Result<u128, StateError> {
+        Ok(u128::from_be_bytes(self.bytes(16)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::keys::Key;
+    use crate::{test_vm, Instruction};
+
+    #[test]
+    fn crc32() {
+        assert_eq!(checksum(b"123456789"), 0xCBF4_3926);
+        assert_eq!(checksum(&[]), 0);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let rom = [0xC0, 0xFF, 0xC1, 0xFF, 0x22, 0x00];
+        let mut vm = test_vm(&rom);
+        vm.run_for(3).unwrap();
+        vm.reg_i = 0x300;
+        vm.reg_delay = 20;
+        vm.audio_pattern = Some([0xAA; AUDIO_PATTERN_SIZE]);
+        vm.execute(Instruction::HIGH).unwrap();
+        vm.display.select_planes(0b11);
+        vm.display.draw(1, 2, &[0xFF, 0x0F], 8, EdgeMode::Clip);
+        vm.keypad.press(Key::KeyA);
+        vm.set_quirks(Quirks::SUPER_CHIP);
+        vm.waiting_for_key = Some(WaitingForKey {
+            held: 0x0400,
+            pressed: Some(3),
+        });
+        let state = vm.save_state();
+
+        let mut restored = test_vm(&rom);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.memory[..], vm.memory[..]);
+        assert_eq!(restored.stack, vm.stack);
+        assert_eq!(restored.reg_sp, 1);
+        assert_eq!(restored.gen_registers, vm.gen_registers);
+        assert_eq!(restored.reg_i, 0x300);
+        assert_eq!(restored.reg_pc, vm.reg_pc);
+        assert_eq!(restored.reg_delay, 20);
+        assert_eq!(restored.audio_pattern, vm.audio_pattern);
+        assert_eq!(restored.display, vm.display);
+        assert!(restored.keypad.is_pressed(Key::KeyA));
+        assert!(!restored.keypad.is_pressed(Key::Key0));
+        assert_eq!(restored.quirks, Quirks::SUPER_CHIP);
+        assert_eq!(restored.waiting_for_key, vm.waiting_for_key);
+
+        // both continue with the same random numbers
+        vm.reg_pc = 0x200;
+        restored.reg_pc = 0x200;
+        vm.run_for(2).unwrap();
+        restored.run_for(2).unwrap();
+        assert_eq!(restored.gen_registers, vm.gen_registers);
+        assert_eq!(restored.save_state(), vm.save_state());
+    }
+
+    #[test]
+    fn wrong_rom() {
+        let state = test_vm(&[0x00, 0xE0]).save_state();
+        let mut vm = test_vm(&[0x00, 0xEE]);
+
+        assert_eq!(vm.load_state(&state), Err(StateError::WrongRom));
+    }
+
+    #[test]
+    fn invalid_states() {
+        let mut vm = test_vm(&[0x00, 0xE0]);
+        let state = vm.save_state();
+
+        assert_eq!(vm.load_state(b"not a state"), Err(StateError::NotAState));
+
+        let mut newer = state.clone();
+        newer[MAGIC.len()] = VERSION + 1;
+        assert_eq!(
+            vm.load_state(&newer),
+            Err(StateError::UnsupportedVersion(VERSION + 1))
+        );
+
+        assert_eq!(
+            vm.load_state(&state[..state.len() - 1]),
+            Err(StateError::Corrupt)
+        );
+        let mut longer = state.clone();
+        longer.push(0);
+        assert_eq!(vm.load_state(&longer), Err(StateError::Corrupt));
+    }
+
+    #[test]
+    fn failed_load_leaves_vm_unchanged() {
+        let mut vm = test_vm(&[0x60, 0x05]);
+        let mut state = vm.save_state();
+        vm.step().unwrap();
+
+        // the quirks come last, and the last of them is the memory quirk,
+        // which cannot be 7
+        assert_eq!(state[state.len() - QUIRKS_SIZE..], encode_quirks(vm.quirks));
+        *state.last_mut().unwrap() = 7;
+        assert_eq!(vm.load_state(&state), Err(StateError::Corrupt));
+        assert_eq!(vm.gen_registers[0], 5);
+        assert_eq!(vm.reg_pc, 0x202);
+    }
+}
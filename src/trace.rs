@@ -0,0 +1,204 @@
+use super::instruction::Instruction;
+use super::{VmError, NUM_REGISTERS};
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Writes a line for every instruction executed by a VM, for comparing runs
+/// with other interpreters:
+///
+/// ```text
+///        0 0200 6A0B LD VA, 0x0B          VA=00->0B
+///        1 0202 A300 LD I, 0x300          I=0000->0300
+///        2 0204 2300 CALL 0x300           SP=0->1
+/// ```
+///
+/// The columns are the number of the instruction since tracing started, PC,
+/// the opcode, the instruction and the registers it changed. Waiting for a
+/// key is traced when the wait begins and when it ends.
+pub struct Tracer {
+    sink: Sink,
+    count: u64,
+    /// The first write error, reported by `finish`.
+    error: Option<io::Error>,
+}
+
+enum Sink {
+    File(BufWriter<File>),
+    Buffer(Vec<u8>),
+}
+
+/// The registers an instruction may change, captured before executing it.
+pub(crate) struct Registers {
+    pub gen_registers: [u8; NUM_REGISTERS],
+    pub reg_i: u16,
+    pub reg_sp: u8,
+}
+
+impl Tracer {
+    pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Tracer> {
+        Ok(Tracer::new(Sink::File(BufWriter::new(File::create(path)?))))
+    }
+
+    pub fn to_buffer() -> Tracer {
+        Tracer::new(Sink::Buffer(Vec::new()))
+    }
+
+    fn new(sink: Sink) -> Tracer {
+        Tracer {
+            sink,
+            count: 0,
+            error: None,
+        }
+    }
+
+    /// Returns the lines traced so far by a tracer writing to a buffer.
+    pub fn buffer(&self) -> Option<&str> {
+        match &self.sink {
+            Sink::Buffer(buffer) => std::str::from_utf8(buffer).ok(),
+            Sink::File(_) => None,
+        }
+    }
+
+    /// Flushes the trace, returning the first error hit while writing it.
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        match &mut self.sink {
+            Sink::File(file) => file.flush(),
+            Sink::Buffer(_) => Ok(()),
+        }
+    }
+
+    pub(crate) fn trace(
+        &mut self,
+        pc: u16,
+        instr: Instruction,
+        before: &Registers,
+        after: &Registers,
+        error: Option<&VmError>,
+    ) {
+        let mut line = format!(
+            "{:>8} {:04X} {:04X} {:<20}",
+            self.count,
+            pc,
+            instr.encode(),
+            instr.to_string()
+        );
+
+        for (x, (old, new)) in before
+            .gen_registers
+            .iter()
+            .zip(after.gen_registers.iter())
+            .enumerate()
+        {
+            if old != new {
+                line.push_str(&format!(" V{:X}={:02X}->{:02X}", x, old, new));
+            }
+        }
+        if before.reg_i != after.reg_i {
+            line.push_str(&format!(" I={:04X}->{:04X}", before.reg_i, after.reg_i));
+        }
+        if before.reg_sp != after.reg_sp {
+            line.push_str(&format!(" SP={:X}->{:X}", before.reg_sp, after.reg_sp));
+        }
+        if let Some(error) = error {
+            line.push_str(&format!(" error: {}", error));
+        }
+
+        let line = line.trim_end();
+        let result = match &mut self.sink {
+            Sink::File(file) => writeln!(file, "{}", line),
+            Sink::Buffer(buffer) => writeln!(buffer, "{}", line),
+        };
+        if let Err(e) = result {
+            self.error.get_or_insert(e);
+        }
+        self.count += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::keys::Key;
+    use crate::test_vm;
+
+    fn trace(rom: &[u8], steps: usize) -> Vec<String> {
+        let mut vm = test_vm(rom);
+        vm.set_tracer(Some(Tracer::to_buffer()));
+        for _ in 0..steps {
+            let _ = vm.step();
+        }
+
+        let tracer = vm.take_tracer().unwrap();
+        tracer.buffer().unwrap().lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(
+            trace(&[0x6A, 0x0B, 0xA3, 0x00, 0x23, 0x00], 3),
+            [
+                "       0 0200 6A0B LD VA, 0x0B          VA=00->0B",
+                "       1 0202 A300 LD I, 0x300          I=0000->0300",
+                "       2 0204 2300 CALL 0x300           SP=0->1",
+            ]
+        );
+    }
+
+    #[test]
+    fn several_changes() {
+        // LD V0..V1 from [I] with I starting at 0, where the font is
+        assert_eq!(
+            trace(&[0xF1, 0x65], 1),
+            ["       0 0200 F165 LD V1, [I]           V0=00->F0 V1=00->90 I=0000->0002"]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            trace(&[0x00, 0xEE], 1),
+            ["       0 0200 00EE RET                  error: stack underflow (pc: 0x200, opcode: 0x00EE)"]
+        );
+    }
+
+    #[test]
+    fn key_wait() {
+        // LD V0, K
+        let mut vm = test_vm(&[0xF0, 0x0A]);
+        vm.set_tracer(Some(Tracer::to_buffer()));
+        for _ in 0..3 {
+            vm.step().unwrap();
+        }
+        vm.keypad.press(Key::Key5);
+        vm.step().unwrap();
+        vm.keypad.release(Key::Key5);
+        vm.step().unwrap();
+
+        let tracer = vm.take_tracer().unwrap();
+        assert_eq!(
+            tracer.buffer().unwrap(),
+            "       0 0200 F00A LD V0, K\n       1 0200 F00A LD V0, K             V0=00->05\n"
+        );
+    }
+
+    #[test]
+    fn file() {
+        let path =
+            std::env::temp_dir().join(format!("chip8-trace-file-{}.log", std::process::id()));
+        let mut vm = test_vm(&[0x6A, 0x0B]);
+        vm.set_tracer(Some(Tracer::to_file(&path).unwrap()));
+        vm.step().unwrap();
+
+        let tracer = vm.take_tracer().unwrap();
+        assert_eq!(tracer.buffer(), None);
+        tracer.finish().unwrap();
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(trace, "       0 0200 6A0B LD VA, 0x0B          VA=00->0B\n");
+    }
+}
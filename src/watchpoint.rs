@@ -0,0 +1,93 @@
+use std::fmt;
+
+/// A condition checked on every step, for stopping execution in a debugger.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Watchpoint {
+    /// An instruction reads the byte at the address.
+    Read(u16),
+    /// An instruction writes the byte at the address, even if the value
+    /// stays the same.
+    Write(u16),
+    /// An instruction changes the value of a register.
+    Register(Register, Change),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Register {
+    V(u8),
+    I,
+}
+
+/// The register changes a watchpoint fires on.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Change {
+    Any,
+    To(u16),
+    From(u16),
+}
+
+impl Change {
+    pub fn matches(self, old: u16, new: u16) -> bool {
+        old != new
+            && match self {
+                Change::Any => true,
+                Change::To(value) => new == value,
+                Change::From(value) => old == value,
+            }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Watchpoint::Read(addr) => write!(f, "read of {:#05X}", addr),
+            Watchpoint::Write(addr) => write!(f, "write to {:#05X}", addr),
+            Watchpoint::Register(register, Change::Any) => write!(f, "change of {}", register),
+            Watchpoint::Register(register, Change::To(value)) => {
+                write!(f, "change of {} to {:#04X}", register, value)
+            }
+            Watchpoint::Register(register, Change::From(value)) => {
+                write!(f, "change of {} from {:#04X}", register, value)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn change_matches() {
+        assert!(Change::Any.matches(1, 2));
+        assert!(!Change::Any.matches(2, 2));
+        assert!(Change::To(2).matches(1, 2));
+        assert!(!Change::To(2).matches(2, 3));
+        assert!(Change::From(1).matches(1, 2));
+        assert!(!Change::From(1).matches(1, 1));
+        assert!(!Change::From(1).matches(2, 3));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Watchpoint::Read(0x300).to_string(), "read of 0x300");
+        assert_eq!(Watchpoint::Write(0x300).to_string(), "write to 0x300");
+        assert_eq!(
+            Watchpoint::Register(Register::V(0xA), Change::Any).to_string(),
+            "change of VA"
+        );
+        assert_eq!(
+            Watchpoint::Register(Register::I, Change::To(0x5)).to_string(),
+            "change of I to 0x05"
+        );
+    }
+}